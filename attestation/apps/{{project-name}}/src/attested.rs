@@ -0,0 +1,151 @@
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::enclave_key;
+
+/// Size of the report data carried by a quote.
+pub const REPORT_DATA_SIZE: usize = 64;
+pub const NONCE_SIZE: usize = 32;
+const MAX_PAYLOAD_HASH_SIZE: usize = 64;
+
+#[derive(Deserialize, Debug)]
+pub struct AttestedResponseInput {
+    /// Hex encoded client nonce, exactly `NONCE_SIZE` bytes.
+    pub nonce: String,
+    /// Hex encoded hash of the payload the response is bound to.
+    pub payload_hash: String,
+}
+
+/// Builds `nonce || payload_hash || enclave public key`.
+///
+/// The nonce and the SPKI encoded P-256 public key have a fixed size, so the payload hash can
+/// have any length up to 64 bytes without making the preimage ambiguous.
+pub fn build_preimage(
+    nonce: &[u8],
+    payload_hash: &[u8],
+    public_key: &[u8],
+) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    if nonce.len() != NONCE_SIZE {
+        return Err(format!("nonce must be {NONCE_SIZE} bytes, got {}", nonce.len()).into());
+    }
+    if payload_hash.is_empty() || payload_hash.len() > MAX_PAYLOAD_HASH_SIZE {
+        return Err(format!(
+            "payload hash must be between 1 and {MAX_PAYLOAD_HASH_SIZE} bytes, got {}",
+            payload_hash.len()
+        )
+        .into());
+    }
+    let mut preimage = Vec::with_capacity(nonce.len() + payload_hash.len() + public_key.len());
+    preimage.extend_from_slice(nonce);
+    preimage.extend_from_slice(payload_hash);
+    preimage.extend_from_slice(public_key);
+    Ok(preimage)
+}
+
+/// Report data is `SHA2-256(preimage)` followed by 32 zero bytes.
+pub fn report_data(digest: &[u8]) -> Vec<u8> {
+    let mut report_data = digest.to_vec();
+    report_data.resize(REPORT_DATA_SIZE, 0);
+    report_data
+}
+
+/// Builds `label || u32_be(len(field)) || field || ...`.
+///
+/// Every field is length prefixed so that moving bytes from one field to the next gives another
+/// transcript.
+pub fn transcript(label: &[u8], fields: &[&[u8]]) -> Vec<u8> {
+    let mut transcript = label.to_vec();
+    for field in fields {
+        transcript.extend((field.len() as u32).to_be_bytes());
+        transcript.extend_from_slice(field);
+    }
+    transcript
+}
+
+/// Quote whose report data commits to `transcript`, hex encoded.
+pub fn transcript_quote(transcript: &[u8]) -> Result<String, Box<dyn std::error::Error>> {
+    let digest = klave::crypto::sha::digest("SHA2-256", transcript)?;
+    let quote = klave::attestation::get_quote(&report_data(&digest))?;
+    Ok(hex::encode(quote))
+}
+
+/// Whether the hex encoded report data of a quote commits to `transcript`.
+pub fn commits_to(
+    report_data_hex: &str,
+    transcript: &[u8],
+) -> Result<bool, Box<dyn std::error::Error>> {
+    let digest = klave::crypto::sha::digest("SHA2-256", transcript)?;
+    Ok(report_data_hex == hex::encode(report_data(&digest)))
+}
+
+pub fn get_attested_response(cmd: String) {
+    let Ok(input) = serde_json::from_str::<AttestedResponseInput>(&cmd) else {
+        klave::notifier::send_string(&format!("failed to parse '{cmd}' as json"));
+        return;
+    };
+    let (Ok(nonce), Ok(payload_hash)) =
+        (hex::decode(&input.nonce), hex::decode(&input.payload_hash))
+    else {
+        klave::notifier::send_string("'nonce' and 'payload_hash' must be hex encoded");
+        return;
+    };
+    let public_key = match enclave_key::enclave_public_key() {
+        Ok(public_key) => public_key,
+        Err(e) => {
+            klave::notifier::send_string(&format!("failed to load enclave public key: {e}"));
+            return;
+        }
+    };
+    let preimage = match build_preimage(&nonce, &payload_hash, &public_key) {
+        Ok(preimage) => preimage,
+        Err(e) => {
+            klave::notifier::send_string(&format!("invalid attested response request: {e}"));
+            return;
+        }
+    };
+    let Ok(digest) = klave::crypto::sha::digest("SHA2-256", &preimage) else {
+        klave::notifier::send_string("failed to hash report data preimage");
+        return;
+    };
+    let report_data = report_data(&digest);
+    let Ok(quote) = klave::attestation::get_quote(&report_data) else {
+        klave::notifier::send_string(&format!("failed to get quote: '{cmd}'"));
+        return;
+    };
+
+    let _ = klave::notifier::send_json(&json!({
+        "quote": quote,
+        "report_data": hex::encode(&report_data),
+        "preimage": hex::encode(&preimage),
+        "nonce": input.nonce,
+        "payload_hash": input.payload_hash,
+        "public_key": hex::encode(&public_key)
+    }));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_build_preimage() {
+        let nonce = [1u8; NONCE_SIZE];
+        let preimage = build_preimage(&nonce, &[2u8; 32], &[3u8; 91]).unwrap();
+        assert_eq!(preimage.len(), NONCE_SIZE + 32 + 91);
+        assert_eq!(&preimage[..NONCE_SIZE], &nonce);
+        assert_eq!(preimage[NONCE_SIZE], 2);
+        assert_eq!(preimage[NONCE_SIZE + 32], 3);
+
+        assert!(build_preimage(&[1u8; 16], &[2u8; 32], &[3u8; 91]).is_err());
+        assert!(build_preimage(&nonce, &[], &[3u8; 91]).is_err());
+        assert!(build_preimage(&nonce, &[2u8; 65], &[3u8; 91]).is_err());
+    }
+
+    #[test]
+    fn test_report_data() {
+        let report_data = report_data(&[7u8; 32]);
+        assert_eq!(report_data.len(), REPORT_DATA_SIZE);
+        assert_eq!(&report_data[..32], &[7u8; 32]);
+        assert!(report_data[32..].iter().all(|b| *b == 0));
+    }
+}
@@ -0,0 +1,304 @@
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use klave::crypto::subtle::{
+    self, AesGcmParams, AesKeyGenParams, CryptoKey, EncryptAlgorithm, KeyGenAlgorithm,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::{attested, handshake, policy, quote};
+
+pub(crate) const SECRET_TABLE: &str = "broker_secrets";
+/// Enclave key the stored secrets are sealed under.
+pub(crate) const SEALING_KEY_NAME: &str = "BrokerSealingKey";
+
+const RELEASE_LABEL: &[u8] = b"klave-key-release-request";
+const RELEASE_RESPONSE_LABEL: &[u8] = b"klave-key-release-response";
+const IV_SIZE: i32 = 12;
+
+#[derive(Serialize, Deserialize, Debug)]
+struct StoredSecret {
+    owner: String,
+    /// Policy the requester's quote is verified against.
+    policy: String,
+    /// Version of the policy when the secret was stored. Later versions are refused, so that
+    /// updating the policy cannot relax the release of an existing secret.
+    policy_version: u64,
+    /// Secret sealed under the sealing key with AES-GCM, bound to the secret name:
+    /// hex encoded iv followed by the ciphertext.
+    sealed_secret: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PutSecretInput {
+    pub name: String,
+    /// Base64 encoded secret.
+    pub secret: String,
+    pub policy: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DeleteSecretInput {
+    pub name: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ReleaseSecretInput {
+    pub name: String,
+    /// Requester's ephemeral public key, SPKI encoded, hex.
+    pub public_key: String,
+}
+
+/// Transcript hashed into the requester's report data:
+/// `"klave-key-release-request" || name || requester public key`, each field length prefixed.
+pub fn release_transcript(name: &str, requester_key: &[u8]) -> Vec<u8> {
+    attested::transcript(RELEASE_LABEL, &[name.as_bytes(), requester_key])
+}
+
+/// Transcript hashed into the broker's report data:
+/// `"klave-key-release-response" || name || requester public key || broker public key`, each
+/// field length prefixed.
+pub fn release_response_transcript(name: &str, requester_key: &[u8], broker_key: &[u8]) -> Vec<u8> {
+    attested::transcript(
+        RELEASE_RESPONSE_LABEL,
+        &[name.as_bytes(), requester_key, broker_key],
+    )
+}
+
+/// Loads the sealing key, creating it on first use. Keys can only be created in a transaction.
+fn load_or_create_sealing_key() -> Result<CryptoKey, Box<dyn std::error::Error>> {
+    if let Ok(key) = subtle::load_key(SEALING_KEY_NAME) {
+        return Ok(key);
+    }
+    let key = subtle::generate_key(
+        &KeyGenAlgorithm::Aes(AesKeyGenParams { length: 256 }),
+        false,
+        &["encrypt", "decrypt"],
+    )?;
+    subtle::save_key(&key, SEALING_KEY_NAME)?;
+    Ok(key)
+}
+
+fn aes_gcm(iv: Vec<u8>, additional_data: &[u8]) -> EncryptAlgorithm {
+    EncryptAlgorithm::AesGcm(AesGcmParams {
+        iv,
+        additional_data: additional_data.to_vec(),
+        tag_length: 128, // 128 bits
+    })
+}
+
+/// Encrypts `secret` under the sealing key, bound to `name`.
+fn seal(name: &str, secret: &[u8]) -> Result<String, Box<dyn std::error::Error>> {
+    let key = load_or_create_sealing_key()?;
+    let mut iv = klave::crypto::random::get_random_bytes(IV_SIZE)?;
+    let mut sealed = subtle::encrypt(&aes_gcm(iv.clone(), name.as_bytes()), &key, secret)?;
+    iv.append(&mut sealed);
+    Ok(hex::encode(iv))
+}
+
+/// Reverses `seal`.
+fn unseal(name: &str, sealed: &str) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let key = subtle::load_key(SEALING_KEY_NAME)?;
+    let iv_and_sealed = hex::decode(sealed)?;
+    if iv_and_sealed.len() <= IV_SIZE as usize {
+        return Err(format!("sealed secret '{name}' is too short").into());
+    }
+    let (iv, sealed) = iv_and_sealed.split_at(IV_SIZE as usize);
+    subtle::decrypt(&aes_gcm(iv.to_vec(), name.as_bytes()), &key, sealed)
+}
+
+fn load_secret(name: &str) -> Result<Option<StoredSecret>, Box<dyn std::error::Error>> {
+    let res = klave::ledger::get_table(SECRET_TABLE).get(name)?;
+    if res.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(serde_json::from_slice::<StoredSecret>(&res)?))
+}
+
+/// Checks that the sender owns secret `name`, when it exists.
+fn check_owner(name: &str) -> Result<Option<StoredSecret>, Box<dyn std::error::Error>> {
+    let sender = klave::context::get("sender")?;
+    match load_secret(name)? {
+        Some(stored) if stored.owner != sender => {
+            Err(format!("only the owner of secret '{name}' can modify it").into())
+        }
+        stored => Ok(stored),
+    }
+}
+
+fn put(input: &PutSecretInput) -> Result<(), Box<dyn std::error::Error>> {
+    let secret = STANDARD
+        .decode(&input.secret)
+        .map_err(|e| format!("'secret' must be base64 encoded: {e}"))?;
+    let sender = klave::context::get("sender")?;
+    let Some((owner, policy_version)) = policy::policy_owner_and_version(&input.policy)? else {
+        return Err(format!("policy '{}' not found", input.policy).into());
+    };
+    if owner != sender {
+        return Err(format!(
+            "secrets can only be stored under a policy of their owner, not '{}'",
+            input.policy
+        )
+        .into());
+    }
+    check_owner(&input.name)?;
+    let stored = StoredSecret {
+        owner: sender,
+        policy: input.policy.clone(),
+        policy_version,
+        sealed_secret: seal(&input.name, &secret)?,
+    };
+    klave::ledger::get_table(SECRET_TABLE).set(&input.name, &serde_json::to_vec(&stored)?)
+}
+
+fn delete(input: &DeleteSecretInput) -> Result<(), Box<dyn std::error::Error>> {
+    if check_owner(&input.name)?.is_none() {
+        return Err(format!("secret '{}' not found", input.name).into());
+    }
+    klave::ledger::get_table(SECRET_TABLE).remove(&input.name)
+}
+
+/// Verifies the requester's quote and, when it satisfies the secret's policy and commits to
+/// `public_key`, wraps the secret to that key.
+fn release(quote: &[u8], input: &ReleaseSecretInput) -> Result<Value, Box<dyn std::error::Error>> {
+    let Some(stored) = load_secret(&input.name)? else {
+        return Err(format!("secret '{}' not found", input.name).into());
+    };
+    let requester_key = hex::decode(&input.public_key)?;
+    let verdict = policy::verify_with_policy(quote, &stored.policy)?;
+    if verdict.policy_version != stored.policy_version {
+        return Err(format!(
+            "policy '{}' was updated to version {} after secret '{}' was stored under version {}, store the secret again",
+            stored.policy, verdict.policy_version, input.name, stored.policy_version
+        )
+        .into());
+    }
+    let bound = attested::commits_to(
+        verdict.quote.report_data(),
+        &release_transcript(&input.name, &requester_key),
+    )?;
+    if !verdict.passed() || !bound {
+        // Denials are not errors so that their evidence stays in the log
+        return Ok(json!({
+            "name": input.name,
+            "released": false,
+            "bound": bound,
+            "policy": stored.policy,
+            "policy_version": verdict.policy_version,
+            "evidence_id": verdict.evidence_id,
+            "rules": verdict.rules
+        }));
+    }
+
+    let (private_key, broker_key) = handshake::generate_ephemeral_key()?;
+    let transcript = release_response_transcript(&input.name, &requester_key, &broker_key);
+    let wrapping_key = handshake::derive_shared_key(&private_key, &requester_key, &transcript)?;
+    let iv = klave::crypto::random::get_random_bytes(IV_SIZE)?;
+    let wrapped = subtle::encrypt(
+        &aes_gcm(iv.clone(), input.name.as_bytes()),
+        &wrapping_key,
+        &unseal(&input.name, &stored.sealed_secret)?,
+    )?;
+    let quote = attested::transcript_quote(&transcript)?;
+    Ok(json!({
+        "name": input.name,
+        "released": true,
+        "policy": stored.policy,
+        "policy_version": verdict.policy_version,
+        "evidence_id": verdict.evidence_id,
+        "public_key": hex::encode(broker_key),
+        "iv": hex::encode(iv),
+        "wrapped_secret": hex::encode(wrapped),
+        "quote": quote
+    }))
+}
+
+pub fn put_secret(cmd: String) {
+    let Ok(input) = serde_json::from_str::<PutSecretInput>(&cmd) else {
+        klave::notifier::send_string(&format!("failed to parse '{cmd}' as json"));
+        klave::router::cancel_transaction();
+        return;
+    };
+    match put(&input) {
+        Ok(()) => {
+            let _ = klave::notifier::send_json(&json!({
+                "name": input.name,
+                "policy": input.policy
+            }));
+        }
+        Err(e) => {
+            klave::notifier::send_string(&format!("Failed to store secret: {e}"));
+            klave::router::cancel_transaction();
+        }
+    }
+}
+
+pub fn delete_secret(cmd: String) {
+    let Ok(input) = serde_json::from_str::<DeleteSecretInput>(&cmd) else {
+        klave::notifier::send_string(&format!("failed to parse '{cmd}' as json"));
+        klave::router::cancel_transaction();
+        return;
+    };
+    match delete(&input) {
+        Ok(()) => {
+            let _ = klave::notifier::send_json(&json!({
+                "name": input.name,
+                "deleted": true
+            }));
+        }
+        Err(e) => {
+            klave::notifier::send_string(&format!("Failed to delete secret: {e}"));
+            klave::router::cancel_transaction();
+        }
+    }
+}
+
+pub fn release_secret(cmd: String) {
+    let Ok(v) = serde_json::from_str::<Value>(&cmd) else {
+        klave::notifier::send_string(&format!("failed to parse '{cmd}' as json"));
+        klave::router::cancel_transaction();
+        return;
+    };
+    let Ok(input) = serde_json::from_value::<ReleaseSecretInput>(v.clone()) else {
+        klave::notifier::send_string("'name' and 'public_key' are required");
+        klave::router::cancel_transaction();
+        return;
+    };
+    let quote = match quote::extract_quote(&v) {
+        Ok(quote) => quote,
+        Err(e) => {
+            klave::notifier::send_string(&format!("Failed to extract 'quote': {e}"));
+            klave::router::cancel_transaction();
+            return;
+        }
+    };
+    match release(&quote, &input) {
+        Ok(response) => {
+            let _ = klave::notifier::send_json(&response);
+        }
+        Err(e) => {
+            klave::notifier::send_string(&format!("Failed to release secret: {e}"));
+            klave::router::cancel_transaction();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_release_transcripts() {
+        let request = release_transcript("db", &[1, 2]);
+        assert_eq!(
+            request,
+            [RELEASE_LABEL, &[0, 0, 0, 2], b"db", &[0, 0, 0, 2], &[1, 2]].concat()
+        );
+        // Moving a byte from the name to the key gives another transcript
+        assert_ne!(release_transcript("d", b"b\x01\x02"), request);
+
+        let response = release_response_transcript("db", &[1, 2], &[3]);
+        assert!(response.starts_with(RELEASE_RESPONSE_LABEL));
+        assert!(response.ends_with(&[0, 0, 0, 2, 1, 2, 0, 0, 0, 1, 3]));
+        assert_ne!(release_transcript("db2", &[1, 2]), request);
+    }
+}
@@ -0,0 +1,57 @@
+use klave::crypto::subtle::{self, CryptoKey};
+use serde_json::json;
+
+/// Name under which the enclave key pair is kept in the key store.
+pub(crate) const ENCLAVE_KEY_NAME: &str = "enclave_key";
+
+fn generate_ecc_crypto_key() -> Result<CryptoKey, Box<dyn std::error::Error>> {
+    let ec_params = subtle::EcKeyGenParams {
+        named_curve: "P-256".to_string(),
+    };
+    let gen_algorithm = subtle::KeyGenAlgorithm::Ecc(ec_params);
+    subtle::generate_key(&gen_algorithm, false, &["sign", "derive_key"])
+}
+
+/// Loads the enclave key pair, which must have been created by `generate-enclave-key`.
+pub fn load_enclave_key() -> Result<CryptoKey, Box<dyn std::error::Error>> {
+    subtle::load_key(ENCLAVE_KEY_NAME)
+        .map_err(|_| "the enclave key does not exist, call generate-enclave-key first".into())
+}
+
+/// Public key of the enclave, SPKI (DER) encoded.
+pub fn enclave_public_key() -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let public_key = subtle::get_public_key(&load_enclave_key()?)?;
+    subtle::export_key("spki", &public_key)
+}
+
+pub fn generate_enclave_key(_cmd: String) {
+    if subtle::load_key(ENCLAVE_KEY_NAME).is_err() {
+        let key = match generate_ecc_crypto_key() {
+            Ok(key) => key,
+            Err(e) => {
+                klave::notifier::send_string(&format!("failed to generate enclave key: {e}"));
+                klave::router::cancel_transaction();
+                return;
+            }
+        };
+        if let Err(e) = subtle::save_key(&key, ENCLAVE_KEY_NAME) {
+            klave::notifier::send_string(&format!("failed to save enclave key: {e}"));
+            klave::router::cancel_transaction();
+            return;
+        }
+    }
+    get_enclave_public_key(String::new());
+}
+
+pub fn get_enclave_public_key(_cmd: String) {
+    match enclave_public_key() {
+        Ok(public_key) => {
+            let _ = klave::notifier::send_json(&json!({
+                "public_key": hex::encode(public_key)
+            }));
+        }
+        Err(e) => {
+            klave::notifier::send_string(&format!("failed to load enclave public key: {e}"));
+        }
+    }
+}
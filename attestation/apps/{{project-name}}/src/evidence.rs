@@ -0,0 +1,273 @@
+use klave::attestation::VerifyQuoteResponse;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::{
+    policy::{self, RuleResult},
+    quote,
+};
+
+pub(crate) const EVIDENCE_TABLE: &str = "attestation_evidence";
+pub(crate) const EVIDENCE_HEAD_TABLE: &str = "attestation_evidence_head";
+/// Quotes referenced by the evidence log, keyed by their hash.
+pub(crate) const EVIDENCE_QUOTE_TABLE: &str = "attestation_evidence_quotes";
+
+const EVIDENCE_HEAD_KEY: &str = "head";
+const BUNDLE_FORMAT: &str = "klave-attestation-evidence/1";
+
+/// Policy evaluation attached to a verification.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PolicyOutcome {
+    pub name: String,
+    pub version: u64,
+    pub passed: bool,
+    pub rules: Vec<RuleResult>,
+}
+
+/// Fields of the `sgx_ql_qv_supplemental_t` returned by `verify_quote`, which identify the
+/// collateral the quote was verified against. The structure is packed and little endian.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SupplementalData {
+    pub major_version: u16,
+    pub minor_version: u16,
+    /// Earliest issue date of the collateral (TCB info, QE identity, CRLs), in seconds.
+    pub earliest_issue_date: i64,
+    /// Latest issue date of the collateral, in seconds.
+    pub latest_issue_date: i64,
+    /// Earliest expiration date of the collateral, in seconds.
+    pub earliest_expiration_date: i64,
+    /// Date of the TCB level the platform matched in the TCB info, in seconds.
+    pub tcb_level_date_tag: i64,
+    /// CRL number of the PCK CRL.
+    pub pck_crl_num: u32,
+    /// CRL number of the root CA CRL.
+    pub root_ca_crl_num: u32,
+    /// TCB evaluation data number of the TCB info and QE identity.
+    pub tcb_eval_ref_num: u32,
+    /// SHA-384 of the root CA public key, hex encoded.
+    pub root_key_id: String,
+}
+
+impl SupplementalData {
+    pub fn parse(data: &[u8]) -> Result<Self, String> {
+        let field = |offset: usize, len: usize| {
+            data.get(offset..offset + len).ok_or_else(|| {
+                format!("supplemental data truncated: {len} bytes expected at offset {offset}")
+            })
+        };
+        let u16_at = |offset| field(offset, 2).map(|b| u16::from_le_bytes(b.try_into().unwrap()));
+        let u32_at = |offset| field(offset, 4).map(|b| u32::from_le_bytes(b.try_into().unwrap()));
+        let i64_at = |offset| field(offset, 8).map(|b| i64::from_le_bytes(b.try_into().unwrap()));
+        Ok(SupplementalData {
+            major_version: u16_at(0)?,
+            minor_version: u16_at(2)?,
+            earliest_issue_date: i64_at(4)?,
+            latest_issue_date: i64_at(12)?,
+            earliest_expiration_date: i64_at(20)?,
+            tcb_level_date_tag: i64_at(28)?,
+            pck_crl_num: u32_at(36)?,
+            root_ca_crl_num: u32_at(40)?,
+            tcb_eval_ref_num: u32_at(44)?,
+            root_key_id: hex::encode(field(48, 48)?),
+        })
+    }
+}
+
+/// What `verify_quote` reports about the collateral it verified a quote against.
+/// The SDK does not hand out the TCB info, QE identity and CRLs themselves, only their
+/// issue dates, CRL numbers and TCB evaluation data number, which pin the exact versions
+/// to fetch from the Intel PCS.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Collateral {
+    /// `trusted_time` the quote was verified at, in nanoseconds.
+    pub verified_at: u64,
+    /// PCK certificate chain embedded in the quote, when it could be parsed.
+    pub pck_certificate_chain: Option<Vec<String>>,
+    /// Raw supplemental data, hex encoded.
+    pub supplemental_data: String,
+    /// Decoded supplemental data, when it has the expected layout.
+    pub supplemental: Option<SupplementalData>,
+    /// Non zero when some of the collateral had expired at `verified_at`.
+    pub collateral_expiration_status: u32,
+    /// Security advisories that apply to the platform's TCB level.
+    pub advisory_ids: Option<String>,
+    /// Report of the Quote Verification Enclave, when verification ran in one.
+    pub qve_report_info: Value,
+}
+
+impl Collateral {
+    fn new(quote: &[u8], response: &VerifyQuoteResponse, verified_at: u64) -> Self {
+        Collateral {
+            verified_at,
+            pck_certificate_chain: quote::parse(quote).ok().map(|q| q.certification_chain),
+            supplemental_data: hex::encode(&response.supp_data.data),
+            supplemental: SupplementalData::parse(&response.supp_data.data).ok(),
+            collateral_expiration_status: response.collateral_expiration_status,
+            advisory_ids: response.sa_list.clone(),
+            qve_report_info: serde_json::to_value(&response.qve_report_info).unwrap_or(Value::Null),
+        }
+    }
+}
+
+/// One entry of the evidence log.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Evidence {
+    pub id: u64,
+    /// SHA2-256 of the quote, hex encoded.
+    pub quote_hash: String,
+    pub sender: String,
+    /// `trusted_time` the quote was verified at, in nanoseconds.
+    pub trusted_time: u64,
+    /// Output of `klave::attestation::verify_quote`.
+    pub result: Value,
+    pub policy: Option<PolicyOutcome>,
+    /// Collateral reported by `verify_quote`, absent from entries recorded before it was kept.
+    #[serde(default)]
+    pub collateral: Option<Collateral>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ExportEvidenceInput {
+    pub evidence_id: u64,
+}
+
+fn evidence_key(id: u64) -> String {
+    format!("{id:020}")
+}
+
+fn head() -> Result<u64, Box<dyn std::error::Error>> {
+    let res = klave::ledger::get_table(EVIDENCE_HEAD_TABLE).get(EVIDENCE_HEAD_KEY)?;
+    if res.is_empty() {
+        return Ok(0);
+    }
+    Ok(serde_json::from_slice::<u64>(&res)?)
+}
+
+/// Appends the verification of `quote` to the evidence log and returns its id.
+pub fn record(
+    quote: &[u8],
+    response: &VerifyQuoteResponse,
+    policy: Option<PolicyOutcome>,
+) -> Result<u64, Box<dyn std::error::Error>> {
+    let quote_hash = hex::encode(klave::crypto::sha::digest("SHA2-256", quote)?);
+    let id = head()? + 1;
+    let trusted_time = klave::context::get("trusted_time")?.parse::<u64>()?;
+    let evidence = Evidence {
+        id,
+        quote_hash,
+        sender: klave::context::get("sender")?,
+        trusted_time,
+        result: serde_json::to_value(response)?,
+        policy,
+        collateral: Some(Collateral::new(quote, response, trusted_time)),
+    };
+    klave::ledger::get_table(EVIDENCE_QUOTE_TABLE).set(&evidence.quote_hash, quote)?;
+    klave::ledger::get_table(EVIDENCE_TABLE)
+        .set(&evidence_key(id), &serde_json::to_vec(&evidence)?)?;
+    klave::ledger::get_table(EVIDENCE_HEAD_TABLE)
+        .set(EVIDENCE_HEAD_KEY, &serde_json::to_vec(&id)?)?;
+    Ok(id)
+}
+
+pub fn load_evidence(id: u64) -> Result<Option<Evidence>, Box<dyn std::error::Error>> {
+    let res = klave::ledger::get_table(EVIDENCE_TABLE).get(&evidence_key(id))?;
+    if res.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(serde_json::from_slice::<Evidence>(&res)?))
+}
+
+/// Checks that the sender may export `evidence`: it recorded the verification, or owns the
+/// policy the quote was checked against.
+fn check_access(evidence: &Evidence) -> Result<(), Box<dyn std::error::Error>> {
+    let sender = klave::context::get("sender")?;
+    if evidence.sender == sender {
+        return Ok(());
+    }
+    if let Some(outcome) = &evidence.policy {
+        if policy::policy_owner(&outcome.name)?.as_deref() == Some(sender.as_str()) {
+            return Ok(());
+        }
+    }
+    Err(format!("not allowed to export evidence {}", evidence.id).into())
+}
+
+/// Builds the document used to re-verify an evidence entry. It references the collateral of
+/// the verification by number and date, which must be fetched again from the Intel PCS.
+pub fn build_bundle(id: u64) -> Result<Value, Box<dyn std::error::Error>> {
+    let Some(evidence) = load_evidence(id)? else {
+        return Err(format!("evidence {id} not found").into());
+    };
+    check_access(&evidence)?;
+    let quote = klave::ledger::get_table(EVIDENCE_QUOTE_TABLE).get(&evidence.quote_hash)?;
+    if quote.is_empty() {
+        return Err(format!("quote {} not found", evidence.quote_hash).into());
+    }
+    // Quotes that are not SGX quotes are still exported, without their parsed form
+    let parsed = quote::parse(&quote).ok();
+    let policy = match &evidence.policy {
+        Some(outcome) => Some(json!({
+            "name": outcome.name,
+            "version": outcome.version,
+            "definition": policy::load_policy_version(&outcome.name, outcome.version)?,
+        })),
+        None => None,
+    };
+    Ok(json!({
+        "format": BUNDLE_FORMAT,
+        "evidence": evidence,
+        "quote": hex::encode(&quote),
+        "parsed_quote": parsed,
+        "collateral": evidence.collateral,
+        "policy": policy,
+    }))
+}
+
+pub fn export_evidence(cmd: String) {
+    let Ok(input) = serde_json::from_str::<ExportEvidenceInput>(&cmd) else {
+        klave::notifier::send_string(&format!("failed to parse '{cmd}' as json"));
+        return;
+    };
+    match build_bundle(input.evidence_id) {
+        Ok(bundle) => {
+            let _ = klave::notifier::send_json(&bundle);
+        }
+        Err(e) => {
+            klave::notifier::send_string(&format!("Failed to export evidence: {e}"));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_supplemental_data() {
+        let mut data = Vec::new();
+        data.extend_from_slice(&3u16.to_le_bytes());
+        data.extend_from_slice(&1u16.to_le_bytes());
+        for date in [1_700_000_000i64, 1_700_100_000, 1_702_000_000, 1_690_000_000] {
+            data.extend_from_slice(&date.to_le_bytes());
+        }
+        for num in [7u32, 2, 17] {
+            data.extend_from_slice(&num.to_le_bytes());
+        }
+        data.extend_from_slice(&[0xab; 48]);
+        data.extend_from_slice(&[0; 64]);
+
+        let supplemental = SupplementalData::parse(&data).unwrap();
+        assert_eq!(supplemental.major_version, 3);
+        assert_eq!(supplemental.minor_version, 1);
+        assert_eq!(supplemental.earliest_issue_date, 1_700_000_000);
+        assert_eq!(supplemental.latest_issue_date, 1_700_100_000);
+        assert_eq!(supplemental.earliest_expiration_date, 1_702_000_000);
+        assert_eq!(supplemental.tcb_level_date_tag, 1_690_000_000);
+        assert_eq!(supplemental.pck_crl_num, 7);
+        assert_eq!(supplemental.root_ca_crl_num, 2);
+        assert_eq!(supplemental.tcb_eval_ref_num, 17);
+        assert_eq!(supplemental.root_key_id, "ab".repeat(48));
+
+        assert!(SupplementalData::parse(&data[..60]).is_err());
+    }
+}
@@ -0,0 +1,394 @@
+use klave::crypto::subtle::{
+    self, AesKeyGenParams, CryptoKey, DerivedKeyAlgorithm, EcKeyGenParams, EcdhDerivParams,
+    HkdfDerivParams, KeyDerivationAlgorithm, KeyGenAlgorithm,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::{attested, policy};
+
+pub(crate) const SESSION_TABLE: &str = "handshake_sessions";
+
+const INIT_LABEL: &[u8] = b"klave-handshake-init";
+const RESPOND_LABEL: &[u8] = b"klave-handshake-respond";
+const SESSION_KEY_SALT: &[u8] = b"klave-handshake-session-key";
+const SESSION_ID_SIZE: i32 = 16;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    Initiator,
+    Responder,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum State {
+    /// The initiator is waiting for the responder's quote.
+    Initiated,
+    /// Both quotes were verified and the session key is stored.
+    Established,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Session {
+    pub session_id: String,
+    /// Sender that opened the session on this side, the only one allowed to read or complete it.
+    pub owner: String,
+    pub role: Role,
+    pub state: State,
+    /// Policy the peer's quote is verified against.
+    pub policy: String,
+    /// Own ephemeral public key, SPKI encoded, hex.
+    pub public_key: String,
+    pub peer_public_key: Option<String>,
+    /// MRENCLAVE of the peer, or MRTD when the peer is a TD.
+    pub peer_mr_enclave: Option<String>,
+    pub created_at: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct InitInput {
+    pub policy: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RespondInput {
+    pub session_id: String,
+    /// Initiator's ephemeral public key, SPKI encoded, hex.
+    pub peer_public_key: String,
+    /// Initiator's quote, hex.
+    pub peer_quote: String,
+    pub policy: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CompleteInput {
+    pub session_id: String,
+    /// Responder's ephemeral public key, SPKI encoded, hex.
+    pub peer_public_key: String,
+    /// Responder's quote, hex.
+    pub peer_quote: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct GetSessionInput {
+    pub session_id: String,
+}
+
+/// Transcript hashed into the initiator's report data:
+/// `"klave-handshake-init" || session_id || initiator public key`, each field length prefixed.
+pub fn init_transcript(session_id: &str, initiator_key: &[u8]) -> Vec<u8> {
+    attested::transcript(INIT_LABEL, &[session_id.as_bytes(), initiator_key])
+}
+
+/// Transcript hashed into the responder's report data:
+/// `"klave-handshake-respond" || session_id || initiator public key || responder public key`,
+/// each field length prefixed.
+///
+/// Including the initiator's key binds the responder's quote to this handshake, so it cannot be
+/// replayed in another one.
+pub fn respond_transcript(session_id: &str, initiator_key: &[u8], responder_key: &[u8]) -> Vec<u8> {
+    attested::transcript(
+        RESPOND_LABEL,
+        &[session_id.as_bytes(), initiator_key, responder_key],
+    )
+}
+
+fn ephemeral_key_name(session_id: &str) -> String {
+    format!("handshake_{session_id}")
+}
+
+fn session_key_name(session_id: &str) -> String {
+    format!("session_{session_id}")
+}
+
+fn load_session(session_id: &str) -> Result<Option<Session>, Box<dyn std::error::Error>> {
+    let res = klave::ledger::get_table(SESSION_TABLE).get(session_id)?;
+    if res.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(serde_json::from_slice::<Session>(&res)?))
+}
+
+/// Loads a session, checking that the sender owns it.
+fn load_owned_session(session_id: &str) -> Result<Session, Box<dyn std::error::Error>> {
+    let Some(session) = load_session(session_id)? else {
+        return Err(format!("session '{session_id}' not found").into());
+    };
+    if session.owner != klave::context::get("sender")? {
+        return Err(format!("only the owner of session '{session_id}' can access it").into());
+    }
+    Ok(session)
+}
+
+fn save_session(session: &Session) -> Result<(), Box<dyn std::error::Error>> {
+    klave::ledger::get_table(SESSION_TABLE).set(&session.session_id, &serde_json::to_vec(session)?)
+}
+
+pub(crate) fn generate_ephemeral_key() -> Result<(CryptoKey, Vec<u8>), Box<dyn std::error::Error>> {
+    let ec_params = subtle::EcKeyGenParams {
+        named_curve: "P-256".to_string(),
+    };
+    let gen_algorithm = subtle::KeyGenAlgorithm::Ecc(ec_params);
+    let private_key = subtle::generate_key(&gen_algorithm, false, &["derive_key"])?;
+    let public_key = subtle::export_key("spki", &subtle::get_public_key(&private_key)?)?;
+    Ok((private_key, public_key))
+}
+
+/// Checks the peer's quote against `policy` and that its report data commits to `transcript`.
+/// Returns the peer's MRENCLAVE, or MRTD for a TD.
+fn verify_peer(
+    peer_quote: &str,
+    policy_name: &str,
+    transcript: &[u8],
+) -> Result<String, Box<dyn std::error::Error>> {
+    let verdict = policy::verify_with_policy(&hex::decode(peer_quote)?, policy_name)?;
+    if !verdict.passed() {
+        let failed = verdict
+            .rules
+            .iter()
+            .filter(|r| !r.passed)
+            .map(|r| format!("{}: {}", r.rule, r.reason))
+            .collect::<Vec<_>>()
+            .join(", ");
+        return Err(format!("peer quote rejected by policy '{policy_name}': {failed}").into());
+    }
+    if !attested::commits_to(verdict.quote.report_data(), transcript)? {
+        return Err("peer quote is not bound to this handshake".into());
+    }
+    Ok(verdict.quote.measurement().to_string())
+}
+
+/// Derives an AES-256-GCM key from our ephemeral private key and the peer's SPKI encoded public
+/// key.
+///
+/// The ECDH shared secret is not used as a key: it is run through HKDF with `transcript` as
+/// info, so that the key is bound to both public keys and to the exchange it was derived for.
+pub(crate) fn derive_shared_key(
+    private_key: &CryptoKey,
+    peer_public_key: &[u8],
+    transcript: &[u8],
+) -> Result<CryptoKey, Box<dyn std::error::Error>> {
+    let import_algorithm = KeyGenAlgorithm::Ecc(EcKeyGenParams {
+        named_curve: "P-256".to_string(),
+    });
+    let peer_key = subtle::import_key("spki", peer_public_key, &import_algorithm, true, &[])?;
+    // The host refers to the peer's public key by its key id
+    let ecdh_algorithm = KeyDerivationAlgorithm::Ecdh(EcdhDerivParams {
+        public: peer_key.name(),
+    });
+    let shared_secret = subtle::derive_key(
+        &ecdh_algorithm,
+        private_key,
+        &DerivedKeyAlgorithm::Aes(AesKeyGenParams { length: 256 }),
+        false,
+        &["derive_key"],
+    )?;
+    let hkdf_algorithm = KeyDerivationAlgorithm::Hkdf(HkdfDerivParams {
+        hash: "SHA-256".to_string(),
+        salt: SESSION_KEY_SALT.to_vec(),
+        info: transcript.to_vec(),
+    });
+    subtle::derive_key(
+        &hkdf_algorithm,
+        &shared_secret,
+        &DerivedKeyAlgorithm::Aes(AesKeyGenParams {
+            length: 256, // AES-256
+        }),
+        false,
+        &["encrypt", "decrypt"],
+    )
+}
+
+/// Derives the session key and saves it in the key store.
+fn derive_session_key(
+    session_id: &str,
+    private_key: &CryptoKey,
+    peer_public_key: &[u8],
+    transcript: &[u8],
+) -> Result<(), Box<dyn std::error::Error>> {
+    let session_key = derive_shared_key(private_key, peer_public_key, transcript)?;
+    subtle::save_key(&session_key, &session_key_name(session_id))
+}
+
+fn init(input: &InitInput) -> Result<(Session, String), Box<dyn std::error::Error>> {
+    let session_id = hex::encode(klave::crypto::random::get_random_bytes(SESSION_ID_SIZE)?);
+    let (private_key, public_key) = generate_ephemeral_key()?;
+    subtle::save_key(&private_key, &ephemeral_key_name(&session_id))?;
+    let quote = attested::transcript_quote(&init_transcript(&session_id, &public_key))?;
+    let session = Session {
+        session_id,
+        owner: klave::context::get("sender")?,
+        role: Role::Initiator,
+        state: State::Initiated,
+        policy: input.policy.clone(),
+        public_key: hex::encode(public_key),
+        peer_public_key: None,
+        peer_mr_enclave: None,
+        created_at: klave::context::get("trusted_time")?.parse::<u64>()?,
+    };
+    save_session(&session)?;
+    Ok((session, quote))
+}
+
+fn respond(input: &RespondInput) -> Result<(Session, String), Box<dyn std::error::Error>> {
+    if load_session(&input.session_id)?.is_some() {
+        return Err(format!("session '{}' already exists", input.session_id).into());
+    }
+    let peer_public_key = hex::decode(&input.peer_public_key)?;
+    let peer_mr_enclave = verify_peer(
+        &input.peer_quote,
+        &input.policy,
+        &init_transcript(&input.session_id, &peer_public_key),
+    )?;
+    // The responder's ephemeral key is never saved, it is dropped once the session key is derived
+    let (private_key, public_key) = generate_ephemeral_key()?;
+    let transcript = respond_transcript(&input.session_id, &peer_public_key, &public_key);
+    derive_session_key(
+        &input.session_id,
+        &private_key,
+        &peer_public_key,
+        &transcript,
+    )?;
+    let quote = attested::transcript_quote(&transcript)?;
+    let session = Session {
+        session_id: input.session_id.clone(),
+        owner: klave::context::get("sender")?,
+        role: Role::Responder,
+        state: State::Established,
+        policy: input.policy.clone(),
+        public_key: hex::encode(public_key),
+        peer_public_key: Some(input.peer_public_key.clone()),
+        peer_mr_enclave: Some(peer_mr_enclave),
+        created_at: klave::context::get("trusted_time")?.parse::<u64>()?,
+    };
+    save_session(&session)?;
+    Ok((session, quote))
+}
+
+fn complete(input: &CompleteInput) -> Result<Session, Box<dyn std::error::Error>> {
+    let mut session = load_owned_session(&input.session_id)?;
+    if session.role != Role::Initiator || session.state != State::Initiated {
+        return Err(format!("session '{}' is not awaiting completion", input.session_id).into());
+    }
+    let peer_public_key = hex::decode(&input.peer_public_key)?;
+    let transcript = respond_transcript(
+        &input.session_id,
+        &hex::decode(&session.public_key)?,
+        &peer_public_key,
+    );
+    session.peer_mr_enclave = Some(verify_peer(
+        &input.peer_quote,
+        &session.policy,
+        &transcript,
+    )?);
+    let private_key = subtle::load_key(&ephemeral_key_name(&input.session_id))?;
+    derive_session_key(
+        &input.session_id,
+        &private_key,
+        &peer_public_key,
+        &transcript,
+    )?;
+    // The ephemeral key is no longer needed once the session key is derived
+    subtle::delete_key(&private_key)?;
+    session.peer_public_key = Some(input.peer_public_key.clone());
+    session.state = State::Established;
+    save_session(&session)?;
+    Ok(session)
+}
+
+pub fn handshake_init(cmd: String) {
+    let Ok(input) = serde_json::from_str::<InitInput>(&cmd) else {
+        klave::notifier::send_string(&format!("failed to parse '{cmd}' as json"));
+        klave::router::cancel_transaction();
+        return;
+    };
+    match init(&input) {
+        Ok((session, quote)) => {
+            let _ = klave::notifier::send_json(&json!({
+                "session_id": session.session_id,
+                "public_key": session.public_key,
+                "quote": quote
+            }));
+        }
+        Err(e) => {
+            klave::notifier::send_string(&format!("Failed to initiate handshake: {e}"));
+            klave::router::cancel_transaction();
+        }
+    }
+}
+
+pub fn handshake_respond(cmd: String) {
+    let Ok(input) = serde_json::from_str::<RespondInput>(&cmd) else {
+        klave::notifier::send_string(&format!("failed to parse '{cmd}' as json"));
+        klave::router::cancel_transaction();
+        return;
+    };
+    match respond(&input) {
+        Ok((session, quote)) => {
+            let _ = klave::notifier::send_json(&json!({
+                "session_id": session.session_id,
+                "public_key": session.public_key,
+                "quote": quote
+            }));
+        }
+        Err(e) => {
+            klave::notifier::send_string(&format!("Failed to respond to handshake: {e}"));
+            klave::router::cancel_transaction();
+        }
+    }
+}
+
+pub fn handshake_complete(cmd: String) {
+    let Ok(input) = serde_json::from_str::<CompleteInput>(&cmd) else {
+        klave::notifier::send_string(&format!("failed to parse '{cmd}' as json"));
+        klave::router::cancel_transaction();
+        return;
+    };
+    match complete(&input) {
+        Ok(session) => {
+            let _ = klave::notifier::send_json(&session);
+        }
+        Err(e) => {
+            klave::notifier::send_string(&format!("Failed to complete handshake: {e}"));
+            klave::router::cancel_transaction();
+        }
+    }
+}
+
+pub fn get_session(cmd: String) {
+    let Ok(input) = serde_json::from_str::<GetSessionInput>(&cmd) else {
+        klave::notifier::send_string(&format!("failed to parse '{cmd}' as json"));
+        return;
+    };
+    match load_owned_session(&input.session_id) {
+        Ok(session) => {
+            let _ = klave::notifier::send_json(&session);
+        }
+        Err(e) => {
+            klave::notifier::send_string(&format!("Failed to load session: {e}"));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_transcripts() {
+        let init = init_transcript("01", &[1, 2]);
+        assert_eq!(
+            init,
+            [INIT_LABEL, &[0, 0, 0, 2], b"01", &[0, 0, 0, 2], &[1, 2]].concat()
+        );
+
+        let respond = respond_transcript("01", &[1, 2], &[3]);
+        assert!(respond.starts_with(RESPOND_LABEL));
+        assert!(respond.ends_with(&[0, 0, 0, 2, 1, 2, 0, 0, 0, 1, 3]));
+        assert_ne!(respond_transcript("02", &[1, 2], &[3]), respond);
+        // Moving a byte from one key to the other gives another transcript
+        assert_ne!(respond_transcript("01", &[1], &[2, 3]), respond);
+    }
+}
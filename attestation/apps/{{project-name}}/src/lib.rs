@@ -1,5 +1,12 @@
 #[allow(warnings)]
 mod bindings;
+mod attested;
+mod broker;
+mod enclave_key;
+mod evidence;
+mod handshake;
+mod policy;
+mod quote;
 
 use bindings::Guest;
 use serde_json::json;
@@ -9,8 +16,22 @@ struct Component;
 impl Guest for Component {
     fn register_routes() {
         klave::router::add_user_query("get-quote-binary");
-        klave::router::add_user_query("verify-quote");
+        klave::router::add_user_transaction("verify-quote");
         klave::router::add_user_query("parse-quote");
+        klave::router::add_user_transaction("generate-enclave-key");
+        klave::router::add_user_query("get-enclave-public-key");
+        klave::router::add_user_query("get-attested-response");
+        klave::router::add_user_transaction("set-policy");
+        klave::router::add_user_query("get-policy");
+        klave::router::add_user_transaction("verify-quote-with-policy");
+        klave::router::add_user_transaction("handshake-init");
+        klave::router::add_user_transaction("handshake-respond");
+        klave::router::add_user_transaction("handshake-complete");
+        klave::router::add_user_query("get-session");
+        klave::router::add_user_query("export-evidence");
+        klave::router::add_user_transaction("put-secret");
+        klave::router::add_user_transaction("delete-secret");
+        klave::router::add_user_transaction("release-secret");
     }
 
     fn get_quote_binary(cmd: String) {
@@ -33,58 +54,77 @@ impl Guest for Component {
     fn verify_quote(cmd: String) {
         let Ok(v) = serde_json::from_str::<Value>(&cmd) else {
             klave::notifier::send_string(&format!("failed to parse '{cmd}' as json"));
+            klave::router::cancel_transaction();
             return;
         };
 
-        let Some(quote) = v.get("quote").and_then(|val| val.as_array()).map(|arr| {
-            arr.iter()
-                .filter_map(|v| v.as_u64().and_then(|n| u8::try_from(n).ok()))
-                .collect::<Vec<u8>>()
-        }) else {
-            klave::notifier::send_string("Failed to extract 'quote' as Vec<u8>");
-            return;
+        let quote = match quote::extract_quote(&v) {
+            Ok(quote) => quote,
+            Err(e) => {
+                klave::notifier::send_string(&format!("Failed to extract 'quote': {e}"));
+                klave::router::cancel_transaction();
+                return;
+            }
         };
 
         let Ok(current_time_str) = klave::context::get("trusted_time") else {
             klave::notifier::send_string("Failed to get current time");
+            klave::router::cancel_transaction();
             return;
         };
 
         let Ok(current_time) = current_time_str.parse::<i64>() else {
             klave::notifier::send_string("Failed to parse current time as i64");
+            klave::router::cancel_transaction();
             return;
         };
 
-        let quote_verification = match klave::attestation::verify_quote(&quote, current_time) {
+        let response = match klave::attestation::verify_quote(&quote, current_time) {
             Ok(v) => v,
             Err(e) => {
                 klave::notifier::send_string(&format!("Failed to verify quote: {e}"));
+                klave::router::cancel_transaction();
+                return;
+            }
+        };
+        let mut quote_verification = match serde_json::to_value(&response) {
+            Ok(v) => v,
+            Err(e) => {
+                klave::notifier::send_string(&format!("Failed to serialize verification: {e}"));
+                klave::router::cancel_transaction();
+                return;
+            }
+        };
+
+        let evidence_id = match evidence::record(&quote, &response, None) {
+            Ok(id) => id,
+            Err(e) => {
+                klave::notifier::send_string(&format!("Failed to record evidence: {e}"));
+                klave::router::cancel_transaction();
                 return;
             }
         };
+        if let Some(result) = quote_verification.as_object_mut() {
+            result.insert("evidence_id".to_string(), json!(evidence_id));
+        }
 
         let _ = klave::notifier::send_json(&quote_verification);
     }
 
     fn parse_quote(cmd: String) {
-        let Ok(quote) = serde_json::from_str::<Value>(&cmd) else {
+        let Ok(v) = serde_json::from_str::<Value>(&cmd) else {
             klave::notifier::send_string(&format!("failed to parse '{cmd}' as json"));
             return;
         };
-        let Some(quote) = quote
-            .get("quote")
-            .and_then(|val| val.as_array())
-            .map(|arr| {
-                arr.iter()
-                    .filter_map(|v| v.as_u64().and_then(|n| u8::try_from(n).ok()))
-                    .collect::<Vec<u8>>()
-            })
-        else {
-            klave::notifier::send_string("Failed to extract 'quote' as Vec<u8>");
-            return;
+        let quote = match quote::extract_quote(&v) {
+            Ok(quote) => quote,
+            Err(e) => {
+                klave::notifier::send_string(&format!("Failed to extract 'quote': {e}"));
+                return;
+            }
         };
 
-        let parsed_quote = match klave::attestation::parse_quote(&quote) {
+        let parsed_quote = match quote::parse(&quote) {
             Ok(q) => q,
             Err(e) => {
                 klave::notifier::send_string(&format!("Failed to parse quote: {e}"));
@@ -94,6 +134,62 @@ impl Guest for Component {
 
         let _ = klave::notifier::send_json(&parsed_quote);
     }
+
+    fn generate_enclave_key(cmd: String) {
+        enclave_key::generate_enclave_key(cmd);
+    }
+
+    fn get_enclave_public_key(cmd: String) {
+        enclave_key::get_enclave_public_key(cmd);
+    }
+
+    fn get_attested_response(cmd: String) {
+        attested::get_attested_response(cmd);
+    }
+
+    fn set_policy(cmd: String) {
+        policy::set_policy(cmd);
+    }
+
+    fn get_policy(cmd: String) {
+        policy::get_policy(cmd);
+    }
+
+    fn verify_quote_with_policy(cmd: String) {
+        policy::verify_quote_with_policy(cmd);
+    }
+
+    fn handshake_init(cmd: String) {
+        handshake::handshake_init(cmd);
+    }
+
+    fn handshake_respond(cmd: String) {
+        handshake::handshake_respond(cmd);
+    }
+
+    fn handshake_complete(cmd: String) {
+        handshake::handshake_complete(cmd);
+    }
+
+    fn get_session(cmd: String) {
+        handshake::get_session(cmd);
+    }
+
+    fn export_evidence(cmd: String) {
+        evidence::export_evidence(cmd);
+    }
+
+    fn put_secret(cmd: String) {
+        broker::put_secret(cmd);
+    }
+
+    fn delete_secret(cmd: String) {
+        broker::delete_secret(cmd);
+    }
+
+    fn release_secret(cmd: String) {
+        broker::release_secret(cmd);
+    }
 }
 
 bindings::export!(Component with_types_in bindings);
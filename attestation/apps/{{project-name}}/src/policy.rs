@@ -0,0 +1,504 @@
+use klave::attestation::VerifyQuoteResponse;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::{
+    evidence::{self, PolicyOutcome, SupplementalData},
+    quote::{self, Quote},
+};
+
+pub(crate) const POLICY_TABLE: &str = "attestation_policies";
+pub(crate) const POLICY_VERSION_TABLE: &str = "attestation_policy_versions";
+
+/// `sgx_ql_qv_result_t` values, as returned in `quote_verification_result`.
+const TCB_STATUSES: [(i32, &str); 9] = [
+    (0x0000, "OK"),
+    (0xA001, "CONFIG_NEEDED"),
+    (0xA002, "OUT_OF_DATE"),
+    (0xA003, "OUT_OF_DATE_CONFIG_NEEDED"),
+    (0xA004, "INVALID_SIGNATURE"),
+    (0xA005, "REVOKED"),
+    (0xA006, "UNSPECIFIED"),
+    (0xA007, "SW_HARDENING_NEEDED"),
+    (0xA008, "CONFIG_AND_SW_HARDENING_NEEDED"),
+];
+
+/// Rules a quote must satisfy. Empty lists and `None` values disable the matching rule,
+/// except for `accepted_tcb_statuses` which only accepts `OK` when empty.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Policy {
+    /// Hex encoded MRENCLAVE values.
+    #[serde(default)]
+    pub mr_enclaves: Vec<String>,
+    /// Hex encoded MRSIGNER values.
+    #[serde(default)]
+    pub mr_signers: Vec<String>,
+    pub min_isv_svn: Option<u64>,
+    #[serde(default)]
+    pub accepted_tcb_statuses: Vec<String>,
+    /// Maximum age, in seconds, of the collateral the quote was verified against.
+    pub max_quote_age_seconds: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct StoredPolicy {
+    owner: String,
+    /// Incremented by every `set-policy`, previous versions are kept in `POLICY_VERSION_TABLE`.
+    #[serde(default)]
+    version: u64,
+    policy: Policy,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SetPolicyInput {
+    pub name: String,
+    pub policy: Policy,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct GetPolicyInput {
+    pub name: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct RuleResult {
+    pub rule: String,
+    pub passed: bool,
+    pub reason: String,
+}
+
+impl RuleResult {
+    fn new(rule: &str, passed: bool, reason: String) -> Self {
+        RuleResult {
+            rule: rule.to_string(),
+            passed,
+            reason,
+        }
+    }
+}
+
+fn tcb_status(verification: &VerifyQuoteResponse) -> String {
+    let code = verification.quote_verification_result;
+    TCB_STATUSES
+        .iter()
+        .find(|(c, _)| *c == code)
+        .map(|(_, name)| name.to_string())
+        .unwrap_or_else(|| format!("{code:#06X}"))
+}
+
+/// Latest issue date of the collateral the quote was verified against, in seconds.
+fn latest_issue_date(verification: &VerifyQuoteResponse) -> Option<u64> {
+    let supplemental = SupplementalData::parse(&verification.supp_data.data).ok()?;
+    u64::try_from(supplemental.latest_issue_date).ok()
+}
+
+fn check_measurement(rule: &str, actual: &str, allowed: &[String]) -> RuleResult {
+    if allowed
+        .iter()
+        .any(|a| a.trim_start_matches("0x").eq_ignore_ascii_case(actual))
+    {
+        RuleResult::new(rule, true, format!("{actual} is allowed"))
+    } else {
+        RuleResult::new(rule, false, format!("{actual} is not allowed"))
+    }
+}
+
+/// Evaluates every rule of `policy` against a parsed quote and the output of
+/// `klave::attestation::verify_quote`. `trusted_time` is in nanoseconds, collateral dates
+/// in seconds.
+pub fn evaluate(
+    policy: &Policy,
+    verification: &VerifyQuoteResponse,
+    quote: &Quote,
+    trusted_time: u64,
+) -> Vec<RuleResult> {
+    let mut results = Vec::new();
+    // MRENCLAVE, MRSIGNER and ISVSVN only exist in the report of an SGX enclave
+    let sgx_rule = |rule: &str| {
+        quote
+            .td_report_body
+            .as_ref()
+            .map(|_| RuleResult::new(rule, false, "not an SGX enclave quote".into()))
+    };
+    if !policy.mr_enclaves.is_empty() {
+        results.push(sgx_rule("mr_enclave").unwrap_or_else(|| {
+            check_measurement(
+                "mr_enclave",
+                &quote.report_body.mr_enclave,
+                &policy.mr_enclaves,
+            )
+        }));
+    }
+    if !policy.mr_signers.is_empty() {
+        results.push(sgx_rule("mr_signer").unwrap_or_else(|| {
+            check_measurement(
+                "mr_signer",
+                &quote.report_body.mr_signer,
+                &policy.mr_signers,
+            )
+        }));
+    }
+    if let Some(min_isv_svn) = policy.min_isv_svn {
+        let svn = quote.report_body.isv_svn as u64;
+        results.push(sgx_rule("min_isv_svn").unwrap_or_else(|| {
+            if svn >= min_isv_svn {
+                RuleResult::new("min_isv_svn", true, format!("{svn} >= {min_isv_svn}"))
+            } else {
+                RuleResult::new("min_isv_svn", false, format!("{svn} < {min_isv_svn}"))
+            }
+        }));
+    }
+    let accepted = if policy.accepted_tcb_statuses.is_empty() {
+        vec!["OK".to_string()]
+    } else {
+        policy
+            .accepted_tcb_statuses
+            .iter()
+            .map(|s| s.to_uppercase())
+            .collect()
+    };
+    let status = tcb_status(verification);
+    results.push(if accepted.contains(&status) {
+        RuleResult::new("tcb_status", true, format!("{status} is accepted"))
+    } else {
+        RuleResult::new("tcb_status", false, format!("{status} is not accepted"))
+    });
+    if let Some(max_age) = policy.max_quote_age_seconds {
+        let now = trusted_time / 1_000_000_000;
+        let issued = latest_issue_date(verification);
+        results.push(match issued.map(|issued| now.saturating_sub(issued)) {
+            Some(age) if age <= max_age => {
+                RuleResult::new("max_quote_age", true, format!("{age}s <= {max_age}s"))
+            }
+            Some(age) => RuleResult::new("max_quote_age", false, format!("{age}s > {max_age}s")),
+            None => RuleResult::new(
+                "max_quote_age",
+                false,
+                "'latest_issue_date' not found in supplemental data".into(),
+            ),
+        });
+    }
+    results
+}
+
+fn policy_version_key(name: &str, version: u64) -> String {
+    format!("{name}/{version:020}")
+}
+
+/// Loads the rules of policy `name` as they were at `version`.
+pub fn load_policy_version(
+    name: &str,
+    version: u64,
+) -> Result<Option<Policy>, Box<dyn std::error::Error>> {
+    let res =
+        klave::ledger::get_table(POLICY_VERSION_TABLE).get(&policy_version_key(name, version))?;
+    if res.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(serde_json::from_slice::<Policy>(&res)?))
+}
+
+fn load_policy(name: &str) -> Result<Option<StoredPolicy>, Box<dyn std::error::Error>> {
+    let res = klave::ledger::get_table(POLICY_TABLE).get(name)?;
+    if res.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(serde_json::from_slice::<StoredPolicy>(&res)?))
+}
+
+pub fn policy_owner(name: &str) -> Result<Option<String>, Box<dyn std::error::Error>> {
+    Ok(load_policy(name)?.map(|stored| stored.owner))
+}
+
+pub fn policy_owner_and_version(
+    name: &str,
+) -> Result<Option<(String, u64)>, Box<dyn std::error::Error>> {
+    Ok(load_policy(name)?.map(|stored| (stored.owner, stored.version)))
+}
+
+pub fn set_policy(cmd: String) {
+    let Ok(input) = serde_json::from_str::<SetPolicyInput>(&cmd) else {
+        klave::notifier::send_string(&format!("failed to parse '{cmd}' as json"));
+        klave::router::cancel_transaction();
+        return;
+    };
+    let Ok(sender) = klave::context::get("sender") else {
+        klave::notifier::send_string("Failed to get sender");
+        klave::router::cancel_transaction();
+        return;
+    };
+    let (owner, version) = match load_policy(&input.name) {
+        Ok(Some(stored)) if stored.owner != sender => {
+            klave::notifier::send_string(&format!(
+                "only the owner of policy '{}' can update it",
+                input.name
+            ));
+            klave::router::cancel_transaction();
+            return;
+        }
+        Ok(Some(stored)) => (stored.owner, stored.version + 1),
+        Ok(None) => (sender, 1),
+        Err(e) => {
+            klave::notifier::send_string(&format!("Failed to load policy: {e}"));
+            klave::router::cancel_transaction();
+            return;
+        }
+    };
+    let stored = StoredPolicy {
+        owner,
+        version,
+        policy: input.policy,
+    };
+    let save = || -> Result<(), Box<dyn std::error::Error>> {
+        klave::ledger::get_table(POLICY_VERSION_TABLE).set(
+            &policy_version_key(&input.name, version),
+            &serde_json::to_vec(&stored.policy)?,
+        )?;
+        klave::ledger::get_table(POLICY_TABLE).set(&input.name, &serde_json::to_vec(&stored)?)
+    };
+    if let Err(e) = save() {
+        klave::notifier::send_string(&format!("Failed to save policy: {e}"));
+        klave::router::cancel_transaction();
+        return;
+    }
+    let _ = klave::notifier::send_json(&json!({
+        "name": input.name,
+        "owner": stored.owner,
+        "version": stored.version,
+        "policy": stored.policy
+    }));
+}
+
+pub fn get_policy(cmd: String) {
+    let Ok(input) = serde_json::from_str::<GetPolicyInput>(&cmd) else {
+        klave::notifier::send_string(&format!("failed to parse '{cmd}' as json"));
+        return;
+    };
+    match load_policy(&input.name) {
+        Ok(Some(stored)) => {
+            let _ = klave::notifier::send_json(&json!({
+                "name": input.name,
+                "owner": stored.owner,
+                "version": stored.version,
+                "policy": stored.policy
+            }));
+        }
+        Ok(None) => {
+            klave::notifier::send_string(&format!("policy '{}' not found", input.name));
+        }
+        Err(e) => {
+            klave::notifier::send_string(&format!("Failed to load policy: {e}"));
+        }
+    }
+}
+
+/// Outcome of checking a quote against a stored policy.
+pub struct PolicyVerdict {
+    pub policy_version: u64,
+    pub evidence_id: u64,
+    pub quote: Quote,
+    pub verification: Value,
+    pub rules: Vec<RuleResult>,
+}
+
+impl PolicyVerdict {
+    pub fn passed(&self) -> bool {
+        self.rules.iter().all(|r| r.passed)
+    }
+}
+
+/// Verifies and parses `quote`, then evaluates the policy stored under `name` against it.
+/// The outcome is recorded in the evidence log.
+pub fn verify_with_policy(
+    quote: &[u8],
+    name: &str,
+) -> Result<PolicyVerdict, Box<dyn std::error::Error>> {
+    let Some(stored) = load_policy(name)? else {
+        return Err(format!("policy '{name}' not found").into());
+    };
+    let current_time = klave::context::get("trusted_time")?.parse::<i64>()?;
+    let response = klave::attestation::verify_quote(quote, current_time)?;
+    let parsed = quote::parse(quote)?;
+    let rules = evaluate(&stored.policy, &response, &parsed, current_time as u64);
+    let verification = serde_json::to_value(&response)?;
+    let evidence_id = evidence::record(
+        quote,
+        &response,
+        Some(PolicyOutcome {
+            name: name.to_string(),
+            version: stored.version,
+            passed: rules.iter().all(|r| r.passed),
+            rules: rules.clone(),
+        }),
+    )?;
+    Ok(PolicyVerdict {
+        policy_version: stored.version,
+        evidence_id,
+        quote: parsed,
+        verification,
+        rules,
+    })
+}
+
+pub fn verify_quote_with_policy(cmd: String) {
+    let Ok(v) = serde_json::from_str::<Value>(&cmd) else {
+        klave::notifier::send_string(&format!("failed to parse '{cmd}' as json"));
+        klave::router::cancel_transaction();
+        return;
+    };
+    let quote = match quote::extract_quote(&v) {
+        Ok(quote) => quote,
+        Err(e) => {
+            klave::notifier::send_string(&format!("Failed to extract 'quote': {e}"));
+            klave::router::cancel_transaction();
+            return;
+        }
+    };
+    let Some(name) = v.get("policy").and_then(|p| p.as_str()).map(str::to_string) else {
+        klave::notifier::send_string("'policy' is required");
+        klave::router::cancel_transaction();
+        return;
+    };
+
+    match verify_with_policy(&quote, &name) {
+        Ok(verdict) => {
+            let _ = klave::notifier::send_json(&json!({
+                "policy": name,
+                "policy_version": verdict.policy_version,
+                "evidence_id": verdict.evidence_id,
+                "passed": verdict.passed(),
+                "rules": verdict.rules,
+                "verification": verdict.verification
+            }));
+        }
+        Err(e) => {
+            klave::notifier::send_string(&format!("Failed to verify quote with policy: {e}"));
+            klave::router::cancel_transaction();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::quote::ReportBody;
+
+    /// `sgx_ql_qv_supplemental_t` prefix: version 3.0, then the earliest and latest issue dates.
+    fn supplemental_data(latest_issue_date: i64) -> Vec<u8> {
+        let mut data = 3u32.to_le_bytes().to_vec();
+        data.extend(1_600_000_000i64.to_le_bytes());
+        data.extend(latest_issue_date.to_le_bytes());
+        data.resize(256, 0);
+        data
+    }
+
+    fn report_body() -> Value {
+        json!({
+            "cpu_svn": { "data": vec![0u8; 16] },
+            "misc_select": { "data": 0 },
+            "reserved1": vec![0u8; 12],
+            "isv_ext_prod_id": { "data": vec![0u8; 16] },
+            "attributes": { "flags": 0, "xfrm": 0 },
+            "mr_enclave": { "data": vec![0u8; 32] },
+            "reserved2": vec![0u8; 32],
+            "mr_signer": { "data": vec![0u8; 32] },
+            "reserved3": vec![0u8; 32],
+            "config_id": { "data": vec![0u8; 64] },
+            "isv_prod_id": { "data": 0 },
+            "isv_svn": { "data": 0 },
+            "config_svn": { "data": 0 },
+            "reserved4": vec![0u8; 42],
+            "isv_family_id": { "data": vec![0u8; 16] },
+            "report_data": { "data": vec![0u8; 64] }
+        })
+    }
+
+    fn fixtures() -> (VerifyQuoteResponse, Quote) {
+        // Output of verify_quote as the host serializes it
+        let verification = serde_json::from_value::<VerifyQuoteResponse>(json!({
+            "collateral_expiration_status": 0,
+            "quote_verification_result": 0xA007,
+            "qve_report_info": {
+                "nonce": { "rand": vec![0u8; 16] },
+                "enclave_target_info": {
+                    "mr_enclave": { "data": vec![0u8; 32] },
+                    "attributes": { "flags": 0, "xfrm": 0 },
+                    "reserved1": vec![0u8; 2],
+                    "config_svn": { "data": 0 },
+                    "misc_select": { "data": 0 },
+                    "reserved2": vec![0u8; 8],
+                    "config_id": { "data": vec![0u8; 64] },
+                    "reserved3": vec![0u8; 384]
+                },
+                "qe_report": {
+                    "body": report_body(),
+                    "key_id": { "data": vec![0u8; 32] },
+                    "mac": { "data": vec![0u8; 16] }
+                }
+            },
+            "quote_verification_result_description": "SW_HARDENING_NEEDED",
+            "sa_list": "INTEL-SA-00615",
+            "supp_data": {
+                "major_version": 3,
+                "data": supplemental_data(1_700_000_000)
+            }
+        }))
+        .unwrap();
+        let parsed = Quote {
+            report_body: ReportBody {
+                mr_enclave: "aa".repeat(32),
+                mr_signer: "bb".repeat(32),
+                isv_svn: 3,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        (verification, parsed)
+    }
+
+    #[test]
+    fn test_evaluate_pass() {
+        let (verification, parsed) = fixtures();
+        let policy = Policy {
+            mr_enclaves: vec!["AA".repeat(32)],
+            mr_signers: vec![format!("0x{}", "bb".repeat(32))],
+            min_isv_svn: Some(2),
+            accepted_tcb_statuses: vec!["ok".into(), "sw_hardening_needed".into()],
+            max_quote_age_seconds: Some(3600),
+        };
+        let rules = evaluate(
+            &policy,
+            &verification,
+            &parsed,
+            1_700_000_100 * 1_000_000_000,
+        );
+        assert_eq!(rules.len(), 5);
+        assert!(rules.iter().all(|r| r.passed), "{rules:?}");
+    }
+
+    #[test]
+    fn test_evaluate_fail() {
+        let (verification, parsed) = fixtures();
+        let policy = Policy {
+            mr_enclaves: vec!["cc".repeat(32)],
+            min_isv_svn: Some(4),
+            max_quote_age_seconds: Some(10),
+            ..Default::default()
+        };
+        let rules = evaluate(
+            &policy,
+            &verification,
+            &parsed,
+            1_700_000_100 * 1_000_000_000,
+        );
+        let failed: Vec<&str> = rules
+            .iter()
+            .filter(|r| !r.passed)
+            .map(|r| r.rule.as_str())
+            .collect();
+        assert_eq!(
+            failed,
+            vec!["mr_enclave", "min_isv_svn", "tcb_status", "max_quote_age"]
+        );
+    }
+}
@@ -0,0 +1,480 @@
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use serde::Serialize;
+use serde_json::Value;
+
+const HEADER_SIZE: usize = 48;
+const REPORT_BODY_SIZE: usize = 384;
+const TD_REPORT_BODY_SIZE: usize = 584;
+const SGX_TEE_TYPE: u32 = 0x0000_0000;
+const TDX_TEE_TYPE: u32 = 0x0000_0081;
+/// Certification data holding the PCK certificate chain as concatenated PEM certificates.
+const PCK_CERT_CHAIN: u16 = 5;
+/// Certification data wrapping the QE report, used by version 4 quotes.
+const QE_REPORT_CERTIFICATION_DATA: u16 = 6;
+
+/// Reads a quote from the `quote` field of a request.
+///
+/// The quote is either a JSON array of bytes or a string encoded as `hex` (default) or
+/// `base64`, as given by the optional `encoding` field. Malformed input is rejected rather than
+/// skipped.
+pub fn extract_quote(v: &Value) -> Result<Vec<u8>, String> {
+    let encoding = v.get("encoding").and_then(|e| e.as_str());
+    match (v.get("quote"), encoding) {
+        (Some(Value::Array(arr)), None) => arr
+            .iter()
+            .enumerate()
+            .map(|(i, b)| {
+                b.as_u64()
+                    .and_then(|n| u8::try_from(n).ok())
+                    .ok_or_else(|| format!("quote byte {i} is not an integer in 0..=255: {b}"))
+            })
+            .collect(),
+        (Some(Value::String(s)), None | Some("hex")) => {
+            hex::decode(s.trim_start_matches("0x")).map_err(|e| format!("invalid hex quote: {e}"))
+        }
+        (Some(Value::String(s)), Some("base64")) => STANDARD
+            .decode(s)
+            .map_err(|e| format!("invalid base64 quote: {e}")),
+        (Some(Value::Array(_)), Some(_)) => {
+            Err("'quote' must be a string when 'encoding' is given".to_string())
+        }
+        (Some(_), Some(encoding)) => Err(format!(
+            "unsupported quote encoding '{encoding}', expected 'hex' or 'base64'"
+        )),
+        (Some(_), None) => Err("'quote' must be an array of bytes or a string".to_string()),
+        (None, _) => Err("missing 'quote' field".to_string()),
+    }
+}
+
+/// Quote header, common to every quote version.
+#[derive(Serialize, Debug, Default, Clone, PartialEq)]
+pub struct QuoteHeader {
+    pub version: u16,
+    /// 2 for ECDSA-256 with P-256, 3 for ECDSA-384 with P-384.
+    pub attestation_key_type: u16,
+    /// 0 for SGX, 0x81 for TDX. Reserved in version 3 quotes.
+    pub tee_type: u32,
+    pub qe_svn: u16,
+    pub pce_svn: u16,
+    pub qe_vendor_id: String,
+    pub user_data: String,
+}
+
+/// SGX enclave report body (`sgx_report_body_t`), byte fields hex encoded.
+#[derive(Serialize, Debug, Default, Clone, PartialEq)]
+pub struct ReportBody {
+    pub cpu_svn: String,
+    pub misc_select: u32,
+    pub isv_ext_prod_id: String,
+    pub attributes: String,
+    pub mr_enclave: String,
+    pub mr_signer: String,
+    pub config_id: String,
+    pub isv_prod_id: u16,
+    pub isv_svn: u16,
+    pub config_svn: u16,
+    pub isv_family_id: String,
+    pub report_data: String,
+}
+
+/// TDX TD report body (`sgx_report2_body_t`), byte fields hex encoded.
+#[derive(Serialize, Debug, Default, Clone, PartialEq)]
+pub struct TdReportBody {
+    pub tee_tcb_svn: String,
+    pub mr_seam: String,
+    pub mr_signer_seam: String,
+    pub seam_attributes: String,
+    pub td_attributes: String,
+    pub xfam: String,
+    pub mr_td: String,
+    pub mr_config_id: String,
+    pub mr_owner: String,
+    pub mr_owner_config: String,
+    pub rt_mr: Vec<String>,
+    pub report_data: String,
+}
+
+/// ECDSA signature data of the quote, byte fields hex encoded.
+#[derive(Serialize, Debug, Default, Clone, PartialEq)]
+pub struct SignatureData {
+    pub isv_enclave_report_signature: String,
+    pub attestation_key: String,
+    /// Report of the Quoting Enclave, which signs `attestation_key`.
+    pub qe_report: ReportBody,
+    pub qe_report_signature: String,
+    pub qe_auth_data: String,
+    pub certification_data_type: u16,
+    /// Raw certification data when it is not a PEM certificate chain.
+    pub certification_data: Option<String>,
+}
+
+#[derive(Serialize, Debug, Default, Clone, PartialEq)]
+pub struct Quote {
+    pub header: QuoteHeader,
+    /// Enclave report of an SGX quote, left empty for a TDX quote.
+    pub report_body: ReportBody,
+    /// TD report of a TDX quote.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub td_report_body: Option<TdReportBody>,
+    pub signature_data: SignatureData,
+    /// PEM encoded PCK certificate chain, leaf first.
+    pub certification_chain: Vec<String>,
+}
+
+impl Quote {
+    /// Hex encoded report data, from the enclave report or the TD report.
+    pub fn report_data(&self) -> &str {
+        match &self.td_report_body {
+            Some(td_report_body) => &td_report_body.report_data,
+            None => &self.report_body.report_data,
+        }
+    }
+
+    /// Hex encoded MRENCLAVE of an SGX quote, or MRTD of a TDX quote.
+    pub fn measurement(&self) -> &str {
+        match &self.td_report_body {
+            Some(td_report_body) => &td_report_body.mr_td,
+            None => &self.report_body.mr_enclave,
+        }
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(bytes: &'a [u8]) -> Self {
+        Reader { bytes, offset: 0 }
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], String> {
+        let end = self
+            .offset
+            .checked_add(len)
+            .filter(|end| *end <= self.bytes.len())
+            .ok_or_else(|| {
+                format!(
+                    "quote truncated: {len} bytes expected at offset {}",
+                    self.offset
+                )
+            })?;
+        let slice = &self.bytes[self.offset..end];
+        self.offset = end;
+        Ok(slice)
+    }
+
+    fn hex(&mut self, len: usize) -> Result<String, String> {
+        Ok(hex::encode(self.take(len)?))
+    }
+
+    fn u16(&mut self) -> Result<u16, String> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Result<u32, String> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn finish(&self, what: &str) -> Result<(), String> {
+        match self.bytes.len() - self.offset {
+            0 => Ok(()),
+            n => Err(format!("{n} trailing bytes after the {what}")),
+        }
+    }
+}
+
+fn parse_header(reader: &mut Reader) -> Result<QuoteHeader, String> {
+    Ok(QuoteHeader {
+        version: reader.u16()?,
+        attestation_key_type: reader.u16()?,
+        tee_type: reader.u32()?,
+        qe_svn: reader.u16()?,
+        pce_svn: reader.u16()?,
+        qe_vendor_id: reader.hex(16)?,
+        user_data: reader.hex(20)?,
+    })
+}
+
+fn parse_report_body(reader: &mut Reader) -> Result<ReportBody, String> {
+    let cpu_svn = reader.hex(16)?;
+    let misc_select = reader.u32()?;
+    reader.take(12)?;
+    let isv_ext_prod_id = reader.hex(16)?;
+    let attributes = reader.hex(16)?;
+    let mr_enclave = reader.hex(32)?;
+    reader.take(32)?;
+    let mr_signer = reader.hex(32)?;
+    reader.take(32)?;
+    let config_id = reader.hex(64)?;
+    let isv_prod_id = reader.u16()?;
+    let isv_svn = reader.u16()?;
+    let config_svn = reader.u16()?;
+    reader.take(42)?;
+    Ok(ReportBody {
+        cpu_svn,
+        misc_select,
+        isv_ext_prod_id,
+        attributes,
+        mr_enclave,
+        mr_signer,
+        config_id,
+        isv_prod_id,
+        isv_svn,
+        config_svn,
+        isv_family_id: reader.hex(16)?,
+        report_data: reader.hex(64)?,
+    })
+}
+
+fn parse_td_report_body(reader: &mut Reader) -> Result<TdReportBody, String> {
+    Ok(TdReportBody {
+        tee_tcb_svn: reader.hex(16)?,
+        mr_seam: reader.hex(48)?,
+        mr_signer_seam: reader.hex(48)?,
+        seam_attributes: reader.hex(8)?,
+        td_attributes: reader.hex(8)?,
+        xfam: reader.hex(8)?,
+        mr_td: reader.hex(48)?,
+        mr_config_id: reader.hex(48)?,
+        mr_owner: reader.hex(48)?,
+        mr_owner_config: reader.hex(48)?,
+        rt_mr: (0..4).map(|_| reader.hex(48)).collect::<Result<_, _>>()?,
+        report_data: reader.hex(64)?,
+    })
+}
+
+/// Splits concatenated PEM certificates, ignoring the trailing NUL bytes some quotes carry.
+fn split_pem_chain(data: &[u8]) -> Result<Vec<String>, String> {
+    const END: &str = "-----END CERTIFICATE-----";
+    let text = std::str::from_utf8(data)
+        .map_err(|_| "certification chain is not valid PEM".to_string())?
+        .trim_end_matches('\0');
+    let mut certificates = Vec::new();
+    let mut rest = text;
+    while let Some(end) = rest.find(END) {
+        let (certificate, tail) = rest.split_at(end + END.len());
+        certificates.push(certificate.trim().to_string());
+        rest = tail;
+    }
+    if !rest.trim().is_empty() {
+        return Err("certification chain has trailing data".to_string());
+    }
+    Ok(certificates)
+}
+
+/// Reads the QE report, its signature and authentication data, then the certification data.
+fn parse_qe_report_certification(
+    reader: &mut Reader,
+    signature_data: &mut SignatureData,
+) -> Result<Vec<String>, String> {
+    signature_data.qe_report = parse_report_body(reader)?;
+    signature_data.qe_report_signature = reader.hex(64)?;
+    let qe_auth_data_size = reader.u16()? as usize;
+    signature_data.qe_auth_data = reader.hex(qe_auth_data_size)?;
+    parse_certification_data(reader, signature_data)
+}
+
+fn parse_certification_data(
+    reader: &mut Reader,
+    signature_data: &mut SignatureData,
+) -> Result<Vec<String>, String> {
+    let certification_data_type = reader.u16()?;
+    let size = reader.u32()? as usize;
+    let data = reader.take(size)?;
+    signature_data.certification_data_type = certification_data_type;
+    match certification_data_type {
+        PCK_CERT_CHAIN => split_pem_chain(data),
+        QE_REPORT_CERTIFICATION_DATA => {
+            parse_qe_report_certification(&mut Reader::new(data), signature_data)
+        }
+        _ => {
+            signature_data.certification_data = Some(hex::encode(data));
+            Ok(Vec::new())
+        }
+    }
+}
+
+/// Parses an ECDSA quote: SGX version 3 or 4, or TDX version 4.
+pub fn parse(bytes: &[u8]) -> Result<Quote, String> {
+    let mut reader = Reader::new(bytes);
+    let header = parse_header(&mut reader)?;
+    debug_assert_eq!(reader.offset, HEADER_SIZE);
+    if header.version != 3 && header.version != 4 {
+        return Err(format!("unsupported quote version {}", header.version));
+    }
+    let (report_body, td_report_body) = match header.tee_type {
+        // The TEE type is reserved, hence zero, in version 3 quotes
+        SGX_TEE_TYPE => {
+            let report_body = parse_report_body(&mut reader)?;
+            debug_assert_eq!(reader.offset, HEADER_SIZE + REPORT_BODY_SIZE);
+            (report_body, None)
+        }
+        TDX_TEE_TYPE if header.version == 4 => {
+            let td_report_body = parse_td_report_body(&mut reader)?;
+            debug_assert_eq!(reader.offset, HEADER_SIZE + TD_REPORT_BODY_SIZE);
+            (ReportBody::default(), Some(td_report_body))
+        }
+        tee_type => {
+            return Err(format!(
+                "unsupported TEE type {tee_type:#x} for a version {} quote",
+                header.version
+            ))
+        }
+    };
+    let signature_data_size = reader.u32()? as usize;
+    let mut signature_reader = Reader::new(reader.take(signature_data_size)?);
+    let mut signature_data = SignatureData {
+        isv_enclave_report_signature: signature_reader.hex(64)?,
+        attestation_key: signature_reader.hex(64)?,
+        ..Default::default()
+    };
+    let certification_chain = if header.version == 3 {
+        parse_qe_report_certification(&mut signature_reader, &mut signature_data)?
+    } else {
+        parse_certification_data(&mut signature_reader, &mut signature_data)?
+    };
+    signature_reader.finish("certification data")?;
+    reader.finish("signature data")?;
+    Ok(Quote {
+        header,
+        report_body,
+        td_report_body,
+        signature_data,
+        certification_chain,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    const PEM: &str = "-----BEGIN CERTIFICATE-----\nAAAA\n-----END CERTIFICATE-----\n";
+
+    fn report_body(mr_enclave: u8, isv_svn: u16) -> Vec<u8> {
+        let mut body = vec![0u8; REPORT_BODY_SIZE];
+        body[64..96].fill(mr_enclave);
+        body[258..260].copy_from_slice(&isv_svn.to_le_bytes());
+        body[320..384].fill(0x11);
+        body
+    }
+
+    fn header(version: u16, tee_type: u32) -> Vec<u8> {
+        let mut header = Vec::new();
+        header.extend(version.to_le_bytes());
+        header.extend(2u16.to_le_bytes());
+        header.extend(tee_type.to_le_bytes());
+        header.extend(7u16.to_le_bytes());
+        header.extend(9u16.to_le_bytes());
+        header.extend([0xaa; 16]);
+        header.extend([0u8; 20]);
+        header
+    }
+
+    /// QE report, its signature and authentication data, then the PCK certificate chain.
+    fn qe_report_certification() -> Vec<u8> {
+        let mut data = report_body(0x33, 1);
+        data.extend([4u8; 64]);
+        data.extend(2u16.to_le_bytes());
+        data.extend([5u8; 2]);
+        let chain = format!("{PEM}{PEM}\0");
+        data.extend(PCK_CERT_CHAIN.to_le_bytes());
+        data.extend((chain.len() as u32).to_le_bytes());
+        data.extend(chain.as_bytes());
+        data
+    }
+
+    fn with_signature(mut quote: Vec<u8>, certification: Vec<u8>) -> Vec<u8> {
+        let mut signature = vec![1u8; 64];
+        signature.extend([2u8; 64]);
+        signature.extend(certification);
+        quote.extend((signature.len() as u32).to_le_bytes());
+        quote.extend(signature);
+        quote
+    }
+
+    fn quote_v3() -> Vec<u8> {
+        let mut quote = header(3, SGX_TEE_TYPE);
+        quote.extend(report_body(0xee, 5));
+        with_signature(quote, qe_report_certification())
+    }
+
+    fn quote_tdx() -> Vec<u8> {
+        let mut quote = header(4, TDX_TEE_TYPE);
+        let mut td_report_body = vec![0u8; TD_REPORT_BODY_SIZE];
+        td_report_body[136..184].fill(0xdd);
+        td_report_body[520..584].fill(0x22);
+        quote.extend(td_report_body);
+        let qe_report_certification = qe_report_certification();
+        let mut certification = QE_REPORT_CERTIFICATION_DATA.to_le_bytes().to_vec();
+        certification.extend((qe_report_certification.len() as u32).to_le_bytes());
+        certification.extend(qe_report_certification);
+        with_signature(quote, certification)
+    }
+
+    #[test]
+    fn test_parse_v3() {
+        let quote = parse(&quote_v3()).unwrap();
+        assert_eq!(quote.header.version, 3);
+        assert_eq!(quote.header.qe_svn, 7);
+        assert_eq!(quote.header.pce_svn, 9);
+        assert_eq!(quote.report_body.mr_enclave, "ee".repeat(32));
+        assert_eq!(quote.report_body.isv_svn, 5);
+        assert_eq!(quote.report_body.report_data, "11".repeat(64));
+        assert_eq!(quote.signature_data.qe_report.mr_enclave, "33".repeat(32));
+        assert_eq!(quote.signature_data.qe_auth_data, "0505");
+        assert_eq!(quote.certification_chain.len(), 2);
+        assert_eq!(quote.certification_chain[0], PEM.trim());
+    }
+
+    #[test]
+    fn test_parse_tdx() {
+        let quote = parse(&quote_tdx()).unwrap();
+        assert_eq!(quote.header.tee_type, TDX_TEE_TYPE);
+        let td_report_body = quote.td_report_body.as_ref().unwrap();
+        assert_eq!(td_report_body.mr_td, "dd".repeat(48));
+        assert_eq!(td_report_body.rt_mr.len(), 4);
+        assert_eq!(quote.measurement(), "dd".repeat(48));
+        assert_eq!(quote.report_data(), "22".repeat(64));
+        assert_eq!(quote.signature_data.qe_report.mr_enclave, "33".repeat(32));
+        assert_eq!(quote.certification_chain.len(), 2);
+    }
+
+    #[test]
+    fn test_parse_rejects_truncated() {
+        let quote = quote_v3();
+        assert!(parse(&quote[..quote.len() - 1]).is_err());
+        assert!(parse(&quote[..100]).is_err());
+    }
+
+    #[test]
+    fn test_parse_rejects_trailing_bytes() {
+        let mut quote = quote_v3();
+        quote.push(0);
+        assert!(parse(&quote).is_err());
+    }
+
+    #[test]
+    fn test_extract_quote() {
+        assert_eq!(
+            extract_quote(&json!({ "quote": [1, 2, 255] })).unwrap(),
+            vec![1, 2, 255]
+        );
+        assert!(extract_quote(&json!({ "quote": [1, 256] })).is_err());
+        assert!(extract_quote(&json!({ "quote": [1, "a"] })).is_err());
+        assert_eq!(
+            extract_quote(&json!({ "quote": "0x01ff" })).unwrap(),
+            vec![1, 255]
+        );
+        assert!(extract_quote(&json!({ "quote": "01f" })).is_err());
+        assert_eq!(
+            extract_quote(&json!({ "quote": "Af8=", "encoding": "base64" })).unwrap(),
+            vec![1, 255]
+        );
+        assert!(extract_quote(&json!({ "quote": "Af8", "encoding": "base64" })).is_err());
+        assert!(extract_quote(&json!({ "quote": "01", "encoding": "utf8" })).is_err());
+        assert!(extract_quote(&json!({})).is_err());
+    }
+}
@@ -0,0 +1,262 @@
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::records::check_table_name;
+
+pub(crate) const ACL_TABLE: &str = "_acl";
+pub(crate) const KEY_GRANT_TABLE: &str = "_acl_keys";
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    Owner,
+    Writer,
+    Reader,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Permission {
+    Read,
+    Write,
+    /// Manage schemas, indexes and grants of a table.
+    Admin,
+}
+
+impl Role {
+    fn allows(&self, permission: Permission) -> bool {
+        match self {
+            Role::Owner => true,
+            Role::Writer => permission != Permission::Admin,
+            Role::Reader => permission == Permission::Read,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct Grants {
+    pub owners: Vec<String>,
+    pub writers: Vec<String>,
+    pub readers: Vec<String>,
+}
+
+impl Grants {
+    fn members(&mut self, role: Role) -> &mut Vec<String> {
+        match role {
+            Role::Owner => &mut self.owners,
+            Role::Writer => &mut self.writers,
+            Role::Reader => &mut self.readers,
+        }
+    }
+
+    fn allows(&self, sender: &str, permission: Permission) -> bool {
+        [
+            (Role::Owner, &self.owners),
+            (Role::Writer, &self.writers),
+            (Role::Reader, &self.readers),
+        ]
+        .iter()
+        .any(|(role, members)| role.allows(permission) && members.iter().any(|m| m == sender))
+    }
+
+    fn grant(&mut self, role: Role, user: &str) {
+        let members = self.members(role);
+        if !members.iter().any(|m| m == user) {
+            members.push(user.to_string());
+        }
+    }
+
+    fn revoke(&mut self, role: Role, user: &str) {
+        self.members(role).retain(|m| m != user);
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct GrantInput {
+    pub table: String,
+    /// Restricts the grant to a single key of the table.
+    pub key: Option<String>,
+    pub user: String,
+    pub role: Role,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct LoadAclInput {
+    pub table: String,
+    pub key: Option<String>,
+}
+
+fn key_grant_key(table: &str, key: &str) -> String {
+    format!("{table}/{key}")
+}
+
+fn load_grants(table: &str, key: &str) -> Result<Option<Grants>, Box<dyn std::error::Error>> {
+    let res = klave::ledger::get_table(table).get(key)?;
+    if res.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(serde_json::from_slice::<Grants>(&res)?))
+}
+
+fn save_grants(table: &str, key: &str, grants: &Grants) -> Result<(), Box<dyn std::error::Error>> {
+    klave::ledger::get_table(table).set(key, &serde_json::to_vec(grants)?)
+}
+
+pub fn load_table_grants(table: &str) -> Result<Option<Grants>, Box<dyn std::error::Error>> {
+    load_grants(ACL_TABLE, table)
+}
+
+pub fn load_key_grants(
+    table: &str,
+    key: &str,
+) -> Result<Option<Grants>, Box<dyn std::error::Error>> {
+    load_grants(KEY_GRANT_TABLE, &key_grant_key(table, key))
+}
+
+/// Checks that the sender of the current request holds `permission` on `table`, or on `key`
+/// of `table` when provided.
+///
+/// Tables without an owner are unclaimed: anyone can read them and the first sender writing to
+/// one becomes its owner.
+pub fn authorize(
+    table: &str,
+    key: Option<&str>,
+    permission: Permission,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let sender = klave::context::get("sender")?;
+    let Some(table_grants) = load_table_grants(table)? else {
+        if permission != Permission::Read {
+            let mut grants = Grants::default();
+            grants.grant(Role::Owner, &sender);
+            save_grants(ACL_TABLE, table, &grants)?;
+        }
+        return Ok(());
+    };
+    if table_grants.allows(&sender, permission) {
+        return Ok(());
+    }
+    if let Some(key) = key {
+        if permission != Permission::Admin {
+            if let Some(key_grants) = load_key_grants(table, key)? {
+                if key_grants.allows(&sender, permission) {
+                    return Ok(());
+                }
+            }
+        }
+    }
+    Err(match key {
+        Some(key) => format!("access denied to key '{key}' of table '{table}'"),
+        None => format!("access denied to table '{table}'"),
+    }
+    .into())
+}
+
+fn update_grants(input: &GrantInput, grant: bool) -> Result<Grants, Box<dyn std::error::Error>> {
+    authorize(&input.table, None, Permission::Admin)?;
+    let (grant_table, grant_key) = match &input.key {
+        Some(key) => {
+            if input.role == Role::Owner {
+                return Err("owners can only be granted on a whole table".into());
+            }
+            (KEY_GRANT_TABLE, key_grant_key(&input.table, key))
+        }
+        None => (ACL_TABLE, input.table.clone()),
+    };
+    let mut grants = load_grants(grant_table, &grant_key)?.unwrap_or_default();
+    if grant {
+        grants.grant(input.role, &input.user);
+    } else {
+        grants.revoke(input.role, &input.user);
+        if input.key.is_none() && grants.owners.is_empty() {
+            return Err(format!("table '{}' must keep at least one owner", input.table).into());
+        }
+    }
+    save_grants(grant_table, &grant_key, &grants)?;
+    Ok(grants)
+}
+
+fn grant_or_revoke(cmd: String, grant: bool) {
+    let Ok(input) = serde_json::from_str::<GrantInput>(&cmd) else {
+        klave::notifier::send_string(&format!("ERROR: failed to parse '{cmd}' as GrantInput"));
+        klave::router::cancel_transaction();
+        return;
+    };
+    if let Err(e) = check_table_name(&input.table) {
+        klave::notifier::send_string(&format!("ERROR: {e}"));
+        klave::router::cancel_transaction();
+        return;
+    }
+    match update_grants(&input, grant) {
+        Ok(grants) => {
+            let _ = klave::notifier::send_json(&json!({
+                "table": input.table,
+                "key": input.key,
+                "grants": grants,
+            }));
+        }
+        Err(e) => {
+            klave::notifier::send_string(&format!("ERROR: failed to update grants: '{e}'"));
+            klave::router::cancel_transaction();
+        }
+    }
+}
+
+pub fn grant_access(cmd: String) {
+    grant_or_revoke(cmd, true);
+}
+
+pub fn revoke_access(cmd: String) {
+    grant_or_revoke(cmd, false);
+}
+
+pub fn load_acl(cmd: String) {
+    let Ok(input) = serde_json::from_str::<LoadAclInput>(&cmd) else {
+        klave::notifier::send_string(&format!("ERROR: failed to parse '{cmd}' as LoadAclInput"));
+        return;
+    };
+    if let Err(e) = check_table_name(&input.table) {
+        klave::notifier::send_string(&format!("ERROR: {e}"));
+        return;
+    }
+    if let Err(e) = authorize(&input.table, None, Permission::Read) {
+        klave::notifier::send_string(&format!("ERROR: {e}"));
+        return;
+    }
+    let grants = match &input.key {
+        Some(key) => load_key_grants(&input.table, key),
+        None => load_table_grants(&input.table),
+    };
+    match grants {
+        Ok(grants) => {
+            let _ = klave::notifier::send_json(&grants.unwrap_or_default());
+        }
+        Err(e) => {
+            klave::notifier::send_string(&format!("ERROR: failed to load grants: '{e}'"));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_grants() {
+        let mut grants = Grants::default();
+        grants.grant(Role::Owner, "alice");
+        grants.grant(Role::Reader, "bob");
+        grants.grant(Role::Reader, "bob");
+        assert_eq!(grants.readers.len(), 1);
+
+        assert!(grants.allows("alice", Permission::Admin));
+        assert!(grants.allows("bob", Permission::Read));
+        assert!(!grants.allows("bob", Permission::Write));
+        assert!(!grants.allows("carol", Permission::Read));
+
+        grants.grant(Role::Writer, "bob");
+        assert!(grants.allows("bob", Permission::Write));
+        assert!(!grants.allows("bob", Permission::Admin));
+
+        grants.revoke(Role::Writer, "bob");
+        assert!(!grants.allows("bob", Permission::Write));
+    }
+}
@@ -0,0 +1,357 @@
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::{
+    acl::{self, Permission},
+    crypto::{self, TableEncryption},
+    encoding::Encoding,
+    index,
+    records::{check_table_name, default_table},
+};
+
+pub(crate) const BLOB_TABLE: &str = "_blobs";
+pub(crate) const BLOB_KEYS_TABLE: &str = "_blob_keys";
+pub(crate) const BLOB_CHUNK_TABLE: &str = "_blob_chunks";
+
+const DEFAULT_CHUNK_SIZE: usize = 64 * 1024;
+const MAX_CHUNK_SIZE: usize = 1024 * 1024;
+
+/// Describes a blob whose content is split across `chunks.len()` ledger keys.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BlobManifest {
+    pub size: usize,
+    pub chunk_size: usize,
+    /// SHA2-256 of the whole content, hex encoded.
+    pub hash: String,
+    /// SHA2-256 of each chunk, hex encoded.
+    pub chunks: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PutBlobInput {
+    #[serde(default = "default_table")]
+    pub table: String,
+    pub key: String,
+    pub data: String,
+    #[serde(default)]
+    pub encoding: Encoding,
+    pub chunk_size: Option<usize>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct LoadBlobInput {
+    #[serde(default = "default_table")]
+    pub table: String,
+    pub key: String,
+    #[serde(default)]
+    pub encoding: Encoding,
+    /// Loads a single chunk, for blobs too large to be returned at once.
+    pub chunk: Option<usize>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DeleteBlobInput {
+    #[serde(default = "default_table")]
+    pub table: String,
+    pub key: String,
+}
+
+fn blob_key(table: &str, key: &str) -> String {
+    format!("{table}/{key}")
+}
+
+fn chunk_key(table: &str, key: &str, index: usize) -> String {
+    format!("{table}/{key}/{index:010}")
+}
+
+fn sha256(data: &[u8]) -> Result<String, Box<dyn std::error::Error>> {
+    Ok(hex::encode(klave::crypto::sha::digest("SHA2-256", data)?))
+}
+
+pub fn load_manifest(
+    table: &str,
+    key: &str,
+) -> Result<Option<BlobManifest>, Box<dyn std::error::Error>> {
+    let res = klave::ledger::get_table(BLOB_TABLE).get(&blob_key(table, key))?;
+    if res.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(serde_json::from_slice::<BlobManifest>(&res)?))
+}
+
+/// Lists the keys of the blobs of `table`, sorted.
+fn load_blob_keys(table: &str) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    index::list_prefixed(&index::per_table(BLOB_KEYS_TABLE, table), "")
+}
+
+// One entry per blob, in a ledger table per table as for the keys of a table.
+fn update_blob_keys(
+    table: &str,
+    key: &str,
+    exists: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let entries = klave::ledger::get_table(&index::per_table(BLOB_KEYS_TABLE, table));
+    if exists {
+        entries.set(key, key.as_bytes())
+    } else {
+        entries.remove(key)
+    }
+}
+
+fn write_chunk(
+    table: &str,
+    key: &str,
+    index: usize,
+    chunk: &[u8],
+    encryption: Option<&TableEncryption>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let location = chunk_key(table, key, index);
+    let stored = match encryption {
+        Some(encryption) => crypto::encrypt_entry(encryption, table, &location, chunk)?,
+        None => chunk.to_vec(),
+    };
+    klave::ledger::get_table(BLOB_CHUNK_TABLE).set(&location, &stored)
+}
+
+/// Reads chunk `index` of a blob and checks it against the hash recorded in its manifest.
+fn read_chunk(
+    table: &str,
+    key: &str,
+    manifest: &BlobManifest,
+    index: usize,
+) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let Some(expected_hash) = manifest.chunks.get(index) else {
+        return Err(format!("blob '{key}' only has {} chunks", manifest.chunks.len()).into());
+    };
+    let location = chunk_key(table, key, index);
+    let mut chunk = klave::ledger::get_table(BLOB_CHUNK_TABLE).get(&location)?;
+    if let Some(encryption) = crypto::load_table_encryption(table)? {
+        chunk = crypto::decrypt_entry(&encryption, table, &location, &chunk)?;
+    }
+    if &sha256(&chunk)? != expected_hash {
+        return Err(format!("chunk {index} of blob '{key}' failed its integrity check").into());
+    }
+    Ok(chunk)
+}
+
+/// Reads a whole blob, checking every chunk and the hash of the reassembled content.
+pub fn read_blob(
+    table: &str,
+    key: &str,
+    manifest: &BlobManifest,
+) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let mut data = Vec::with_capacity(manifest.size);
+    for index in 0..manifest.chunks.len() {
+        data.extend(read_chunk(table, key, manifest, index)?);
+    }
+    if data.len() != manifest.size || sha256(&data)? != manifest.hash {
+        return Err(format!("blob '{key}' failed its integrity check").into());
+    }
+    Ok(data)
+}
+
+fn remove_chunks(
+    table: &str,
+    key: &str,
+    from: usize,
+    to: usize,
+) -> Result<(), Box<dyn std::error::Error>> {
+    for index in from..to {
+        klave::ledger::get_table(BLOB_CHUNK_TABLE).remove(&chunk_key(table, key, index))?;
+    }
+    Ok(())
+}
+
+/// Splits `data` in chunks of `chunk_size` bytes and writes them, replacing any previous blob
+/// stored under `key`.
+pub fn write_blob(
+    table: &str,
+    key: &str,
+    data: &[u8],
+    chunk_size: usize,
+) -> Result<BlobManifest, Box<dyn std::error::Error>> {
+    if chunk_size == 0 || chunk_size > MAX_CHUNK_SIZE {
+        return Err(format!("chunk size must be between 1 and {MAX_CHUNK_SIZE} bytes").into());
+    }
+    let encryption = crypto::load_table_encryption(table)?;
+    let mut chunks = Vec::new();
+    for (index, chunk) in data.chunks(chunk_size).enumerate() {
+        write_chunk(table, key, index, chunk, encryption.as_ref())?;
+        chunks.push(sha256(chunk)?);
+    }
+    if let Some(previous) = load_manifest(table, key)? {
+        remove_chunks(table, key, chunks.len(), previous.chunks.len())?;
+    }
+    let manifest = BlobManifest {
+        size: data.len(),
+        chunk_size,
+        hash: sha256(data)?,
+        chunks,
+    };
+    klave::ledger::get_table(BLOB_TABLE)
+        .set(&blob_key(table, key), &serde_json::to_vec(&manifest)?)?;
+    update_blob_keys(table, key, true)?;
+    Ok(manifest)
+}
+
+pub fn remove_blob(
+    table: &str,
+    key: &str,
+) -> Result<Option<BlobManifest>, Box<dyn std::error::Error>> {
+    let Some(manifest) = load_manifest(table, key)? else {
+        return Ok(None);
+    };
+    remove_chunks(table, key, 0, manifest.chunks.len())?;
+    klave::ledger::get_table(BLOB_TABLE).remove(&blob_key(table, key))?;
+    update_blob_keys(table, key, false)?;
+    Ok(Some(manifest))
+}
+
+/// Re-encrypts the chunks of every blob of `table` with the current key of `new`.
+/// `old` is `None` when the chunks were stored in plaintext until now.
+pub fn reencrypt(
+    table: &str,
+    old: Option<&TableEncryption>,
+    new: &TableEncryption,
+) -> Result<usize, Box<dyn std::error::Error>> {
+    let keys = load_blob_keys(table)?;
+    for key in &keys {
+        let Some(manifest) = load_manifest(table, key)? else {
+            continue;
+        };
+        for index in 0..manifest.chunks.len() {
+            let location = chunk_key(table, key, index);
+            let stored = klave::ledger::get_table(BLOB_CHUNK_TABLE).get(&location)?;
+            let chunk = match old {
+                Some(old) => crypto::decrypt_entry(old, table, &location, &stored)?,
+                None => stored,
+            };
+            write_chunk(table, key, index, &chunk, Some(new))?;
+        }
+    }
+    Ok(keys.len())
+}
+
+pub fn put_blob(cmd: String) {
+    let Ok(input) = serde_json::from_str::<PutBlobInput>(&cmd) else {
+        klave::notifier::send_string(&format!("failed to parse '{cmd}' as json"));
+        klave::router::cancel_transaction();
+        return;
+    };
+    if let Err(e) = check_table_name(&input.table) {
+        klave::notifier::send_string(&e);
+        klave::router::cancel_transaction();
+        return;
+    }
+    if let Err(e) = acl::authorize(&input.table, Some(&input.key), Permission::Write) {
+        klave::notifier::send_string(&e.to_string());
+        klave::router::cancel_transaction();
+        return;
+    }
+    let data = match input.encoding.decode(&input.data) {
+        Ok(data) => data,
+        Err(e) => {
+            klave::notifier::send_string(&format!("failed to decode data: '{e}'"));
+            klave::router::cancel_transaction();
+            return;
+        }
+    };
+    let chunk_size = input.chunk_size.unwrap_or(DEFAULT_CHUNK_SIZE);
+    match write_blob(&input.table, &input.key, &data, chunk_size) {
+        Ok(manifest) => {
+            let _ = klave::notifier::send_json(&json!({
+                "table": input.table,
+                "key": input.key,
+                "manifest": manifest,
+            }));
+        }
+        Err(e) => {
+            klave::notifier::send_string(&format!("failed to write blob: '{e}'"));
+            klave::router::cancel_transaction();
+        }
+    }
+}
+
+pub fn load_blob(cmd: String) {
+    let Ok(input) = serde_json::from_str::<LoadBlobInput>(&cmd) else {
+        klave::notifier::send_string(&format!("failed to parse '{cmd}' as json"));
+        return;
+    };
+    if let Err(e) = check_table_name(&input.table) {
+        klave::notifier::send_string(&e);
+        return;
+    }
+    if let Err(e) = acl::authorize(&input.table, Some(&input.key), Permission::Read) {
+        klave::notifier::send_string(&e.to_string());
+        return;
+    }
+    let load = || -> Result<Option<serde_json::Value>, Box<dyn std::error::Error>> {
+        let Some(manifest) = load_manifest(&input.table, &input.key)? else {
+            return Ok(None);
+        };
+        let data = match input.chunk {
+            Some(index) => read_chunk(&input.table, &input.key, &manifest, index)?,
+            None => read_blob(&input.table, &input.key, &manifest)?,
+        };
+        Ok(Some(json!({
+            "table": input.table,
+            "key": input.key,
+            "manifest": manifest,
+            "chunk": input.chunk,
+            "encoding": input.encoding,
+            "data": input.encoding.encode(&data)?,
+        })))
+    };
+    match load() {
+        Ok(Some(blob)) => {
+            let _ = klave::notifier::send_json(&blob);
+        }
+        Ok(None) => {
+            klave::notifier::send_string(&format!(
+                "the blob '{}' was not found in table {}",
+                input.key, input.table
+            ));
+        }
+        Err(e) => {
+            klave::notifier::send_string(&format!("failed to read blob: '{e}'"));
+        }
+    }
+}
+
+pub fn delete_blob(cmd: String) {
+    let Ok(input) = serde_json::from_str::<DeleteBlobInput>(&cmd) else {
+        klave::notifier::send_string(&format!("failed to parse '{cmd}' as json"));
+        klave::router::cancel_transaction();
+        return;
+    };
+    if let Err(e) = check_table_name(&input.table) {
+        klave::notifier::send_string(&e);
+        klave::router::cancel_transaction();
+        return;
+    }
+    if let Err(e) = acl::authorize(&input.table, Some(&input.key), Permission::Write) {
+        klave::notifier::send_string(&e.to_string());
+        klave::router::cancel_transaction();
+        return;
+    }
+    match remove_blob(&input.table, &input.key) {
+        Ok(Some(_)) => {
+            let _ = klave::notifier::send_json(&json!({
+                "deleted": true,
+                "table": input.table,
+                "key": input.key
+            }));
+        }
+        Ok(None) => {
+            klave::notifier::send_string(&format!(
+                "the blob '{}' was not found in table {}",
+                input.key, input.table
+            ));
+            klave::router::cancel_transaction();
+        }
+        Err(e) => {
+            klave::notifier::send_string(&format!("failed to delete blob: '{e}'"));
+            klave::router::cancel_transaction();
+        }
+    }
+}
@@ -0,0 +1,282 @@
+use klave::crypto::subtle::{
+    self, decrypt, delete_key, derive_key, encrypt, load_key, save_key, AesGcmParams,
+    AesKeyGenParams, CryptoKey, DerivedKeyAlgorithm, EncryptAlgorithm, HkdfDerivParams,
+    KeyDerivationAlgorithm,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::{
+    acl::{self, Permission},
+    blob, history, index,
+    records::check_table_name,
+};
+
+pub(crate) const ENCRYPTION_TABLE: &str = "_encryption";
+
+// AES-GCM constants
+pub const AES_GCM_IV_SIZE: usize = 12; // 12 bytes (96 bits) - optimal for AES-GCM
+const KEY_VERSION_SIZE: usize = 4;
+
+/// Encryption settings of a table.
+///
+/// Each rotation replaces the master key and bumps the version; entries record the version of
+/// the key they were encrypted with. A rotation re-encrypts every entry in the same
+/// transaction, so only the current key is kept.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TableEncryption {
+    pub version: u32,
+    pub master_key_name: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct EncryptionInput {
+    pub table: String,
+}
+
+pub fn generate_ecc_crypto_key() -> Result<CryptoKey, Box<dyn std::error::Error>> {
+    let ec_params = subtle::EcKeyGenParams {
+        named_curve: "P-256".to_string(),
+    };
+    let gen_algorithm = subtle::KeyGenAlgorithm::Ecc(ec_params);
+
+    let private_key = match subtle::generate_key(&gen_algorithm, false, &["sign", "derive_key"]) {
+        Ok(result) => result,
+        Err(err) => {
+            klave::notifier::send_string(&err.to_string());
+            return Err(err);
+        }
+    };
+    Ok(private_key)
+}
+
+/// Generates a new master key, saves it in the key store and returns its name.
+fn create_master_key() -> Result<String, Box<dyn std::error::Error>> {
+    let master_key_name = hex::encode(klave::crypto::random::get_random_bytes(32)?);
+    let master_key = generate_ecc_crypto_key()?;
+    save_key(&master_key, &master_key_name)?;
+    Ok(master_key_name)
+}
+
+pub fn derive_aes_gcm_key(
+    master_key: &CryptoKey,
+    table: &str,
+    version: u32,
+) -> Result<CryptoKey, Box<dyn std::error::Error>> {
+    // Use HKDF to derive a key from the master key, the table name and the key version
+    let hkdf_derivation_params = HkdfDerivParams {
+        hash: "SHA-256".to_string(),
+        salt: format!("klave-salt-ledger-'{table}'").into_bytes(),
+        info: format!("klave-info-ledger-v{version}").into_bytes(),
+    };
+    let derivation_algorithm = KeyDerivationAlgorithm::Hkdf(hkdf_derivation_params);
+    let aes_key_gen_params = AesKeyGenParams {
+        length: 256, // AES-256
+    };
+    let derived_key_algorithm = DerivedKeyAlgorithm::Aes(aes_key_gen_params);
+    derive_key(
+        &derivation_algorithm,
+        master_key,
+        &derived_key_algorithm,
+        false,
+        &["encrypt", "decrypt"],
+    )
+}
+
+fn table_key(
+    encryption: &TableEncryption,
+    table: &str,
+    version: u32,
+) -> Result<CryptoKey, Box<dyn std::error::Error>> {
+    if version != encryption.version {
+        return Err(format!("unknown key version {version} for table '{table}'").into());
+    }
+    let master_key = load_key(&encryption.master_key_name)?;
+    derive_aes_gcm_key(&master_key, table, version)
+}
+
+pub fn load_table_encryption(
+    table: &str,
+) -> Result<Option<TableEncryption>, Box<dyn std::error::Error>> {
+    let res = klave::ledger::get_table(ENCRYPTION_TABLE).get(table)?;
+    if res.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(serde_json::from_slice::<TableEncryption>(&res)?))
+}
+
+fn save_table_encryption(
+    table: &str,
+    encryption: &TableEncryption,
+) -> Result<(), Box<dyn std::error::Error>> {
+    klave::ledger::get_table(ENCRYPTION_TABLE).set(table, &serde_json::to_vec(encryption)?)
+}
+
+/// Encrypts `plaintext` with the current key of `table`.
+///
+/// The output is laid out as `key version (4 bytes, big endian) || iv (12 bytes) || ciphertext`.
+/// `location` identifies where the entry is stored and is bound to the ciphertext as additional
+/// data, so that an entry cannot be moved to another key.
+pub fn encrypt_entry(
+    encryption: &TableEncryption,
+    table: &str,
+    location: &str,
+    plaintext: &[u8],
+) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let version = encryption.version;
+    let aes_gcm_key = table_key(encryption, table, version)?;
+    let iv = klave::crypto::random::get_random_bytes(AES_GCM_IV_SIZE as i32)?;
+    let encrypt_algo = EncryptAlgorithm::AesGcm(AesGcmParams {
+        iv: iv.clone(),
+        additional_data: location.as_bytes().to_vec(),
+        tag_length: 128, // 128 bits
+    });
+    let mut ciphertext = encrypt(&encrypt_algo, &aes_gcm_key, plaintext)?;
+
+    let mut entry = version.to_be_bytes().to_vec();
+    entry.extend_from_slice(&iv);
+    entry.append(&mut ciphertext);
+    Ok(entry)
+}
+
+/// Reverses [`encrypt_entry`]. Only entries encrypted with the current key can be decrypted.
+pub fn decrypt_entry(
+    encryption: &TableEncryption,
+    table: &str,
+    location: &str,
+    entry: &[u8],
+) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    if entry.len() < KEY_VERSION_SIZE + AES_GCM_IV_SIZE {
+        return Err(format!("encrypted entry '{location}' is truncated").into());
+    }
+    let (version, rest) = entry.split_at(KEY_VERSION_SIZE);
+    let (iv, ciphertext) = rest.split_at(AES_GCM_IV_SIZE);
+    let version = u32::from_be_bytes(version.try_into()?);
+    let aes_gcm_key = table_key(encryption, table, version)?;
+    let decrypt_algo = EncryptAlgorithm::AesGcm(AesGcmParams {
+        iv: iv.to_vec(),
+        additional_data: location.as_bytes().to_vec(),
+        tag_length: 128, // 128 bits
+    });
+    decrypt(&decrypt_algo, &aes_gcm_key, ciphertext)
+}
+
+pub fn entry_location(table: &str, key: &str) -> String {
+    format!("{table}/{key}")
+}
+
+/// Re-encrypts every entry of `table`, the history of its live and deleted keys and its blobs, with the current key of `new`.
+/// `old` is `None` when the table was stored in plaintext until now.
+fn reencrypt_table(
+    table: &str,
+    old: Option<&TableEncryption>,
+    new: &TableEncryption,
+) -> Result<usize, Box<dyn std::error::Error>> {
+    let mut reencrypted = 0;
+    // Deleted keys are no longer listed by the index but keep their version chain
+    let mut keys = index::load_keys(table)?;
+    keys.extend(history::load_keys(table)?);
+    keys.sort();
+    keys.dedup();
+    for key in keys {
+        history::reencrypt(table, &key, old, new)?;
+        let location = entry_location(table, &key);
+        let stored = klave::ledger::get_table(table).get(&key)?;
+        if stored.is_empty() {
+            continue;
+        }
+        let plaintext = match old {
+            Some(old) => decrypt_entry(old, table, &location, &stored)?,
+            None => stored,
+        };
+        klave::ledger::get_table(table)
+            .set(&key, &encrypt_entry(new, table, &location, &plaintext)?)?;
+        reencrypted += 1;
+    }
+    reencrypted += blob::reencrypt(table, old, new)?;
+    Ok(reencrypted)
+}
+
+fn enable(table: &str) -> Result<usize, Box<dyn std::error::Error>> {
+    acl::authorize(table, None, Permission::Admin)?;
+    if load_table_encryption(table)?.is_some() {
+        return Err(format!("encryption is already enabled on table '{table}'").into());
+    }
+    let encryption = TableEncryption {
+        version: 0,
+        master_key_name: create_master_key()?,
+    };
+    let reencrypted = reencrypt_table(table, None, &encryption)?;
+    save_table_encryption(table, &encryption)?;
+    Ok(reencrypted)
+}
+
+fn rotate(table: &str) -> Result<usize, Box<dyn std::error::Error>> {
+    acl::authorize(table, None, Permission::Admin)?;
+    let Some(old) = load_table_encryption(table)? else {
+        return Err(format!("encryption is not enabled on table '{table}'").into());
+    };
+    let new = TableEncryption {
+        version: old.version + 1,
+        master_key_name: create_master_key()?,
+    };
+    let reencrypted = reencrypt_table(table, Some(&old), &new)?;
+    save_table_encryption(table, &new)?;
+    // Nothing is encrypted with the old key anymore
+    delete_key(&load_key(&old.master_key_name)?)?;
+    Ok(reencrypted)
+}
+
+pub fn enable_encryption(cmd: String) {
+    let Ok(input) = serde_json::from_str::<EncryptionInput>(&cmd) else {
+        klave::notifier::send_string(&format!(
+            "ERROR: failed to parse '{cmd}' as EncryptionInput"
+        ));
+        klave::router::cancel_transaction();
+        return;
+    };
+    if let Err(e) = check_table_name(&input.table) {
+        klave::notifier::send_string(&format!("ERROR: {e}"));
+        klave::router::cancel_transaction();
+        return;
+    }
+    match enable(&input.table) {
+        Ok(encrypted) => {
+            let _ = klave::notifier::send_json(&json!({
+                "table": input.table,
+                "encrypted": encrypted,
+            }));
+        }
+        Err(e) => {
+            klave::notifier::send_string(&format!("ERROR: failed to enable encryption: '{e}'"));
+            klave::router::cancel_transaction();
+        }
+    }
+}
+
+pub fn rotate_encryption_key(cmd: String) {
+    let Ok(input) = serde_json::from_str::<EncryptionInput>(&cmd) else {
+        klave::notifier::send_string(&format!(
+            "ERROR: failed to parse '{cmd}' as EncryptionInput"
+        ));
+        klave::router::cancel_transaction();
+        return;
+    };
+    if let Err(e) = check_table_name(&input.table) {
+        klave::notifier::send_string(&format!("ERROR: {e}"));
+        klave::router::cancel_transaction();
+        return;
+    }
+    match rotate(&input.table) {
+        Ok(reencrypted) => {
+            let _ = klave::notifier::send_json(&json!({
+                "table": input.table,
+                "reencrypted": reencrypted,
+            }));
+        }
+        Err(e) => {
+            klave::notifier::send_string(&format!("ERROR: failed to rotate key: '{e}'"));
+            klave::router::cancel_transaction();
+        }
+    }
+}
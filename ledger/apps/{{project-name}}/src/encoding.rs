@@ -0,0 +1,106 @@
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+/// Encoding of a value exchanged with the app.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Encoding {
+    #[default]
+    Utf8,
+    Hex,
+    Base64,
+}
+
+impl Encoding {
+    pub fn decode(&self, data: &str) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        match self {
+            Encoding::Utf8 => Ok(data.as_bytes().to_vec()),
+            Encoding::Hex => Ok(hex::decode(data)?),
+            Encoding::Base64 => Ok(STANDARD.decode(data)?),
+        }
+    }
+
+    pub fn encode(&self, bytes: &[u8]) -> Result<String, Box<dyn std::error::Error>> {
+        match self {
+            Encoding::Utf8 => Ok(String::from_utf8(bytes.to_vec()).map_err(|_| {
+                "the value is not valid utf8, load it with the hex or base64 encoding"
+            })?),
+            Encoding::Hex => Ok(hex::encode(bytes)),
+            Encoding::Base64 => Ok(STANDARD.encode(bytes)),
+        }
+    }
+}
+
+/// Represents raw bytes stored in a table without schema as a record.
+///
+/// Valid utf8 is kept as a string, anything else becomes `{ "base64": "..." }` so that binary
+/// values can still be hashed, indexed and kept in history like any other record.
+pub fn bytes_to_value(bytes: Vec<u8>) -> Value {
+    match String::from_utf8(bytes) {
+        Ok(s) => Value::String(s),
+        Err(e) => json!({ "base64": STANDARD.encode(e.into_bytes()) }),
+    }
+}
+
+/// Reverses [`bytes_to_value`], returning `None` for values that cannot be stored as raw bytes.
+pub fn value_to_bytes(value: &Value) -> Result<Option<Vec<u8>>, Box<dyn std::error::Error>> {
+    if let Some(s) = value.as_str() {
+        return Ok(Some(s.as_bytes().to_vec()));
+    }
+    match value.as_object() {
+        Some(object) if object.len() == 1 => match object.get("base64").and_then(Value::as_str) {
+            Some(data) => Ok(Some(STANDARD.decode(data)?)),
+            None => Ok(None),
+        },
+        _ => Ok(None),
+    }
+}
+
+/// Decodes a value received with `encoding` into its record representation.
+pub fn decode_value(
+    value: &Value,
+    encoding: Encoding,
+) -> Result<Value, Box<dyn std::error::Error>> {
+    if encoding == Encoding::Utf8 {
+        return Ok(value.clone());
+    }
+    let Some(data) = value.as_str() else {
+        return Err(format!("{encoding:?} encoded values must be strings").into());
+    };
+    Ok(bytes_to_value(encoding.decode(data)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bytes_roundtrip() {
+        let binary = vec![0xff, 0x00, 0xfe];
+        let value = bytes_to_value(binary.clone());
+        assert_eq!(value, json!({ "base64": "/wD+" }));
+        assert_eq!(value_to_bytes(&value).unwrap(), Some(binary));
+
+        let text = bytes_to_value(b"hello".to_vec());
+        assert_eq!(text, json!("hello"));
+        assert_eq!(value_to_bytes(&text).unwrap(), Some(b"hello".to_vec()));
+
+        assert_eq!(value_to_bytes(&json!({ "a": 1 })).unwrap(), None);
+    }
+
+    #[test]
+    fn test_decode_value() {
+        assert_eq!(
+            decode_value(&json!("68656c6c6f"), Encoding::Hex).unwrap(),
+            json!("hello")
+        );
+        assert_eq!(
+            decode_value(&json!("/wD+"), Encoding::Base64).unwrap(),
+            json!({ "base64": "/wD+" })
+        );
+        assert!(decode_value(&json!("zz"), Encoding::Hex).is_err());
+        assert!(decode_value(&json!(1), Encoding::Base64).is_err());
+        assert!(Encoding::Utf8.encode(&[0xff]).is_err());
+    }
+}
@@ -0,0 +1,288 @@
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::{
+    acl::{self, Permission},
+    crypto::{self, TableEncryption},
+    index::{self, page_size},
+    records::{check_table_name, default_table},
+};
+
+pub(crate) const HISTORY_TABLE: &str = "_history";
+pub(crate) const HISTORY_HEAD_TABLE: &str = "_history_heads";
+
+/// One link of the append-only version chain of a key.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Version {
+    pub version: u64,
+    pub sender: String,
+    /// `trusted_time` of the transaction that wrote this version, in nanoseconds.
+    pub trusted_time: u64,
+    /// `None` when this version deletes the key.
+    pub value: Option<Value>,
+    /// Hex encoded encrypted value, replacing `value` in the ledger when encryption is enabled
+    /// on the table.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ciphertext: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct LoadVersionInput {
+    #[serde(default = "default_table")]
+    pub table: String,
+    pub key: String,
+    pub version: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct LoadHistoryInput {
+    #[serde(default = "default_table")]
+    pub table: String,
+    pub key: String,
+    pub from_version: Option<u64>,
+    pub limit: Option<usize>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct LoadAtTimeInput {
+    #[serde(default = "default_table")]
+    pub table: String,
+    pub key: String,
+    /// Point in time, in nanoseconds, as returned by `trusted_time`.
+    pub time: u64,
+}
+
+// Heads of the version chains of `table`, one entry per key.
+fn head_table(table: &str) -> String {
+    index::per_table(HISTORY_HEAD_TABLE, table)
+}
+
+fn version_key(table: &str, key: &str, version: u64) -> String {
+    format!("{table}/{key}/{version:020}")
+}
+
+/// Lists the keys of `table` that have a version chain, including deleted keys, sorted.
+pub fn load_keys(table: &str) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    index::list_prefixed(&head_table(table), "")
+}
+
+/// Returns the latest version of `key`, 0 if it has never been written.
+pub fn current_version(table: &str, key: &str) -> Result<u64, Box<dyn std::error::Error>> {
+    let res = klave::ledger::get_table(&head_table(table)).get(key)?;
+    if res.is_empty() {
+        return Ok(0);
+    }
+    Ok(serde_json::from_slice::<u64>(&res)?)
+}
+
+pub fn load_version(
+    table: &str,
+    key: &str,
+    version: u64,
+) -> Result<Option<Version>, Box<dyn std::error::Error>> {
+    let location = version_key(table, key, version);
+    let res = klave::ledger::get_table(HISTORY_TABLE).get(&location)?;
+    if res.is_empty() {
+        return Ok(None);
+    }
+    let mut entry = serde_json::from_slice::<Version>(&res)?;
+    if let Some(ciphertext) = entry.ciphertext.take() {
+        let Some(encryption) = crypto::load_table_encryption(table)? else {
+            return Err(format!("version {version} of key '{key}' is encrypted").into());
+        };
+        let plaintext =
+            crypto::decrypt_entry(&encryption, table, &location, &hex::decode(ciphertext)?)?;
+        entry.value = Some(serde_json::from_slice::<Value>(&plaintext)?);
+    }
+    Ok(Some(entry))
+}
+
+fn save_version(
+    table: &str,
+    key: &str,
+    mut entry: Version,
+    encryption: Option<&TableEncryption>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let location = version_key(table, key, entry.version);
+    if let (Some(encryption), Some(value)) = (encryption, entry.value.take()) {
+        let ciphertext =
+            crypto::encrypt_entry(encryption, table, &location, &serde_json::to_vec(&value)?)?;
+        entry.ciphertext = Some(hex::encode(ciphertext));
+    }
+    klave::ledger::get_table(HISTORY_TABLE).set(&location, &serde_json::to_vec(&entry)?)
+}
+
+/// Appends a new version of `key` holding `value`, `None` recording a deletion.
+pub fn on_write(
+    table: &str,
+    key: &str,
+    value: Option<&Value>,
+) -> Result<u64, Box<dyn std::error::Error>> {
+    let sender = klave::context::get("sender")?;
+    let trusted_time = klave::context::get("trusted_time")?.parse::<u64>()?;
+    let version = current_version(table, key)? + 1;
+    let entry = Version {
+        version,
+        sender,
+        trusted_time,
+        value: value.cloned(),
+        ciphertext: None,
+    };
+    save_version(
+        table,
+        key,
+        entry,
+        crypto::load_table_encryption(table)?.as_ref(),
+    )?;
+    klave::ledger::get_table(&head_table(table)).set(key, &serde_json::to_vec(&version)?)?;
+    Ok(version)
+}
+
+/// Re-encrypts the whole version chain of `key` with the current key of `new`.
+/// `old` is `None` when the chain was stored in plaintext until now.
+pub fn reencrypt(
+    table: &str,
+    key: &str,
+    old: Option<&TableEncryption>,
+    new: &TableEncryption,
+) -> Result<(), Box<dyn std::error::Error>> {
+    for version in 1..=current_version(table, key)? {
+        let location = version_key(table, key, version);
+        let res = klave::ledger::get_table(HISTORY_TABLE).get(&location)?;
+        if res.is_empty() {
+            continue;
+        }
+        let mut entry = serde_json::from_slice::<Version>(&res)?;
+        if let (Some(old), Some(ciphertext)) = (old, entry.ciphertext.take()) {
+            let plaintext =
+                crypto::decrypt_entry(old, table, &location, &hex::decode(ciphertext)?)?;
+            entry.value = Some(serde_json::from_slice::<Value>(&plaintext)?);
+        }
+        save_version(table, key, entry, Some(new))?;
+    }
+    Ok(())
+}
+
+/// Finds the version of `key` that was current at `time`.
+///
+/// Versions are appended in transaction order, so their `trusted_time` is non-decreasing and
+/// the chain can be binary searched.
+pub fn load_at_time(
+    table: &str,
+    key: &str,
+    time: u64,
+) -> Result<Option<Version>, Box<dyn std::error::Error>> {
+    let (mut low, mut high) = (1, current_version(table, key)?);
+    let mut found = None;
+    while low <= high {
+        let mid = low + (high - low) / 2;
+        let Some(version) = load_version(table, key, mid)? else {
+            return Err(format!("version {mid} of key '{key}' is missing").into());
+        };
+        if version.trusted_time <= time {
+            low = mid + 1;
+            found = Some(version);
+        } else {
+            high = mid - 1;
+        }
+    }
+    Ok(found)
+}
+
+pub fn load_version_query(cmd: String) {
+    let Ok(input) = serde_json::from_str::<LoadVersionInput>(&cmd) else {
+        klave::notifier::send_string(&format!("failed to parse '{cmd}' as json"));
+        return;
+    };
+    if let Err(e) = check_table_name(&input.table) {
+        klave::notifier::send_string(&e);
+        return;
+    }
+    if let Err(e) = acl::authorize(&input.table, Some(&input.key), Permission::Read) {
+        klave::notifier::send_string(&e.to_string());
+        return;
+    }
+    match load_version(&input.table, &input.key, input.version) {
+        Ok(Some(version)) => {
+            let _ = klave::notifier::send_json(&version);
+        }
+        Ok(None) => {
+            klave::notifier::send_string(&format!(
+                "version {} of key '{}' was not found in table {}",
+                input.version, input.key, input.table
+            ));
+        }
+        Err(e) => {
+            klave::notifier::send_string(&format!("failed to read from ledger: '{e}'"));
+        }
+    }
+}
+
+pub fn load_history(cmd: String) {
+    let Ok(input) = serde_json::from_str::<LoadHistoryInput>(&cmd) else {
+        klave::notifier::send_string(&format!("failed to parse '{cmd}' as json"));
+        return;
+    };
+    if let Err(e) = check_table_name(&input.table) {
+        klave::notifier::send_string(&e);
+        return;
+    }
+    if let Err(e) = acl::authorize(&input.table, Some(&input.key), Permission::Read) {
+        klave::notifier::send_string(&e.to_string());
+        return;
+    }
+    let load_page = || -> Result<Value, Box<dyn std::error::Error>> {
+        let head = current_version(&input.table, &input.key)?;
+        let from = input.from_version.unwrap_or(1).max(1);
+        let to = head.min(from.saturating_add(page_size(input.limit) as u64) - 1);
+        let mut versions = Vec::new();
+        for v in from..=to {
+            if let Some(version) = load_version(&input.table, &input.key, v)? {
+                versions.push(version);
+            }
+        }
+        let next_version = if to < head { Some(to + 1) } else { None };
+        Ok(json!({
+            "current_version": head,
+            "versions": versions,
+            "next_version": next_version,
+        }))
+    };
+    match load_page() {
+        Ok(page) => {
+            let _ = klave::notifier::send_json(&page);
+        }
+        Err(e) => {
+            klave::notifier::send_string(&format!("failed to read from ledger: '{e}'"));
+        }
+    }
+}
+
+pub fn load_at_time_query(cmd: String) {
+    let Ok(input) = serde_json::from_str::<LoadAtTimeInput>(&cmd) else {
+        klave::notifier::send_string(&format!("failed to parse '{cmd}' as json"));
+        return;
+    };
+    if let Err(e) = check_table_name(&input.table) {
+        klave::notifier::send_string(&e);
+        return;
+    }
+    if let Err(e) = acl::authorize(&input.table, Some(&input.key), Permission::Read) {
+        klave::notifier::send_string(&e.to_string());
+        return;
+    }
+    match load_at_time(&input.table, &input.key, input.time) {
+        Ok(Some(version)) => {
+            let _ = klave::notifier::send_json(&version);
+        }
+        Ok(None) => {
+            klave::notifier::send_string(&format!(
+                "the key '{}' did not exist in table {} at time {}",
+                input.key, input.table, input.time
+            ));
+        }
+        Err(e) => {
+            klave::notifier::send_string(&format!("failed to read from ledger: '{e}'"));
+        }
+    }
+}
@@ -0,0 +1,470 @@
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::{
+    acl::{self, Permission},
+    records::{self, check_table_name},
+};
+
+pub(crate) const KEYS_TABLE: &str = "_keys";
+pub(crate) const INDEX_DEFINITION_TABLE: &str = "_index_definitions";
+pub(crate) const INDEX_ENTRY_TABLE: &str = "_index_entries";
+
+pub(crate) const DEFAULT_PAGE_SIZE: usize = 100;
+pub(crate) const MAX_PAGE_SIZE: usize = 1000;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct IndexDefinition {
+    pub name: String,
+    /// Field of the record to index, either a top-level field name or a JSON pointer (`/a/b`).
+    pub field: String,
+}
+
+impl IndexDefinition {
+    fn extract(&self, record: &Value) -> Option<String> {
+        let pointer = if self.field.starts_with('/') {
+            self.field.clone()
+        } else {
+            format!("/{}", self.field)
+        };
+        match record.pointer(&pointer) {
+            None | Some(Value::Null) => None,
+            Some(v) => Some(v.to_string()),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RegisterIndexInput {
+    pub table: String,
+    pub name: String,
+    pub field: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ListKeysInput {
+    pub table: String,
+    #[serde(default)]
+    pub prefix: String,
+    pub cursor: Option<String>,
+    pub limit: Option<usize>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct FindByIndexInput {
+    pub table: String,
+    pub index: String,
+    pub value: Value,
+    pub cursor: Option<String>,
+    pub limit: Option<usize>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Page {
+    pub keys: Vec<String>,
+    pub next_cursor: Option<String>,
+}
+
+/// Lists the keys of `ledger_table` starting with `prefix`, with the prefix stripped, sorted.
+pub(crate) fn list_prefixed(
+    ledger_table: &str,
+    prefix: &str,
+) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    let mut keys: Vec<String> = klave::ledger::get_table(ledger_table)
+        .list_keys()?
+        .into_iter()
+        .filter_map(|k| k.strip_prefix(prefix).map(|k| k.to_string()))
+        .collect();
+    keys.sort();
+    Ok(keys)
+}
+
+/// Index names end up in the keys of the index entries, so they cannot contain `/`.
+pub fn check_index_name(name: &str) -> Result<(), String> {
+    if name.is_empty() {
+        return Err("index name cannot be empty".to_string());
+    }
+    if name.contains('/') {
+        return Err(format!("index name '{name}' cannot contain '/'"));
+    }
+    Ok(())
+}
+
+/// Returns one page of `sorted` restricted to `prefix`, starting strictly after `cursor`.
+pub fn paginate(sorted: &[String], prefix: &str, cursor: Option<&str>, limit: usize) -> Page {
+    let mut start = sorted.partition_point(|k| k.as_str() < prefix);
+    if let Some(c) = cursor {
+        start = start.max(sorted.partition_point(|k| k.as_str() <= c));
+    }
+    let mut keys: Vec<String> = sorted[start..]
+        .iter()
+        .take_while(|k| k.starts_with(prefix))
+        .take(limit + 1)
+        .cloned()
+        .collect();
+    let next_cursor = if keys.len() > limit {
+        keys.truncate(limit);
+        keys.last().cloned()
+    } else {
+        None
+    };
+    Page { keys, next_cursor }
+}
+
+pub(crate) fn page_size(limit: Option<usize>) -> usize {
+    limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE)
+}
+
+/// Ledger table holding the entries of `table` in `ledger_table`, e.g. `_keys/users`.
+///
+/// `list_keys` reads a whole ledger table, so giving every table its own bounds a listing to
+/// the entries of that table. Table and index names cannot contain `/`, so the names are
+/// unambiguous.
+pub(crate) fn per_table(ledger_table: &str, table: &str) -> String {
+    format!("{ledger_table}/{table}")
+}
+
+// Ledger table of the entries of an index, one entry per indexed key.
+fn index_entry_table(table: &str, index: &str) -> String {
+    format!("{}/{index}", per_table(INDEX_ENTRY_TABLE, table))
+}
+
+// Prefix of the entries of the keys whose indexed field equals `value`. The value is hex
+// encoded since it may contain `/`.
+fn index_entry_prefix(value: &str) -> String {
+    format!("{}/", hex::encode(value))
+}
+
+fn add_index_entry(
+    table: &str,
+    index: &str,
+    value: &str,
+    key: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let entry = format!("{}{key}", index_entry_prefix(value));
+    klave::ledger::get_table(&index_entry_table(table, index)).set(&entry, key.as_bytes())
+}
+
+fn remove_index_entry(
+    table: &str,
+    index: &str,
+    value: &str,
+    key: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let entry = format!("{}{key}", index_entry_prefix(value));
+    klave::ledger::get_table(&index_entry_table(table, index)).remove(&entry)
+}
+
+/// Lists the keys of `table`, sorted.
+pub fn load_keys(table: &str) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    list_prefixed(&per_table(KEYS_TABLE, table), "")
+}
+
+pub fn load_index_definitions(
+    table: &str,
+) -> Result<Vec<IndexDefinition>, Box<dyn std::error::Error>> {
+    let res = klave::ledger::get_table(INDEX_DEFINITION_TABLE).get(table)?;
+    if res.is_empty() {
+        return Ok(Vec::new());
+    }
+    Ok(serde_json::from_slice::<Vec<IndexDefinition>>(&res)?)
+}
+
+/// Keeps the key list and the secondary indexes of `table` consistent with a write.
+///
+/// `old` is the record previously stored under `key` and `new` the record being written,
+/// `None` standing respectively for an insertion and a deletion.
+pub fn on_write(
+    table: &str,
+    key: &str,
+    old: Option<&Value>,
+    new: Option<&Value>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let keys = klave::ledger::get_table(&per_table(KEYS_TABLE, table));
+    match (old, new) {
+        (None, Some(_)) => keys.set(key, key.as_bytes())?,
+        (Some(_), None) => keys.remove(key)?,
+        _ => (),
+    }
+
+    for definition in load_index_definitions(table)? {
+        let old_entry = old.and_then(|v| definition.extract(v));
+        let new_entry = new.and_then(|v| definition.extract(v));
+        if old_entry == new_entry {
+            continue;
+        }
+        if let Some(entry) = old_entry {
+            remove_index_entry(table, &definition.name, &entry, key)?;
+        }
+        if let Some(entry) = new_entry {
+            add_index_entry(table, &definition.name, &entry, key)?;
+        }
+    }
+    Ok(())
+}
+
+fn load_entries(table: &str, keys: &[String]) -> Result<Vec<Value>, Box<dyn std::error::Error>> {
+    let mut entries = Vec::with_capacity(keys.len());
+    for key in keys {
+        if let Some(value) = records::get_record(table, key)? {
+            entries.push(json!({ "key": key, "value": value }));
+        }
+    }
+    Ok(entries)
+}
+
+pub fn register_index(cmd: String) {
+    let Ok(input) = serde_json::from_str::<RegisterIndexInput>(&cmd) else {
+        klave::notifier::send_string(&format!(
+            "ERROR: failed to parse '{cmd}' as RegisterIndexInput"
+        ));
+        klave::router::cancel_transaction();
+        return;
+    };
+    if let Err(e) = check_table_name(&input.table) {
+        klave::notifier::send_string(&format!("ERROR: {e}"));
+        klave::router::cancel_transaction();
+        return;
+    }
+    if let Err(e) = check_index_name(&input.name) {
+        klave::notifier::send_string(&format!("ERROR: {e}"));
+        klave::router::cancel_transaction();
+        return;
+    }
+    if let Err(e) = acl::authorize(&input.table, None, Permission::Admin) {
+        klave::notifier::send_string(&format!("ERROR: {e}"));
+        klave::router::cancel_transaction();
+        return;
+    }
+    let mut definitions = match load_index_definitions(&input.table) {
+        Ok(d) => d,
+        Err(e) => {
+            klave::notifier::send_string(&format!("ERROR: failed to load indexes: '{e}'"));
+            klave::router::cancel_transaction();
+            return;
+        }
+    };
+    if definitions.iter().any(|d| d.name == input.name) {
+        klave::notifier::send_string(&format!(
+            "ERROR: index '{}' already exists on table '{}'",
+            input.name, input.table
+        ));
+        klave::router::cancel_transaction();
+        return;
+    }
+    let definition = IndexDefinition {
+        name: input.name.clone(),
+        field: input.field.clone(),
+    };
+
+    // Back-fill the index with the records already stored in the table
+    let backfill = || -> Result<usize, Box<dyn std::error::Error>> {
+        let mut indexed = 0;
+        for key in load_keys(&input.table)? {
+            let Some(record) = records::get_record(&input.table, &key)? else {
+                continue;
+            };
+            if let Some(entry) = definition.extract(&record) {
+                add_index_entry(&input.table, &definition.name, &entry, &key)?;
+                indexed += 1;
+            }
+        }
+        Ok(indexed)
+    };
+    let indexed = match backfill() {
+        Ok(n) => n,
+        Err(e) => {
+            klave::notifier::send_string(&format!("ERROR: failed to build index: '{e}'"));
+            klave::router::cancel_transaction();
+            return;
+        }
+    };
+
+    definitions.push(definition);
+    let saved = serde_json::to_vec(&definitions)
+        .map_err(|e| e.into())
+        .and_then(|bytes| {
+            klave::ledger::get_table(INDEX_DEFINITION_TABLE).set(&input.table, &bytes)
+        });
+    if let Err(e) = saved {
+        klave::notifier::send_string(&format!("ERROR: failed to save index: '{e}'"));
+        klave::router::cancel_transaction();
+        return;
+    }
+    let _ = klave::notifier::send_json(&json!({
+        "registered": true,
+        "table": input.table,
+        "index": input.name,
+        "indexed": indexed,
+    }));
+}
+
+pub fn list_keys(cmd: String) {
+    let Ok(input) = serde_json::from_str::<ListKeysInput>(&cmd) else {
+        klave::notifier::send_string(&format!("ERROR: failed to parse '{cmd}' as ListKeysInput"));
+        return;
+    };
+    if let Err(e) = check_table_name(&input.table) {
+        klave::notifier::send_string(&format!("ERROR: {e}"));
+        return;
+    }
+    if let Err(e) = acl::authorize(&input.table, None, Permission::Read) {
+        klave::notifier::send_string(&format!("ERROR: {e}"));
+        return;
+    }
+    let keys = match load_keys(&input.table) {
+        Ok(k) => k,
+        Err(e) => {
+            klave::notifier::send_string(&format!("ERROR: failed to load keys: '{e}'"));
+            return;
+        }
+    };
+    let page = paginate(
+        &keys,
+        &input.prefix,
+        input.cursor.as_deref(),
+        page_size(input.limit),
+    );
+    let _ = klave::notifier::send_json(&page);
+}
+
+pub fn scan_prefix(cmd: String) {
+    let Ok(input) = serde_json::from_str::<ListKeysInput>(&cmd) else {
+        klave::notifier::send_string(&format!("ERROR: failed to parse '{cmd}' as ListKeysInput"));
+        return;
+    };
+    if let Err(e) = check_table_name(&input.table) {
+        klave::notifier::send_string(&format!("ERROR: {e}"));
+        return;
+    }
+    if let Err(e) = acl::authorize(&input.table, None, Permission::Read) {
+        klave::notifier::send_string(&format!("ERROR: {e}"));
+        return;
+    }
+    let keys = match load_keys(&input.table) {
+        Ok(k) => k,
+        Err(e) => {
+            klave::notifier::send_string(&format!("ERROR: failed to load keys: '{e}'"));
+            return;
+        }
+    };
+    let page = paginate(
+        &keys,
+        &input.prefix,
+        input.cursor.as_deref(),
+        page_size(input.limit),
+    );
+    match load_entries(&input.table, &page.keys) {
+        Ok(entries) => {
+            let _ = klave::notifier::send_json(&json!({
+                "entries": entries,
+                "next_cursor": page.next_cursor,
+            }));
+        }
+        Err(e) => {
+            klave::notifier::send_string(&format!("ERROR: failed to read from ledger: '{e}'"));
+        }
+    }
+}
+
+pub fn find_by_index(cmd: String) {
+    let Ok(input) = serde_json::from_str::<FindByIndexInput>(&cmd) else {
+        klave::notifier::send_string(&format!(
+            "ERROR: failed to parse '{cmd}' as FindByIndexInput"
+        ));
+        return;
+    };
+    if let Err(e) = check_table_name(&input.table) {
+        klave::notifier::send_string(&format!("ERROR: {e}"));
+        return;
+    }
+    if let Err(e) = acl::authorize(&input.table, None, Permission::Read) {
+        klave::notifier::send_string(&format!("ERROR: {e}"));
+        return;
+    }
+    match load_index_definitions(&input.table) {
+        Ok(definitions) if definitions.iter().any(|d| d.name == input.index) => (),
+        Ok(_) => {
+            klave::notifier::send_string(&format!(
+                "ERROR: index '{}' not found on table '{}'",
+                input.index, input.table
+            ));
+            return;
+        }
+        Err(e) => {
+            klave::notifier::send_string(&format!("ERROR: failed to load indexes: '{e}'"));
+            return;
+        }
+    }
+    let prefix = index_entry_prefix(&input.value.to_string());
+    let keys = match list_prefixed(&index_entry_table(&input.table, &input.index), &prefix) {
+        Ok(k) => k,
+        Err(e) => {
+            klave::notifier::send_string(&format!("ERROR: failed to load index: '{e}'"));
+            return;
+        }
+    };
+    let page = paginate(&keys, "", input.cursor.as_deref(), page_size(input.limit));
+    match load_entries(&input.table, &page.keys) {
+        Ok(entries) => {
+            let _ = klave::notifier::send_json(&json!({
+                "entries": entries,
+                "next_cursor": page.next_cursor,
+            }));
+        }
+        Err(e) => {
+            klave::notifier::send_string(&format!("ERROR: failed to read from ledger: '{e}'"));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_paginate() {
+        let keys: Vec<String> = ["a1", "b1", "b2", "b3", "c1"]
+            .iter()
+            .map(|k| k.to_string())
+            .collect();
+
+        let page = paginate(&keys, "b", None, 2);
+        assert_eq!(page.keys, vec!["b1", "b2"]);
+        assert_eq!(page.next_cursor.as_deref(), Some("b2"));
+
+        let page = paginate(&keys, "b", page.next_cursor.as_deref(), 2);
+        assert_eq!(page.keys, vec!["b3"]);
+        assert_eq!(page.next_cursor, None);
+
+        let page = paginate(&keys, "", None, 10);
+        assert_eq!(page.keys.len(), 5);
+        assert_eq!(page.next_cursor, None);
+    }
+
+    #[test]
+    fn test_extract() {
+        let definition = IndexDefinition {
+            name: "by_city".to_string(),
+            field: "/address/city".to_string(),
+        };
+        let record = json!({ "address": { "city": "Paris" } });
+        assert_eq!(definition.extract(&record).as_deref(), Some("\"Paris\""));
+        assert_eq!(definition.extract(&json!({ "address": null })), None);
+    }
+
+    #[test]
+    fn test_entry_keys() {
+        // Values containing `/` cannot be mistaken for a longer prefix
+        let a = index_entry_prefix("\"a\"");
+        let b = index_entry_prefix("\"a/b\"");
+        assert!(!format!("{b}k").starts_with(&a));
+        assert_eq!(per_table(KEYS_TABLE, "users"), "_keys/users");
+        assert_eq!(
+            index_entry_table("users", "by_city"),
+            "_index_entries/users/by_city"
+        );
+        assert!(check_index_name("by/city").is_err());
+        assert!(check_index_name("by_city").is_ok());
+    }
+}
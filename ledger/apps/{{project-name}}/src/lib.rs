@@ -1,58 +1,145 @@
 #[allow(warnings)]
 mod bindings;
+mod acl;
+mod blob;
+mod crypto;
+mod encoding;
+mod history;
+mod index;
+mod merkle;
+mod records;
+mod schema;
 
 use bindings::Guest;
-use serde_json::json;
-use serde_json::Value;
 struct Component;
 
 impl Guest for Component {
     fn register_routes() {
         klave::router::add_user_query("load-from-ledger");
         klave::router::add_user_transaction("insert-in-ledger");
+        klave::router::add_user_transaction("delete-from-ledger");
+        klave::router::add_user_transaction("compare-and-swap");
+        klave::router::add_user_transaction("batch-write");
+        klave::router::add_user_query("load-version");
+        klave::router::add_user_query("load-history");
+        klave::router::add_user_query("load-at-time");
+        klave::router::add_user_transaction("register-schema");
+        klave::router::add_user_query("load-schema");
+        klave::router::add_user_transaction("register-index");
+        klave::router::add_user_query("list-keys");
+        klave::router::add_user_query("scan-prefix");
+        klave::router::add_user_query("find-by-index");
+        klave::router::add_user_transaction("grant-access");
+        klave::router::add_user_transaction("revoke-access");
+        klave::router::add_user_query("load-acl");
+        klave::router::add_user_transaction("enable-encryption");
+        klave::router::add_user_transaction("rotate-encryption-key");
+        klave::router::add_user_query("get-root");
+        klave::router::add_user_query("get-proof");
+        klave::router::add_user_query("verify-proof");
+        klave::router::add_user_transaction("put-blob");
+        klave::router::add_user_query("load-blob");
+        klave::router::add_user_transaction("delete-blob");
     }
 
     fn load_from_ledger(cmd: String) {
-        let Ok(v) = serde_json::from_str::<Value>(&cmd) else {
-            klave::notifier::send_string(&format!("failed to parse '{cmd}' as json"));
-            return;
-        };
-        let key = v["key"].as_str().unwrap();
-        let Ok(res) = klave::ledger::get_table("my_table").get(key) else {
-            klave::notifier::send_string(&format!("failed to read from ledger: '{cmd}'"));
-            return;
-        };
-        let msg = if res.is_empty() {
-            format!("the key '{cmd}' was not found in table my_table")
-        } else {
-            let result_as_json = json!({
-                "value": String::from_utf8(res).unwrap_or("!! utf8 parsing error !!".to_owned()),
-            });
-            format!("{result_as_json}")
-        };
-        klave::notifier::send_string(&msg);
+        records::load_from_ledger(cmd);
     }
 
     fn insert_in_ledger(cmd: String) {
-        let Ok(v) = serde_json::from_str::<Value>(&cmd) else {
-            klave::notifier::send_string(&format!("failed to parse '{cmd}' as json"));
-            klave::router::cancel_transaction();
-            return;
-        };
-        let key = v["key"].as_str().unwrap();
-        let value = v["value"].as_str().unwrap().as_bytes();
-        if let Err(e) = klave::ledger::get_table("my_table").set(key, value) {
-            klave::notifier::send_string(&format!("failed to write to ledger: '{e}'"));
-            klave::router::cancel_transaction();
-            return;
-        }
-
-        let result_as_json = json!({
-        "inserted": true,
-        "key": key,
-        "value": value
-        });
-        klave::notifier::send_string(&result_as_json.to_string());
+        records::insert_in_ledger(cmd);
+    }
+
+    fn delete_from_ledger(cmd: String) {
+        records::delete_from_ledger(cmd);
+    }
+
+    fn compare_and_swap(cmd: String) {
+        records::compare_and_swap(cmd);
+    }
+
+    fn batch_write(cmd: String) {
+        records::batch_write(cmd);
+    }
+
+    fn load_version(cmd: String) {
+        history::load_version_query(cmd);
+    }
+
+    fn load_history(cmd: String) {
+        history::load_history(cmd);
+    }
+
+    fn load_at_time(cmd: String) {
+        history::load_at_time_query(cmd);
+    }
+
+    fn register_schema(cmd: String) {
+        schema::register_schema(cmd);
+    }
+
+    fn load_schema(cmd: String) {
+        schema::load_schema_query(cmd);
+    }
+
+    fn register_index(cmd: String) {
+        index::register_index(cmd);
+    }
+
+    fn list_keys(cmd: String) {
+        index::list_keys(cmd);
+    }
+
+    fn scan_prefix(cmd: String) {
+        index::scan_prefix(cmd);
+    }
+
+    fn find_by_index(cmd: String) {
+        index::find_by_index(cmd);
+    }
+
+    fn grant_access(cmd: String) {
+        acl::grant_access(cmd);
+    }
+
+    fn revoke_access(cmd: String) {
+        acl::revoke_access(cmd);
+    }
+
+    fn load_acl(cmd: String) {
+        acl::load_acl(cmd);
+    }
+
+    fn enable_encryption(cmd: String) {
+        crypto::enable_encryption(cmd);
+    }
+
+    fn rotate_encryption_key(cmd: String) {
+        crypto::rotate_encryption_key(cmd);
+    }
+
+    fn get_root(cmd: String) {
+        merkle::get_root(cmd);
+    }
+
+    fn get_proof(cmd: String) {
+        merkle::get_proof(cmd);
+    }
+
+    fn verify_proof(cmd: String) {
+        merkle::verify_proof_query(cmd);
+    }
+
+    fn put_blob(cmd: String) {
+        blob::put_blob(cmd);
+    }
+
+    fn load_blob(cmd: String) {
+        blob::load_blob(cmd);
+    }
+
+    fn delete_blob(cmd: String) {
+        blob::delete_blob(cmd);
     }
 }
 
@@ -0,0 +1,540 @@
+use rs_merkle::{algorithms::Sha256, Hasher, MerkleProof};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::{
+    acl::{self, Permission},
+    history,
+    records::{check_table_name, default_table, record_bytes},
+};
+
+pub(crate) const MERKLE_TABLE: &str = "_merkle";
+pub(crate) const MERKLE_NODE_TABLE: &str = "_merkle_nodes";
+pub(crate) const MERKLE_ROOT_TABLE: &str = "_merkle_roots";
+pub(crate) const MERKLE_POSITION_TABLE: &str = "_merkle_positions";
+
+const LEAF_PREFIX: u8 = 0x00;
+const DELETED_LEAF_PREFIX: u8 = 0x01;
+
+/// Root of the tree of a table after a write.
+///
+/// The tree is append-only: every write adds one leaf, so a leaf count identifies a state of
+/// the tree. One root is kept per leaf count, with the `trusted_time` of the write.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RootEntry {
+    pub leaf_count: usize,
+    pub root: String,
+    /// `trusted_time` of the write that appended the last leaf, in nanoseconds.
+    pub trusted_time: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct GetRootInput {
+    pub table: String,
+    /// Point in time, in nanoseconds, defaulting to now.
+    pub time: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct GetProofInput {
+    #[serde(default = "default_table")]
+    pub table: String,
+    pub key: String,
+    /// Point in time, in nanoseconds, defaulting to now.
+    pub time: Option<u64>,
+}
+
+/// Inclusion proof of a version of a key, see the README for the verification procedure.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct InclusionProof {
+    pub table: String,
+    pub key: String,
+    pub version: u64,
+    /// Bytes the version is stored as, before encryption, hex encoded: the leaf commits to
+    /// them rather than to a serialization of the value.
+    pub data: String,
+    pub leaf: String,
+    pub leaf_index: usize,
+    pub leaf_count: usize,
+    /// Sibling hashes from the leaf up to the root, hex encoded.
+    pub proof: Vec<String>,
+    pub root: String,
+    pub updated_at: u64,
+}
+
+/// Position of the leaf of a version in the tree of its table.
+#[derive(Serialize, Deserialize, Debug)]
+struct LeafPosition {
+    index: usize,
+    /// Whether the version was stored as JSON, the table having a schema, or as raw bytes, so
+    /// that a proof can rebuild the bytes the leaf commits to from the history.
+    json: bool,
+}
+
+/// Storage of the complete nodes of a tree, addressed by level (0 for the leaves) and index.
+pub trait NodeStore {
+    fn load(&self, level: u32, index: usize) -> Result<[u8; 32], Box<dyn std::error::Error>>;
+    fn save(
+        &mut self,
+        level: u32,
+        index: usize,
+        hash: &[u8; 32],
+    ) -> Result<(), Box<dyn std::error::Error>>;
+}
+
+/// Nodes of the tree of a table, one ledger entry each.
+struct LedgerNodes<'a> {
+    table: &'a str,
+}
+
+impl NodeStore for LedgerNodes<'_> {
+    fn load(&self, level: u32, index: usize) -> Result<[u8; 32], Box<dyn std::error::Error>> {
+        let node = format!("{}/{level}/{index}", self.table);
+        let res = klave::ledger::get_table(MERKLE_NODE_TABLE).get(&node)?;
+        res.try_into()
+            .map_err(|_| format!("merkle node '{node}' is missing").into())
+    }
+
+    fn save(
+        &mut self,
+        level: u32,
+        index: usize,
+        hash: &[u8; 32],
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let node = format!("{}/{level}/{index}", self.table);
+        klave::ledger::get_table(MERKLE_NODE_TABLE).set(&node, hash)
+    }
+}
+
+fn key_bytes(key: &str) -> Vec<u8> {
+    let mut bytes = (key.len() as u32).to_be_bytes().to_vec();
+    bytes.extend_from_slice(key.as_bytes());
+    bytes
+}
+
+/// `SHA-256(0x00 || u32_be(len(key)) || key || u64_be(version) || SHA-256(data))`, `data`
+/// being the bytes the version is stored as, before encryption.
+pub fn leaf_hash(key: &str, version: u64, data: &[u8]) -> [u8; 32] {
+    let mut preimage = vec![LEAF_PREFIX];
+    preimage.extend(key_bytes(key));
+    preimage.extend(version.to_be_bytes());
+    preimage.extend(Sha256::hash(data));
+    Sha256::hash(&preimage)
+}
+
+/// `SHA-256(0x01 || u32_be(len(key)) || key || u64_be(version))`
+pub fn deleted_leaf_hash(key: &str, version: u64) -> [u8; 32] {
+    let mut preimage = vec![DELETED_LEAF_PREFIX];
+    preimage.extend(key_bytes(key));
+    preimage.extend(version.to_be_bytes());
+    Sha256::hash(&preimage)
+}
+
+fn decode_hash(hash: &str) -> Result<[u8; 32], Box<dyn std::error::Error>> {
+    let bytes = hex::decode(hash)?;
+    bytes
+        .try_into()
+        .map_err(|_| format!("'{hash}' is not a 32 bytes hash").into())
+}
+
+fn position_key(table: &str, key: &str, version: u64) -> String {
+    format!("{table}/{key}/{version:020}")
+}
+
+fn root_key(table: &str, leaf_count: usize) -> String {
+    format!("{table}/{leaf_count:020}")
+}
+
+/// Number of levels above the leaves of a tree of `leaf_count` leaves.
+fn depth(leaf_count: usize) -> u32 {
+    leaf_count.next_power_of_two().trailing_zeros()
+}
+
+/// Appends `leaf` to a tree of `leaf_count` leaves, saving the leaf and the nodes it completes.
+pub fn append_leaf(
+    store: &mut impl NodeStore,
+    leaf_count: usize,
+    leaf: &[u8; 32],
+) -> Result<(), Box<dyn std::error::Error>> {
+    store.save(0, leaf_count, leaf)?;
+    let (mut level, mut index, mut hash) = (0, leaf_count, *leaf);
+    // A right child completes its parent
+    while index % 2 == 1 {
+        hash = Sha256::concat_and_hash(&store.load(level, index - 1)?, Some(&hash));
+        level += 1;
+        index /= 2;
+        store.save(level, index, &hash)?;
+    }
+    Ok(())
+}
+
+/// Hash of the node at `level` and `index` in the tree of the first `leaf_count` leaves.
+///
+/// Complete nodes are loaded from the store. The last node of a level may only cover part of
+/// its range: it is recomputed from its children, a node without a right sibling being
+/// carried up to the next level unchanged, as in `rs_merkle`.
+pub fn node_hash(
+    store: &impl NodeStore,
+    level: u32,
+    index: usize,
+    leaf_count: usize,
+) -> Result<[u8; 32], Box<dyn std::error::Error>> {
+    if (index + 1) << level <= leaf_count {
+        return store.load(level, index);
+    }
+    let left = node_hash(store, level - 1, 2 * index, leaf_count)?;
+    if (2 * index + 1) << (level - 1) >= leaf_count {
+        return Ok(left);
+    }
+    let right = node_hash(store, level - 1, 2 * index + 1, leaf_count)?;
+    Ok(Sha256::concat_and_hash(&left, Some(&right)))
+}
+
+/// Root of the tree of the first `leaf_count` leaves.
+pub fn root_hash(
+    store: &impl NodeStore,
+    leaf_count: usize,
+) -> Result<[u8; 32], Box<dyn std::error::Error>> {
+    if leaf_count == 0 {
+        return Err("the tree is empty".into());
+    }
+    node_hash(store, depth(leaf_count), 0, leaf_count)
+}
+
+/// Sibling hashes of `leaf_index` from the leaf up to the root of the first `leaf_count` leaves.
+pub fn proof_hashes(
+    store: &impl NodeStore,
+    leaf_index: usize,
+    leaf_count: usize,
+) -> Result<Vec<[u8; 32]>, Box<dyn std::error::Error>> {
+    let mut hashes = Vec::new();
+    for level in 0..depth(leaf_count) {
+        let sibling = (leaf_index >> level) ^ 1;
+        // A node without a sibling is carried up and adds nothing to the proof
+        if sibling << level < leaf_count {
+            hashes.push(node_hash(store, level, sibling, leaf_count)?);
+        }
+    }
+    Ok(hashes)
+}
+
+/// Returns the number of leaves of the tree of `table`.
+pub fn leaf_count(table: &str) -> Result<usize, Box<dyn std::error::Error>> {
+    let res = klave::ledger::get_table(MERKLE_TABLE).get(table)?;
+    if res.is_empty() {
+        return Ok(0);
+    }
+    Ok(serde_json::from_slice::<usize>(&res)?)
+}
+
+fn load_root(
+    table: &str,
+    leaf_count: usize,
+) -> Result<Option<RootEntry>, Box<dyn std::error::Error>> {
+    let res = klave::ledger::get_table(MERKLE_ROOT_TABLE).get(&root_key(table, leaf_count))?;
+    if res.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(serde_json::from_slice::<RootEntry>(&res)?))
+}
+
+/// Finds the root of the tree of `table` at `time`, `None` for the current root.
+///
+/// Roots are recorded in write order, so their `trusted_time` is non-decreasing and they can
+/// be binary searched.
+pub fn load_root_at(
+    table: &str,
+    time: Option<u64>,
+) -> Result<Option<RootEntry>, Box<dyn std::error::Error>> {
+    let (mut low, mut high) = (1, leaf_count(table)?);
+    let Some(time) = time else {
+        return load_root(table, high);
+    };
+    let mut found = None;
+    while low <= high {
+        let mid = low + (high - low) / 2;
+        let Some(root) = load_root(table, mid)? else {
+            return Err(format!("root {mid} of table '{table}' is missing").into());
+        };
+        if root.trusted_time <= time {
+            low = mid + 1;
+            found = Some(root);
+        } else {
+            high = mid - 1;
+        }
+    }
+    Ok(found)
+}
+
+fn load_position(
+    table: &str,
+    key: &str,
+    version: u64,
+) -> Result<Option<LeafPosition>, Box<dyn std::error::Error>> {
+    let res =
+        klave::ledger::get_table(MERKLE_POSITION_TABLE).get(&position_key(table, key, version))?;
+    if res.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(serde_json::from_slice::<LeafPosition>(&res)?))
+}
+
+/// Appends the leaf of `version` of `key` to the tree of `table` and records the new root.
+/// `data` holds the bytes the version is stored as, before encryption, and is `None` when the
+/// key is deleted; `json` tells whether they are the JSON serialization of the value.
+pub fn on_write(
+    table: &str,
+    key: &str,
+    version: u64,
+    data: Option<&[u8]>,
+    json: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let leaf = match data {
+        Some(data) => leaf_hash(key, version, data),
+        None => deleted_leaf_hash(key, version),
+    };
+    let position = leaf_count(table)?;
+    let mut store = LedgerNodes { table };
+    append_leaf(&mut store, position, &leaf)?;
+    let root = RootEntry {
+        leaf_count: position + 1,
+        root: hex::encode(root_hash(&store, position + 1)?),
+        trusted_time: klave::context::get("trusted_time")?.parse::<u64>()?,
+    };
+    klave::ledger::get_table(MERKLE_POSITION_TABLE).set(
+        &position_key(table, key, version),
+        &serde_json::to_vec(&LeafPosition {
+            index: position,
+            json,
+        })?,
+    )?;
+    klave::ledger::get_table(MERKLE_ROOT_TABLE).set(
+        &root_key(table, root.leaf_count),
+        &serde_json::to_vec(&root)?,
+    )?;
+    klave::ledger::get_table(MERKLE_TABLE).set(table, &serde_json::to_vec(&root.leaf_count)?)
+}
+
+/// Proves the value `key` held at `time`, `None` for its current value, against the root of
+/// the tree of `table` at that time.
+pub fn build_proof(
+    table: &str,
+    key: &str,
+    time: Option<u64>,
+) -> Result<InclusionProof, Box<dyn std::error::Error>> {
+    let Some(root) = load_root_at(table, time)? else {
+        return Err(format!("table {table} has no root at that time").into());
+    };
+    let Some(version) = history::load_at_time(table, key, root.trusted_time)? else {
+        return Err(format!("the key '{key}' was not found in table {table}").into());
+    };
+    let Some(value) = version.value else {
+        return Err(format!("the key '{key}' was deleted from table {table}").into());
+    };
+    let Some(position) = load_position(table, key, version.version)? else {
+        return Err(format!("the key '{key}' is not committed in table {table}").into());
+    };
+    let leaf_index = position.index;
+    if leaf_index >= root.leaf_count {
+        return Err(format!("the key '{key}' is not committed in table {table}").into());
+    }
+    let store = LedgerNodes { table };
+    let proof = proof_hashes(&store, leaf_index, root.leaf_count)?;
+    let data = record_bytes(table, &value, position.json)?;
+    Ok(InclusionProof {
+        table: table.to_string(),
+        key: key.to_string(),
+        version: version.version,
+        leaf: hex::encode(leaf_hash(key, version.version, &data)),
+        data: hex::encode(data),
+        leaf_index,
+        leaf_count: root.leaf_count,
+        proof: proof.iter().map(hex::encode).collect(),
+        root: root.root,
+        updated_at: root.trusted_time,
+    })
+}
+
+/// Checks an [`InclusionProof`] the same way an off-enclave verifier would.
+pub fn verify_proof(proof: &InclusionProof) -> Result<bool, Box<dyn std::error::Error>> {
+    let leaf = leaf_hash(&proof.key, proof.version, &hex::decode(&proof.data)?);
+    if hex::encode(leaf) != proof.leaf {
+        return Ok(false);
+    }
+    let proof_hashes = proof
+        .proof
+        .iter()
+        .map(|hash| decode_hash(hash))
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(MerkleProof::<Sha256>::new(proof_hashes).verify(
+        decode_hash(&proof.root)?,
+        &[proof.leaf_index],
+        &[leaf],
+        proof.leaf_count,
+    ))
+}
+
+pub fn get_root(cmd: String) {
+    let Ok(input) = serde_json::from_str::<GetRootInput>(&cmd) else {
+        klave::notifier::send_string(&format!("ERROR: failed to parse '{cmd}' as GetRootInput"));
+        return;
+    };
+    if let Err(e) = check_table_name(&input.table) {
+        klave::notifier::send_string(&format!("ERROR: {e}"));
+        return;
+    }
+    if let Err(e) = acl::authorize(&input.table, None, Permission::Read) {
+        klave::notifier::send_string(&format!("ERROR: {e}"));
+        return;
+    }
+    match load_root_at(&input.table, input.time) {
+        Ok(root) => {
+            let _ = klave::notifier::send_json(&json!({
+                "table": input.table,
+                "root": root.as_ref().map(|r| &r.root),
+                "leaf_count": root.as_ref().map_or(0, |r| r.leaf_count),
+                "updated_at": root.as_ref().map_or(0, |r| r.trusted_time),
+            }));
+        }
+        Err(e) => {
+            klave::notifier::send_string(&format!("ERROR: failed to load root: '{e}'"));
+        }
+    }
+}
+
+pub fn get_proof(cmd: String) {
+    let Ok(input) = serde_json::from_str::<GetProofInput>(&cmd) else {
+        klave::notifier::send_string(&format!("ERROR: failed to parse '{cmd}' as GetProofInput"));
+        return;
+    };
+    if let Err(e) = check_table_name(&input.table) {
+        klave::notifier::send_string(&format!("ERROR: {e}"));
+        return;
+    }
+    if let Err(e) = acl::authorize(&input.table, Some(&input.key), Permission::Read) {
+        klave::notifier::send_string(&format!("ERROR: {e}"));
+        return;
+    }
+    match build_proof(&input.table, &input.key, input.time) {
+        Ok(proof) => {
+            let _ = klave::notifier::send_json(&proof);
+        }
+        Err(e) => {
+            klave::notifier::send_string(&format!("ERROR: failed to build proof: '{e}'"));
+        }
+    }
+}
+
+pub fn verify_proof_query(cmd: String) {
+    let Ok(proof) = serde_json::from_str::<InclusionProof>(&cmd) else {
+        klave::notifier::send_string(&format!("ERROR: failed to parse '{cmd}' as InclusionProof"));
+        return;
+    };
+    match verify_proof(&proof) {
+        Ok(valid) => {
+            let _ = klave::notifier::send_json(&json!({ "valid": valid }));
+        }
+        Err(e) => {
+            klave::notifier::send_string(&format!("ERROR: failed to verify proof: '{e}'"));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    impl NodeStore for HashMap<(u32, usize), [u8; 32]> {
+        fn load(&self, level: u32, index: usize) -> Result<[u8; 32], Box<dyn std::error::Error>> {
+            self.get(&(level, index))
+                .copied()
+                .ok_or_else(|| "missing node".into())
+        }
+
+        fn save(
+            &mut self,
+            level: u32,
+            index: usize,
+            hash: &[u8; 32],
+        ) -> Result<(), Box<dyn std::error::Error>> {
+            self.insert((level, index), *hash);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_incremental_tree_matches_rs_merkle() {
+        let leaves: Vec<[u8; 32]> = (0..13u64)
+            .map(|i| leaf_hash(&format!("key{i}"), 1, format!("value{i}").as_bytes()))
+            .collect();
+        let mut store = HashMap::new();
+        for (count, leaf) in leaves.iter().enumerate() {
+            append_leaf(&mut store, count, leaf).unwrap();
+        }
+        // Every past state of the tree can be proven against
+        for count in 1..=leaves.len() {
+            let tree = rs_merkle::MerkleTree::<Sha256>::from_leaves(&leaves[..count]);
+            assert_eq!(root_hash(&store, count).unwrap(), tree.root().unwrap());
+            for index in 0..count {
+                assert_eq!(
+                    proof_hashes(&store, index, count).unwrap(),
+                    tree.proof(&[index]).proof_hashes()
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_proof_roundtrip() {
+        let values: Vec<(String, Vec<u8>)> = (0..5)
+            .map(|i| {
+                (
+                    format!("key{i}"),
+                    serde_json::to_vec(&json!({ "n": i })).unwrap(),
+                )
+            })
+            .collect();
+        let mut store = HashMap::new();
+        for (count, (key, data)) in values.iter().enumerate() {
+            append_leaf(&mut store, count, &leaf_hash(key, 1, data)).unwrap();
+        }
+        append_leaf(&mut store, values.len(), &deleted_leaf_hash("key1", 2)).unwrap();
+        let leaf_count = values.len() + 1;
+
+        let (key, data) = &values[3];
+        let mut proof = InclusionProof {
+            table: "my_table".to_string(),
+            key: key.clone(),
+            version: 1,
+            data: hex::encode(data),
+            leaf: hex::encode(leaf_hash(key, 1, data)),
+            leaf_index: 3,
+            leaf_count,
+            proof: proof_hashes(&store, 3, leaf_count)
+                .unwrap()
+                .iter()
+                .map(hex::encode)
+                .collect(),
+            root: hex::encode(root_hash(&store, leaf_count).unwrap()),
+            updated_at: 0,
+        };
+        assert!(verify_proof(&proof).unwrap());
+
+        proof.version = 2;
+        assert!(!verify_proof(&proof).unwrap());
+        proof.version = 1;
+        proof.data = hex::encode(r#"{"n":3}"#);
+        assert!(verify_proof(&proof).unwrap());
+        // The same value laid out differently is a different record
+        proof.data = hex::encode(r#"{ "n": 3 }"#);
+        assert!(!verify_proof(&proof).unwrap());
+    }
+
+    #[test]
+    fn test_leaf_domain_separation() {
+        assert_ne!(leaf_hash("a", 1, b"b"), deleted_leaf_hash("a", 1));
+        assert_ne!(leaf_hash("ab", 1, b"c"), leaf_hash("a", 1, b"bc"));
+    }
+}
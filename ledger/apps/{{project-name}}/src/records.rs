@@ -0,0 +1,424 @@
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::{
+    acl::{self, Permission},
+    crypto,
+    encoding::{self, Encoding},
+    history, index, merkle, schema,
+};
+
+pub(crate) const DEFAULT_TABLE: &str = "my_table";
+
+pub(crate) fn default_table() -> String {
+    DEFAULT_TABLE.to_string()
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct LoadInput {
+    #[serde(default = "default_table")]
+    pub table: String,
+    pub key: String,
+    /// Returns the stored bytes with this encoding instead of the record.
+    #[serde(default)]
+    pub encoding: Option<Encoding>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct InsertInput {
+    #[serde(default = "default_table")]
+    pub table: String,
+    pub key: String,
+    pub value: Value,
+    #[serde(default)]
+    pub encoding: Encoding,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CompareAndSwapInput {
+    #[serde(default = "default_table")]
+    pub table: String,
+    pub key: String,
+    /// Hash returned by `load-from-ledger` for the value the caller expects to replace,
+    /// `None` meaning the key must not exist yet.
+    pub expected_hash: Option<String>,
+    /// Version the caller expects to replace, 0 meaning the key must not exist yet.
+    /// Takes precedence over `expected_hash` when provided.
+    pub expected_version: Option<u64>,
+    pub value: Value,
+    #[serde(default)]
+    pub encoding: Encoding,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum WriteOperation {
+    Insert {
+        #[serde(default = "default_table")]
+        table: String,
+        key: String,
+        value: Value,
+        #[serde(default)]
+        encoding: Encoding,
+    },
+    Delete {
+        #[serde(default = "default_table")]
+        table: String,
+        key: String,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct BatchWriteInput {
+    pub operations: Vec<WriteOperation>,
+}
+
+/// Tables starting with `_` are reserved for the template's own bookkeeping
+/// (schemas, indexes, ...) and cannot be addressed by callers.
+pub fn check_table_name(table: &str) -> Result<(), String> {
+    if table.is_empty() {
+        return Err("table name cannot be empty".to_string());
+    }
+    if table.starts_with('_') {
+        return Err(format!("table name '{table}' is reserved"));
+    }
+    if table.contains('/') {
+        return Err(format!("table name '{table}' cannot contain '/'"));
+    }
+    Ok(())
+}
+
+/// Reads the bytes stored under `key`, decrypting them if encryption is enabled on the table.
+pub fn get_raw_record(
+    table: &str,
+    key: &str,
+) -> Result<Option<Vec<u8>>, Box<dyn std::error::Error>> {
+    let mut res = klave::ledger::get_table(table).get(key)?;
+    if res.is_empty() {
+        return Ok(None);
+    }
+    if let Some(encryption) = crypto::load_table_encryption(table)? {
+        res = crypto::decrypt_entry(
+            &encryption,
+            table,
+            &crypto::entry_location(table, key),
+            &res,
+        )?;
+    }
+    Ok(Some(res))
+}
+
+/// Reads a record from `table`.
+///
+/// Tables with a registered schema hold JSON records and are returned as such;
+/// tables without a schema hold raw bytes, see [`encoding::bytes_to_value`].
+pub fn get_record(table: &str, key: &str) -> Result<Option<Value>, Box<dyn std::error::Error>> {
+    let Some(res) = get_raw_record(table, key)? else {
+        return Ok(None);
+    };
+    if schema::load_schema(table)?.is_some() {
+        return Ok(Some(serde_json::from_slice::<Value>(&res)?));
+    }
+    Ok(Some(encoding::bytes_to_value(res)))
+}
+
+/// Bytes `value` is stored as, before encryption: its JSON serialization in a table with a
+/// schema (`json`), its raw bytes otherwise, see [`encoding::value_to_bytes`].
+pub fn record_bytes(
+    table: &str,
+    value: &Value,
+    json: bool,
+) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    if json {
+        return Ok(serde_json::to_vec(value)?);
+    }
+    encoding::value_to_bytes(value)?.ok_or_else(|| {
+        format!("table '{table}' has no schema, only string or encoded values can be stored").into()
+    })
+}
+
+/// Validates `value` against the schema of `table`, if any, and writes it under `key`.
+pub fn put_record(table: &str, key: &str, value: &Value) -> Result<(), Box<dyn std::error::Error>> {
+    let table_schema = schema::load_schema(table)?;
+    if let Some(table_schema) = &table_schema {
+        schema::validate(table_schema, value)?;
+    }
+    let json = table_schema.is_some();
+    let data = record_bytes(table, value, json)?;
+    let bytes = match crypto::load_table_encryption(table)? {
+        Some(encryption) => crypto::encrypt_entry(
+            &encryption,
+            table,
+            &crypto::entry_location(table, key),
+            &data,
+        )?,
+        None => data.clone(),
+    };
+    let old = get_record(table, key)?;
+    klave::ledger::get_table(table).set(key, &bytes)?;
+    index::on_write(table, key, old.as_ref(), Some(value))?;
+    let version = history::on_write(table, key, Some(value))?;
+    merkle::on_write(table, key, version, Some(&data), json)?;
+    Ok(())
+}
+
+/// Removes `key` from `table` and returns the record it held, if any.
+pub fn delete_record(table: &str, key: &str) -> Result<Option<Value>, Box<dyn std::error::Error>> {
+    let Some(old) = get_record(table, key)? else {
+        return Ok(None);
+    };
+    klave::ledger::get_table(table).remove(key)?;
+    index::on_write(table, key, Some(&old), None)?;
+    let version = history::on_write(table, key, None)?;
+    merkle::on_write(table, key, version, None, false)?;
+    Ok(Some(old))
+}
+
+/// SHA2-256 of the JSON serialization of a record, hex encoded.
+///
+/// The hash is computed over the record rather than over the stored bytes so that it does not
+/// depend on how the value is laid out in the ledger.
+pub fn record_hash(value: &Value) -> Result<String, Box<dyn std::error::Error>> {
+    let digest = klave::crypto::sha::digest("SHA2-256", &serde_json::to_vec(value)?)?;
+    Ok(hex::encode(digest))
+}
+
+fn apply_operation(operation: &WriteOperation) -> Result<(), Box<dyn std::error::Error>> {
+    match operation {
+        WriteOperation::Insert {
+            table,
+            key,
+            value,
+            encoding,
+        } => {
+            check_table_name(table)?;
+            acl::authorize(table, Some(key), Permission::Write)?;
+            put_record(table, key, &encoding::decode_value(value, *encoding)?)
+        }
+        WriteOperation::Delete { table, key } => {
+            check_table_name(table)?;
+            acl::authorize(table, Some(key), Permission::Write)?;
+            match delete_record(table, key)? {
+                Some(_) => Ok(()),
+                None => Err(format!("the key '{key}' was not found in table {table}").into()),
+            }
+        }
+    }
+}
+
+pub fn load_from_ledger(cmd: String) {
+    let Ok(input) = serde_json::from_str::<LoadInput>(&cmd) else {
+        klave::notifier::send_string(&format!("failed to parse '{cmd}' as json"));
+        return;
+    };
+    if let Err(e) = check_table_name(&input.table) {
+        klave::notifier::send_string(&e);
+        return;
+    }
+    if let Err(e) = acl::authorize(&input.table, Some(&input.key), Permission::Read) {
+        klave::notifier::send_string(&e.to_string());
+        return;
+    }
+    let msg = match get_record(&input.table, &input.key) {
+        Ok(Some(value)) => match (
+            record_hash(&value),
+            history::current_version(&input.table, &input.key),
+        ) {
+            (Ok(hash), Ok(version)) => match input.encoding {
+                Some(encoding) => match get_raw_record(&input.table, &input.key)
+                    .and_then(|res| encoding.encode(&res.unwrap_or_default()))
+                {
+                    Ok(encoded) => json!({
+                        "value": encoded,
+                        "encoding": encoding,
+                        "hash": hash,
+                        "version": version
+                    })
+                    .to_string(),
+                    Err(e) => format!("failed to encode record: '{e}'"),
+                },
+                None => json!({ "value": value, "hash": hash, "version": version }).to_string(),
+            },
+            (Err(e), _) => format!("failed to hash record: '{e}'"),
+            (_, Err(e)) => format!("failed to read version: '{e}'"),
+        },
+        Ok(None) => format!(
+            "the key '{}' was not found in table {}",
+            input.key, input.table
+        ),
+        Err(e) => format!("failed to read from ledger: '{e}'"),
+    };
+    klave::notifier::send_string(&msg);
+}
+
+pub fn insert_in_ledger(cmd: String) {
+    let Ok(input) = serde_json::from_str::<InsertInput>(&cmd) else {
+        klave::notifier::send_string(&format!("failed to parse '{cmd}' as json"));
+        klave::router::cancel_transaction();
+        return;
+    };
+    if let Err(e) = check_table_name(&input.table) {
+        klave::notifier::send_string(&e);
+        klave::router::cancel_transaction();
+        return;
+    }
+    if let Err(e) = acl::authorize(&input.table, Some(&input.key), Permission::Write) {
+        klave::notifier::send_string(&e.to_string());
+        klave::router::cancel_transaction();
+        return;
+    }
+    let value = match encoding::decode_value(&input.value, input.encoding) {
+        Ok(value) => value,
+        Err(e) => {
+            klave::notifier::send_string(&format!("failed to decode value: '{e}'"));
+            klave::router::cancel_transaction();
+            return;
+        }
+    };
+    if let Err(e) = put_record(&input.table, &input.key, &value) {
+        klave::notifier::send_string(&format!("failed to write to ledger: '{e}'"));
+        klave::router::cancel_transaction();
+        return;
+    }
+
+    let result_as_json = json!({
+        "inserted": true,
+        "table": input.table,
+        "key": input.key,
+        "value": input.value
+    });
+    klave::notifier::send_string(&result_as_json.to_string());
+}
+
+pub fn delete_from_ledger(cmd: String) {
+    let Ok(input) = serde_json::from_str::<LoadInput>(&cmd) else {
+        klave::notifier::send_string(&format!("failed to parse '{cmd}' as json"));
+        klave::router::cancel_transaction();
+        return;
+    };
+    if let Err(e) = check_table_name(&input.table) {
+        klave::notifier::send_string(&e);
+        klave::router::cancel_transaction();
+        return;
+    }
+    if let Err(e) = acl::authorize(&input.table, Some(&input.key), Permission::Write) {
+        klave::notifier::send_string(&e.to_string());
+        klave::router::cancel_transaction();
+        return;
+    }
+    match delete_record(&input.table, &input.key) {
+        Ok(Some(_)) => {
+            let result_as_json = json!({
+                "deleted": true,
+                "table": input.table,
+                "key": input.key
+            });
+            klave::notifier::send_string(&result_as_json.to_string());
+        }
+        Ok(None) => {
+            klave::notifier::send_string(&format!(
+                "the key '{}' was not found in table {}",
+                input.key, input.table
+            ));
+            klave::router::cancel_transaction();
+        }
+        Err(e) => {
+            klave::notifier::send_string(&format!("failed to delete from ledger: '{e}'"));
+            klave::router::cancel_transaction();
+        }
+    }
+}
+
+pub fn compare_and_swap(cmd: String) {
+    let Ok(input) = serde_json::from_str::<CompareAndSwapInput>(&cmd) else {
+        klave::notifier::send_string(&format!("failed to parse '{cmd}' as json"));
+        klave::router::cancel_transaction();
+        return;
+    };
+    if let Err(e) = check_table_name(&input.table) {
+        klave::notifier::send_string(&e);
+        klave::router::cancel_transaction();
+        return;
+    }
+    if let Err(e) = acl::authorize(&input.table, Some(&input.key), Permission::Write) {
+        klave::notifier::send_string(&e.to_string());
+        klave::router::cancel_transaction();
+        return;
+    }
+    let matches = || -> Result<(bool, Value), Box<dyn std::error::Error>> {
+        if let Some(expected_version) = input.expected_version {
+            let current_version = history::current_version(&input.table, &input.key)?;
+            // A deleted key keeps its version chain but must be treated as missing
+            let exists = get_record(&input.table, &input.key)?.is_some();
+            let matched = if exists {
+                current_version == expected_version
+            } else {
+                expected_version == 0
+            };
+            return Ok((matched, json!({ "current_version": current_version })));
+        }
+        let current_hash = match get_record(&input.table, &input.key)? {
+            Some(current) => Some(record_hash(&current)?),
+            None => None,
+        };
+        Ok((
+            current_hash == input.expected_hash,
+            json!({ "current_hash": current_hash }),
+        ))
+    };
+    let (matched, current) = match matches() {
+        Ok(res) => res,
+        Err(e) => {
+            klave::notifier::send_string(&format!("failed to read from ledger: '{e}'"));
+            klave::router::cancel_transaction();
+            return;
+        }
+    };
+    let value = match encoding::decode_value(&input.value, input.encoding) {
+        Ok(value) => value,
+        Err(e) => {
+            klave::notifier::send_string(&format!("failed to decode value: '{e}'"));
+            klave::router::cancel_transaction();
+            return;
+        }
+    };
+    if !matched {
+        let _ = klave::notifier::send_json(&json!({
+            "swapped": false,
+            "table": input.table,
+            "key": input.key,
+            "current": current
+        }));
+        klave::router::cancel_transaction();
+        return;
+    }
+    if let Err(e) = put_record(&input.table, &input.key, &value) {
+        klave::notifier::send_string(&format!("failed to write to ledger: '{e}'"));
+        klave::router::cancel_transaction();
+        return;
+    }
+    let _ = klave::notifier::send_json(&json!({
+        "swapped": true,
+        "table": input.table,
+        "key": input.key
+    }));
+}
+
+pub fn batch_write(cmd: String) {
+    let Ok(input) = serde_json::from_str::<BatchWriteInput>(&cmd) else {
+        klave::notifier::send_string(&format!("failed to parse '{cmd}' as json"));
+        klave::router::cancel_transaction();
+        return;
+    };
+    // Any failure cancels the whole transaction so that either all operations land or none do
+    for (i, operation) in input.operations.iter().enumerate() {
+        if let Err(e) = apply_operation(operation) {
+            klave::notifier::send_string(&format!("batch operation {i} failed: '{e}'"));
+            klave::router::cancel_transaction();
+            return;
+        }
+    }
+    let _ = klave::notifier::send_json(&json!({
+        "applied": input.operations.len()
+    }));
+}
@@ -0,0 +1,344 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{
+    acl::{self, Permission},
+    index, records,
+};
+
+pub(crate) const SCHEMA_TABLE: &str = "_schemas";
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RegisterSchemaInput {
+    pub table: String,
+    pub schema: Value,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct TableInput {
+    pub table: String,
+}
+
+/// Loads the JSON Schema registered for `table`, if any.
+pub fn load_schema(table: &str) -> Result<Option<Value>, Box<dyn std::error::Error>> {
+    let res = klave::ledger::get_table(SCHEMA_TABLE).get(table)?;
+    if res.is_empty() {
+        return Ok(None);
+    }
+    match serde_json::from_slice::<Value>(&res) {
+        Ok(schema) => Ok(Some(schema)),
+        Err(e) => {
+            klave::notifier::send_string(&format!(
+                "ERROR: failed to deserialize schema for table '{table}': {e}"
+            ));
+            Err(e.into())
+        }
+    }
+}
+
+pub fn save_schema(table: &str, schema: &Value) -> Result<(), Box<dyn std::error::Error>> {
+    klave::ledger::get_table(SCHEMA_TABLE).set(table, &serde_json::to_vec(schema)?)
+}
+
+/// Validates `value` against a JSON Schema.
+///
+/// Only the subset of the specification needed for record validation is supported:
+/// `type`, `enum`, `const`, `properties`, `required`, `additionalProperties`, `items`,
+/// `minimum`, `maximum`, `minLength`, `maxLength`, `minItems` and `maxItems`.
+/// Unknown keywords are ignored.
+pub fn validate(schema: &Value, value: &Value) -> Result<(), String> {
+    validate_at(schema, value, "$")
+}
+
+/// Checks that a schema only uses keywords with well-formed arguments, so that
+/// registration fails early instead of every later insert.
+pub fn check_schema(schema: &Value) -> Result<(), String> {
+    let Some(obj) = schema.as_object() else {
+        return Err("schema must be a json object".to_string());
+    };
+    if let Some(t) = obj.get("type") {
+        let types = match t {
+            Value::String(s) => vec![s.as_str()],
+            Value::Array(arr) => arr.iter().filter_map(|v| v.as_str()).collect(),
+            _ => return Err("'type' must be a string or an array of strings".to_string()),
+        };
+        for t in types {
+            if !matches!(
+                t,
+                "null" | "boolean" | "object" | "array" | "number" | "integer" | "string"
+            ) {
+                return Err(format!("unknown type '{t}'"));
+            }
+        }
+    }
+    if let Some(properties) = obj.get("properties") {
+        let Some(properties) = properties.as_object() else {
+            return Err("'properties' must be an object".to_string());
+        };
+        for sub_schema in properties.values() {
+            check_schema(sub_schema)?;
+        }
+    }
+    if let Some(required) = obj.get("required") {
+        if !required
+            .as_array()
+            .is_some_and(|arr| arr.iter().all(|v| v.is_string()))
+        {
+            return Err("'required' must be an array of strings".to_string());
+        }
+    }
+    if let Some(items) = obj.get("items") {
+        check_schema(items)?;
+    }
+    if let Some(Value::Object(_)) = obj.get("additionalProperties") {
+        check_schema(&obj["additionalProperties"])?;
+    }
+    Ok(())
+}
+
+/// Checks that every record already stored in `table` is a JSON value matching `schema`,
+/// so that registering a schema cannot make existing records unreadable.
+pub fn check_existing_records(table: &str, schema: &Value) -> Result<(), String> {
+    let keys = index::load_keys(table).map_err(|e| format!("failed to list keys: {e}"))?;
+    for key in keys {
+        let res = match records::get_raw_record(table, &key) {
+            Ok(Some(res)) => res,
+            Ok(None) => continue,
+            Err(e) => return Err(format!("failed to read record '{key}': {e}")),
+        };
+        let Ok(record) = serde_json::from_slice::<Value>(&res) else {
+            return Err(format!("record '{key}' is not a JSON value"));
+        };
+        validate(schema, &record).map_err(|e| format!("record '{key}': {e}"))?;
+    }
+    Ok(())
+}
+
+fn type_matches(expected: &str, value: &Value) -> bool {
+    match expected {
+        "null" => value.is_null(),
+        "boolean" => value.is_boolean(),
+        "object" => value.is_object(),
+        "array" => value.is_array(),
+        "number" => value.is_number(),
+        "integer" => value.is_i64() || value.is_u64(),
+        "string" => value.is_string(),
+        _ => false,
+    }
+}
+
+fn validate_at(schema: &Value, value: &Value, path: &str) -> Result<(), String> {
+    let Some(schema) = schema.as_object() else {
+        return Ok(());
+    };
+
+    if let Some(t) = schema.get("type") {
+        let matched = match t {
+            Value::String(s) => type_matches(s, value),
+            Value::Array(arr) => arr
+                .iter()
+                .filter_map(|v| v.as_str())
+                .any(|s| type_matches(s, value)),
+            _ => true,
+        };
+        if !matched {
+            return Err(format!("{path}: expected type {t}, found {value}"));
+        }
+    }
+
+    if let Some(Value::Array(allowed)) = schema.get("enum") {
+        if !allowed.contains(value) {
+            return Err(format!(
+                "{path}: {value} is not one of {}",
+                Value::from(allowed.clone())
+            ));
+        }
+    }
+
+    if let Some(expected) = schema.get("const") {
+        if expected != value {
+            return Err(format!("{path}: expected {expected}, found {value}"));
+        }
+    }
+
+    if let Some(n) = value.as_f64() {
+        if let Some(min) = schema.get("minimum").and_then(|v| v.as_f64()) {
+            if n < min {
+                return Err(format!("{path}: {n} is lower than minimum {min}"));
+            }
+        }
+        if let Some(max) = schema.get("maximum").and_then(|v| v.as_f64()) {
+            if n > max {
+                return Err(format!("{path}: {n} is greater than maximum {max}"));
+            }
+        }
+    }
+
+    if let Some(s) = value.as_str() {
+        let len = s.chars().count() as u64;
+        if let Some(min) = schema.get("minLength").and_then(|v| v.as_u64()) {
+            if len < min {
+                return Err(format!("{path}: string shorter than {min} characters"));
+            }
+        }
+        if let Some(max) = schema.get("maxLength").and_then(|v| v.as_u64()) {
+            if len > max {
+                return Err(format!("{path}: string longer than {max} characters"));
+            }
+        }
+    }
+
+    if let Some(arr) = value.as_array() {
+        let len = arr.len() as u64;
+        if let Some(min) = schema.get("minItems").and_then(|v| v.as_u64()) {
+            if len < min {
+                return Err(format!("{path}: array has fewer than {min} items"));
+            }
+        }
+        if let Some(max) = schema.get("maxItems").and_then(|v| v.as_u64()) {
+            if len > max {
+                return Err(format!("{path}: array has more than {max} items"));
+            }
+        }
+        if let Some(items) = schema.get("items") {
+            for (i, item) in arr.iter().enumerate() {
+                validate_at(items, item, &format!("{path}[{i}]"))?;
+            }
+        }
+    }
+
+    if let Some(obj) = value.as_object() {
+        if let Some(Value::Array(required)) = schema.get("required") {
+            for field in required.iter().filter_map(|v| v.as_str()) {
+                if !obj.contains_key(field) {
+                    return Err(format!("{path}: missing required property '{field}'"));
+                }
+            }
+        }
+        let properties = schema.get("properties").and_then(|v| v.as_object());
+        for (field, field_value) in obj {
+            let field_path = format!("{path}.{field}");
+            match properties.and_then(|p| p.get(field)) {
+                Some(sub_schema) => validate_at(sub_schema, field_value, &field_path)?,
+                None => match schema.get("additionalProperties") {
+                    Some(Value::Bool(false)) => {
+                        return Err(format!("{path}: unexpected property '{field}'"));
+                    }
+                    Some(sub_schema @ Value::Object(_)) => {
+                        validate_at(sub_schema, field_value, &field_path)?
+                    }
+                    _ => (),
+                },
+            }
+        }
+    }
+
+    Ok(())
+}
+
+pub fn register_schema(cmd: String) {
+    let Ok(input) = serde_json::from_str::<RegisterSchemaInput>(&cmd) else {
+        klave::notifier::send_string(&format!(
+            "ERROR: failed to parse '{cmd}' as RegisterSchemaInput"
+        ));
+        klave::router::cancel_transaction();
+        return;
+    };
+    if let Err(e) = crate::records::check_table_name(&input.table) {
+        klave::notifier::send_string(&format!("ERROR: {e}"));
+        klave::router::cancel_transaction();
+        return;
+    }
+    if let Err(e) = acl::authorize(&input.table, None, Permission::Admin) {
+        klave::notifier::send_string(&format!("ERROR: {e}"));
+        klave::router::cancel_transaction();
+        return;
+    }
+    if let Err(e) = check_schema(&input.schema) {
+        klave::notifier::send_string(&format!("ERROR: invalid schema: {e}"));
+        klave::router::cancel_transaction();
+        return;
+    }
+    if let Err(e) = check_existing_records(&input.table, &input.schema) {
+        klave::notifier::send_string(&format!(
+            "ERROR: table '{}' holds records not matching the schema: {e}",
+            input.table
+        ));
+        klave::router::cancel_transaction();
+        return;
+    }
+    if let Err(e) = save_schema(&input.table, &input.schema) {
+        klave::notifier::send_string(&format!("ERROR: failed to save schema: '{e}'"));
+        klave::router::cancel_transaction();
+        return;
+    }
+    let _ = klave::notifier::send_json(&serde_json::json!({
+        "registered": true,
+        "table": input.table,
+    }));
+}
+
+pub fn load_schema_query(cmd: String) {
+    let Ok(input) = serde_json::from_str::<TableInput>(&cmd) else {
+        klave::notifier::send_string(&format!("ERROR: failed to parse '{cmd}' as TableInput"));
+        return;
+    };
+    if let Err(e) = crate::records::check_table_name(&input.table) {
+        klave::notifier::send_string(&format!("ERROR: {e}"));
+        return;
+    }
+    if let Err(e) = acl::authorize(&input.table, None, Permission::Read) {
+        klave::notifier::send_string(&format!("ERROR: {e}"));
+        return;
+    }
+    match load_schema(&input.table) {
+        Ok(Some(schema)) => {
+            let _ = klave::notifier::send_json(&schema);
+        }
+        Ok(None) => {
+            klave::notifier::send_string(&format!(
+                "ERROR: no schema registered for table '{}'",
+                input.table
+            ));
+        }
+        Err(e) => {
+            klave::notifier::send_string(&format!("ERROR: failed to load schema: '{e}'"));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn test_validate_object() {
+        let schema = json!({
+            "type": "object",
+            "required": ["name", "age"],
+            "additionalProperties": false,
+            "properties": {
+                "name": { "type": "string", "minLength": 1 },
+                "age": { "type": "integer", "minimum": 0 },
+                "tags": { "type": "array", "items": { "type": "string" } }
+            }
+        });
+        assert!(check_schema(&schema).is_ok());
+        assert!(validate(&schema, &json!({ "name": "alice", "age": 30 })).is_ok());
+        assert!(validate(&schema, &json!({ "name": "alice" })).is_err());
+        assert!(validate(&schema, &json!({ "name": "alice", "age": -1 })).is_err());
+        assert!(validate(&schema, &json!({ "name": "alice", "age": 1.5 })).is_err());
+        assert!(validate(&schema, &json!({ "name": "a", "age": 1, "x": 0 })).is_err());
+        assert!(validate(&schema, &json!({ "name": "a", "age": 1, "tags": [1] })).is_err());
+    }
+
+    #[test]
+    fn test_check_schema() {
+        assert!(check_schema(&json!("string")).is_err());
+        assert!(check_schema(&json!({ "type": "text" })).is_err());
+        assert!(check_schema(&json!({ "required": "name" })).is_err());
+        assert!(check_schema(&json!({ "type": ["string", "null"] })).is_ok());
+    }
+}
@@ -1,16 +1,12 @@
-use serde_json::Value;
+use serde_json::json;
 
-use crate::database::{self, EncryptedQueryWithEncryptedUser};
+use crate::database;
+use crate::dsl::QuerySpec;
 
-pub fn read_encrypted_data_per_user(cmd: String) {
-    let input: database::ReadEncryptedTablePerUserInput = match serde_json::from_str(&cmd) {
-        Ok(input) => input,
-        Err(err) => {
-            klave::notifier::send_string(&format!("Invalid input: {err}"));
-            return;
-        }
-    };
-    let mut client: database::Client = match database::Client::load(input.database_id.clone()) {
+// Loads the client, connects to the DB and runs the query described by the spec.
+// Results are decrypted, only the owner of the client can run queries.
+pub fn run_query_spec(spec: QuerySpec) {
+    let client: database::Client = match database::Client::load_owned(spec.database_id.clone()) {
         Ok(c) => c,
         Err(err) => {
             klave::notifier::send_string(&format!("Failed to load client: {err}"));
@@ -27,104 +23,60 @@ pub fn read_encrypted_data_per_user(cmd: String) {
         }
     };
 
-    // Build query where first name and last name have been replaced with corresponding encrypted values
-    let query: EncryptedQueryWithEncryptedUser = match client.build_encrypted_query_per_user(&input)
-    {
-        Ok(res) => res,
-        Err(err) => {
-            klave::notifier::send_string(&format!("Failed to create query: {err}"));
-            return;
+    // Run query
+    match client.run_query(&spec) {
+        Ok(res) => {
+            let _ = klave::notifier::send_json(&res);
         }
-    };
-
-    let mut result = match client.query::<Vec<Vec<Value>>>(&query.query) {
-        Ok(res) => res,
         Err(err) => {
-            klave::notifier::send_string(&format!("Failed to query the DB: {err}"));
-            return;
+            klave::notifier::send_string(&format!("Failed to run the query: {err}"));
         }
-    };
-
-    let first_name_cleartext = input.first_name.trim().to_string();
-    let last_name_cleartext = input.last_name.trim().to_string();
-
-    for elem in result.resultset.iter_mut() {
-        let first_name_value = match elem.get_mut(0) {
-            Some(res) => res,
-            None => {
-                klave::notifier::send_string("Missing first name");
-                return;
-            }
-        };
-        if let Some(val) = first_name_value.as_str() {
-            if val == query.first_name_encryption {
-                *first_name_value = serde_json::Value::String(first_name_cleartext.clone());
-            }
-        };
-
-        let last_name_value = match elem.get_mut(1) {
-            Some(res) => res,
-            None => {
-                klave::notifier::send_string("Missing last name");
-                return;
-            }
-        };
-        if let Some(val) = last_name_value.as_str() {
-            if val == query.last_name_encryption {
-                *last_name_value = serde_json::Value::String(last_name_cleartext.clone());
-            }
-        };
     }
-
-    let _ = klave::notifier::send_json(&result);
 }
 
-pub fn avg_age_for_male(cmd: String) {
-    let input: database::DatabaseIdInput = match serde_json::from_str(&cmd) {
+pub fn read_encrypted_data_per_user(cmd: String) {
+    let input: database::ReadEncryptedTablePerUserInput = match serde_json::from_str(&cmd) {
         Ok(input) => input,
         Err(err) => {
             klave::notifier::send_string(&format!("Invalid input: {err}"));
             return;
         }
     };
-    let mut client: database::Client = match database::Client::load(input.database_id.clone()) {
-        Ok(c) => c,
-        Err(err) => {
-            klave::notifier::send_string(&format!("Failed to load client: {err}"));
-            return;
-        }
-    };
 
-    // Connect to the DB and establish a handle
-    match client.connect() {
-        Ok(_) => (),
-        Err(err) => {
-            klave::notifier::send_string(&format!("Failed to connect to client: {err}"));
-            return;
-        }
-    };
-
-    // Query
-    let query = match client.build_encrypted_query_per_gender(&"Male".to_string()) {
-        Ok(res) => res,
+    // Purchases of the user, encrypted columns are found in the catalog
+    let spec = match serde_json::from_value::<QuerySpec>(json!({
+        "database_id": input.database_id,
+        "table": input.table,
+        "alias": "u",
+        "joins": [
+            { "table": "purchases", "alias": "pu", "left": "pu.user_id", "right": "u.id" },
+            { "table": "products", "alias": "pr", "left": "pr.id", "right": "pu.product_id" }
+        ],
+        "select": [
+            { "column": "u.first_name" },
+            { "column": "u.last_name" },
+            { "column": "pu.purchase_date" },
+            { "column": "pr.product_name" },
+            { "column": "pr.category" },
+            { "column": "pr.brand" },
+            { "column": "pr.description" },
+            { "column": "pr.price" }
+        ],
+        "filters": [
+            { "column": "u.first_name", "op": "eq", "value": input.first_name.trim() },
+            { "column": "u.last_name", "op": "eq", "value": input.last_name.trim() }
+        ]
+    })) {
+        Ok(spec) => spec,
         Err(err) => {
             klave::notifier::send_string(&format!("Failed to build the query: {err}"));
             return;
         }
     };
-
-    // Run query
-    match client.query::<Vec<Vec<Value>>>(&query) {
-        Ok(res) => {
-            let _ = klave::notifier::send_json(&res);
-        }
-        Err(err) => {
-            klave::notifier::send_string(&format!("Failed to run the query: {err}"));
-        }
-    }
+    run_query_spec(spec);
 }
 
-pub fn avg_age_for_female(cmd: String) {
+fn avg_age_for_gender(cmd: String, gender: &str) {
     let input: database::DatabaseIdInput = match serde_json::from_str(&cmd) {
         Ok(input) => input,
         Err(err) => {
@@ -132,39 +84,35 @@ pub fn avg_age_for_female(cmd: String) {
             return;
         }
     };
-    let mut client: database::Client = match database::Client::load(input.database_id.clone()) {
-        Ok(c) => c,
-        Err(err) => {
-            klave::notifier::send_string(&format!("Failed to load client: {err}"));
-            return;
-        }
-    };
 
-    // Connect to the DB and establish a handle
-    match client.connect() {
-        Ok(_) => (),
-        Err(err) => {
-            klave::notifier::send_string(&format!("Failed to connect to client: {err}"));
-            return;
-        }
-    };
-
-    // Query
-    let query = match client.build_encrypted_query_per_gender(&"Female".to_string()) {
-        Ok(res) => res,
+    // Average age of the users of this gender with purchases over 300
+    let spec = match serde_json::from_value::<QuerySpec>(json!({
+        "database_id": input.database_id,
+        "table": "users",
+        "alias": "u",
+        "joins": [
+            { "table": "purchases", "alias": "pu", "left": "pu.user_id", "right": "u.id" },
+            { "table": "products", "alias": "pr", "left": "pr.id", "right": "pu.product_id" }
+        ],
+        "select": [{ "column": "u.age", "aggregate": "avg" }],
+        "filters": [
+            { "column": "pu.total_price", "op": "gt", "value": 300 },
+            { "column": "u.gender", "op": "eq", "value": gender }
+        ]
+    })) {
+        Ok(spec) => spec,
         Err(err) => {
             klave::notifier::send_string(&format!("Failed to build the query: {err}"));
             return;
         }
     };
+    run_query_spec(spec);
+}
 
-    // Run query
-    match client.query::<Vec<Vec<Value>>>(&query) {
-        Ok(res) => {
-            let _ = klave::notifier::send_json(&res);
-        }
-        Err(err) => {
-            klave::notifier::send_string(&format!("Failed to run the query: {err}"));
-        }
-    }
+pub fn avg_age_for_male(cmd: String) {
+    avg_age_for_gender(cmd, "Male");
+}
+
+pub fn avg_age_for_female(cmd: String) {
+    avg_age_for_gender(cmd, "Female");
 }
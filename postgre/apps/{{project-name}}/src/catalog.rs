@@ -0,0 +1,332 @@
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::crypto::{compute_encryption_mac, mac_eq};
+use crate::database;
+use crate::dsl::EncryptedColumn;
+
+pub(crate) const ENCRYPTED_COLUMN_CATALOG_TABLE: &str = "EncryptedColumnCatalogTable";
+// Suffix of the column holding the blind indexes of a `blind_index` column
+pub(crate) const BLIND_INDEX_SUFFIX: &str = "_bidx";
+
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EncryptionMode {
+    // Equal values give equal ciphertexts, the column can be filtered on
+    #[default]
+    Deterministic,
+    // Random iv, equal values give different ciphertexts, the column cannot be filtered on
+    Randomized,
+    // Random iv, plus an HMAC of the value in `<column>_bidx` to filter on equality
+    BlindIndex,
+}
+
+impl EncryptionMode {
+    // Whether equality filters can be run on the column.
+    pub fn is_searchable(&self) -> bool {
+        !matches!(self, EncryptionMode::Randomized)
+    }
+}
+
+// Column holding the blind indexes of a column, e.g. `email` becomes `email_bidx`.
+pub fn blind_index_column(column: &str) -> String {
+    format!("{column}{BLIND_INDEX_SUFFIX}")
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CatalogEntry {
+    pub table: String,
+    pub column: String,
+    #[serde(default = "default_primary_key")]
+    pub primary_key: String, // Used to re-encrypt the column in chunks
+    pub key_version: u32, // Version of the master key the column is encrypted under
+    pub mode: EncryptionMode,
+    pub registered_at: u64, // trusted_time, in nanoseconds
+    // trusted_time when the encryption was recorded by mark_columns_encrypted. The column holds
+    // plaintext until then.
+    #[serde(default)]
+    pub encrypted_at: Option<u64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RegisterEncryptedColumnsInput {
+    pub database_id: String,
+    pub table: String,
+    pub columns: Vec<String>,
+    #[serde(default = "default_primary_key")]
+    pub primary_key: String,
+    #[serde(default)]
+    pub mode: EncryptionMode,
+}
+
+// Receipt returned by execute_table_encryption, to record the encryption in the catalog.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EncryptionReceipt {
+    pub database_id: String,
+    pub table: String,
+    pub columns: Vec<String>,
+    pub key_version: u32, // Version of the master key the columns were encrypted under
+    pub mac: String,
+}
+
+impl EncryptionReceipt {
+    // Data authenticated by the mac.
+    pub fn mac_data(
+        database_id: &str,
+        table: &str,
+        columns: &[String],
+        key_version: u32,
+    ) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        Ok(serde_json::to_vec(&json!({
+            "database_id": database_id,
+            "table": table,
+            "columns": columns,
+            "key_version": key_version,
+        }))?)
+    }
+}
+
+fn default_primary_key() -> String {
+    "id".to_string()
+}
+
+// Columns encrypted through encrypt_columns, per database_id.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Catalog {
+    pub(crate) database_id: String,
+    pub(crate) entries: Vec<CatalogEntry>,
+}
+
+impl Catalog {
+    pub fn new(database_id: &str) -> Self {
+        Self {
+            database_id: database_id.to_string(),
+            entries: Vec::new(),
+        }
+    }
+
+    pub fn load(database_id: &str) -> Result<Catalog, Box<dyn std::error::Error>> {
+        let v = match klave::ledger::get_table(ENCRYPTED_COLUMN_CATALOG_TABLE).get(database_id) {
+            Ok(v) if !v.is_empty() => v,
+            _ => return Ok(Catalog::new(database_id)),
+        };
+        match serde_json::from_slice::<Catalog>(&v) {
+            Ok(catalog) => Ok(catalog),
+            Err(e) => {
+                klave::notifier::send_string(&format!(
+                    "ERROR: failed to parse encrypted column catalog: {e}"
+                ));
+                Err(e.into())
+            }
+        }
+    }
+
+    pub fn save(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let serialized = serde_json::to_string(&self)?;
+        klave::ledger::get_table(ENCRYPTED_COLUMN_CATALOG_TABLE)
+            .set(&self.database_id, serialized.as_bytes())
+    }
+
+    pub fn remove(&self) -> Result<(), Box<dyn std::error::Error>> {
+        klave::ledger::get_table(ENCRYPTED_COLUMN_CATALOG_TABLE).remove(&self.database_id)
+    }
+
+    pub fn get(&self, table: &str, column: &str) -> Option<&CatalogEntry> {
+        self.entries
+            .iter()
+            .find(|e| e.table == table && e.column == column)
+    }
+
+    // Records a column, registering it again with the same mode is a no-op.
+    pub fn register(&mut self, entry: CatalogEntry) -> Result<(), Box<dyn std::error::Error>> {
+        if let Some(existing) = self.get(&entry.table, &entry.column) {
+            if existing.mode != entry.mode {
+                return Err(format!(
+                    "Column {}.{} is already registered with mode {:?}",
+                    entry.table, entry.column, existing.mode
+                )
+                .into());
+            }
+            return Ok(());
+        }
+        self.entries.push(entry);
+        Ok(())
+    }
+
+    // Records that columns were encrypted under the given master key version.
+    pub fn mark_encrypted(
+        &mut self,
+        table: &str,
+        columns: &[String],
+        key_version: u32,
+        encrypted_at: u64,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        for column in columns {
+            let Some(entry) = self
+                .entries
+                .iter_mut()
+                .find(|e| e.table == table && e.column == *column)
+            else {
+                return Err(format!("Column {table}.{column} is not registered").into());
+            };
+            if entry.encrypted_at.is_some() {
+                return Err(format!("Column {table}.{column} is already encrypted").into());
+            }
+            entry.key_version = key_version;
+            entry.encrypted_at = Some(encrypted_at);
+        }
+        Ok(())
+    }
+
+    // First registered column that is not encrypted yet.
+    pub fn first_unencrypted(&self) -> Option<&CatalogEntry> {
+        self.entries.iter().find(|e| e.encrypted_at.is_none())
+    }
+
+    // Marks every column as encrypted under the given master key version.
+    pub fn set_key_version(&mut self, key_version: u32) {
+        for entry in self.entries.iter_mut() {
+            entry.key_version = key_version;
+        }
+    }
+
+    // Encrypted columns of the given tables. Registered columns that are not encrypted yet hold
+    // plaintext and are left out.
+    pub fn encrypted_columns(&self, tables: &[&str]) -> Vec<EncryptedColumn> {
+        self.entries
+            .iter()
+            .filter(|e| tables.contains(&e.table.as_str()) && e.encrypted_at.is_some())
+            .map(|e| EncryptedColumn {
+                table: e.table.clone(),
+                column: e.column.clone(),
+                mode: e.mode,
+            })
+            .collect()
+    }
+}
+
+fn mark(receipt: &EncryptionReceipt) -> Result<Catalog, Box<dyn std::error::Error>> {
+    let client = database::Client::load_owned(receipt.database_id.clone())?;
+    let master_key = client.master_key_of_version(receipt.key_version)?;
+    let data = EncryptionReceipt::mac_data(
+        &receipt.database_id,
+        &receipt.table,
+        &receipt.columns,
+        receipt.key_version,
+    )?;
+    if !mac_eq(
+        &compute_encryption_mac(&master_key.hmac_key, &receipt.database_id, &data)?,
+        &receipt.mac,
+    ) {
+        return Err("Receipt was not returned by execute_table_encryption".into());
+    }
+    let encrypted_at = klave::context::get("trusted_time")?.parse::<u64>()?;
+    let mut catalog = Catalog::load(&receipt.database_id)?;
+    catalog.mark_encrypted(
+        &receipt.table,
+        &receipt.columns,
+        receipt.key_version,
+        encrypted_at,
+    )?;
+    catalog.save()?;
+    Ok(catalog)
+}
+
+pub fn mark_columns_encrypted(cmd: String) {
+    let receipt: EncryptionReceipt = match serde_json::from_str(&cmd) {
+        Ok(input) => input,
+        Err(err) => {
+            klave::notifier::send_string(&format!("Invalid input: {err}"));
+            return;
+        }
+    };
+    match mark(&receipt) {
+        Ok(catalog) => {
+            let _ = klave::notifier::send_json(&catalog);
+        }
+        Err(err) => {
+            klave::notifier::send_string(&format!("Failed to mark columns as encrypted: {err}"));
+            klave::router::cancel_transaction();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(table: &str, column: &str, registered_at: u64) -> CatalogEntry {
+        CatalogEntry {
+            table: table.to_string(),
+            column: column.to_string(),
+            primary_key: "id".to_string(),
+            key_version: 1,
+            mode: EncryptionMode::Deterministic,
+            registered_at,
+            encrypted_at: Some(registered_at),
+        }
+    }
+
+    #[test]
+    fn test_register() {
+        let mut catalog = Catalog::new("db");
+        catalog.register(entry("users", "gender", 1)).unwrap();
+        catalog.register(entry("users", "gender", 2)).unwrap();
+        catalog.register(entry("products", "brand", 3)).unwrap();
+        let mut email = entry("users", "email", 4);
+        email.mode = EncryptionMode::BlindIndex;
+        assert!(catalog.register(email.clone()).is_ok());
+        email.mode = EncryptionMode::Randomized;
+        assert!(catalog.register(email).is_err());
+
+        // Registered but not encrypted yet
+        catalog
+            .register(CatalogEntry {
+                encrypted_at: None,
+                ..entry("users", "notes", 5)
+            })
+            .unwrap();
+
+        assert_eq!(catalog.entries.len(), 4);
+        assert_eq!(catalog.get("users", "gender").unwrap().registered_at, 1);
+        assert_eq!(
+            catalog.encrypted_columns(&["users"]),
+            vec![
+                EncryptedColumn {
+                    table: "users".to_string(),
+                    column: "gender".to_string(),
+                    mode: EncryptionMode::Deterministic
+                },
+                EncryptedColumn {
+                    table: "users".to_string(),
+                    column: "email".to_string(),
+                    mode: EncryptionMode::BlindIndex
+                }
+            ]
+        );
+    }
+
+    #[test]
+    fn test_mark_encrypted() {
+        let mut catalog = Catalog::new("db");
+        catalog
+            .register(CatalogEntry {
+                encrypted_at: None,
+                ..entry("users", "gender", 1)
+            })
+            .unwrap();
+        assert!(catalog.encrypted_columns(&["users"]).is_empty());
+        assert_eq!(catalog.first_unencrypted().unwrap().column, "gender");
+
+        let columns = vec!["gender".to_string()];
+        assert!(catalog
+            .mark_encrypted("users", &["age".to_string()], 2, 10)
+            .is_err());
+        catalog.mark_encrypted("users", &columns, 2, 10).unwrap();
+        assert_eq!(catalog.get("users", "gender").unwrap().key_version, 2);
+        assert_eq!(catalog.encrypted_columns(&["users"]).len(), 1);
+        assert!(catalog.first_unencrypted().is_none());
+        // Encrypting a column twice would make it unreadable
+        assert!(catalog.mark_encrypted("users", &columns, 2, 11).is_err());
+    }
+}
@@ -0,0 +1,237 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+// Connection attempts before giving up, on connect and on a stale handle
+pub(crate) const MAX_CONNECT_ATTEMPTS: usize = 3;
+// Cached handles unused for longer are evicted
+pub(crate) const IDLE_TIMEOUT_NS: u64 = 5 * 60 * 1_000_000_000;
+
+#[derive(Debug, Clone, PartialEq)]
+struct CachedHandle {
+    handle: String,
+    fingerprint: String, // Digest of the connection settings the handle was opened with
+    last_used: u64,      // trusted_time, in nanoseconds
+}
+
+// Open `klave::sql` handles, per database_id.
+#[derive(Debug, Default)]
+pub struct ConnectionCache {
+    handles: HashMap<String, CachedHandle>,
+    // Open cursors per database_id. Their transaction lives on the handle, which is then
+    // neither evicted nor replaced.
+    pinned: HashMap<String, usize>,
+}
+
+thread_local! {
+    // Kept for as long as the app instance lives
+    static CONNECTIONS: RefCell<ConnectionCache> = RefCell::new(ConnectionCache::default());
+}
+
+impl ConnectionCache {
+    // Handle opened for `database_id` with the same settings.
+    pub fn get(&mut self, database_id: &str, fingerprint: &str, now: u64) -> Option<String> {
+        let cached = self.handles.get_mut(database_id)?;
+        if cached.fingerprint != fingerprint {
+            return None;
+        }
+        cached.last_used = cached.last_used.max(now);
+        Some(cached.handle.clone())
+    }
+
+    // Any handle opened for `database_id`.
+    pub fn current(&self, database_id: &str) -> Option<String> {
+        self.handles.get(database_id).map(|c| c.handle.clone())
+    }
+
+    // Caches a handle, returns the one it replaces.
+    pub fn insert(
+        &mut self,
+        database_id: &str,
+        handle: String,
+        fingerprint: String,
+        now: u64,
+    ) -> Option<String> {
+        self.handles
+            .insert(
+                database_id.to_string(),
+                CachedHandle {
+                    handle,
+                    fingerprint,
+                    last_used: now,
+                },
+            )
+            .map(|c| c.handle)
+    }
+
+    pub fn remove(&mut self, database_id: &str) -> Option<String> {
+        self.handles.remove(database_id).map(|c| c.handle)
+    }
+
+    pub fn pin(&mut self, database_id: &str) {
+        *self.pinned.entry(database_id.to_string()).or_default() += 1;
+    }
+
+    pub fn unpin(&mut self, database_id: &str) {
+        if let Some(count) = self.pinned.get_mut(database_id) {
+            *count -= 1;
+            if *count == 0 {
+                self.pinned.remove(database_id);
+            }
+        }
+    }
+
+    pub fn is_pinned(&self, database_id: &str) -> bool {
+        self.pinned.contains_key(database_id)
+    }
+
+    // Removes the unpinned handles unused for longer than IDLE_TIMEOUT_NS, returns them.
+    pub fn evict_idle(&mut self, now: u64) -> Vec<String> {
+        let idle: Vec<String> = self
+            .handles
+            .iter()
+            .filter(|(database_id, _)| !self.is_pinned(database_id))
+            .filter(|(_, c)| now.saturating_sub(c.last_used) > IDLE_TIMEOUT_NS)
+            .map(|(database_id, _)| database_id.clone())
+            .collect();
+        idle.iter()
+            .filter_map(|database_id| self.remove(database_id))
+            .collect()
+    }
+}
+
+fn now() -> u64 {
+    // trusted_time may not be given to queries, idle handles are then kept
+    klave::context::get("trusted_time")
+        .ok()
+        .and_then(|t| t.parse::<u64>().ok())
+        .unwrap_or(0)
+}
+
+// Opens a handle, trying up to MAX_CONNECT_ATTEMPTS times.
+fn open<F>(connection_string: F) -> Result<String, Box<dyn std::error::Error>>
+where
+    F: Fn() -> Result<String, Box<dyn std::error::Error>>,
+{
+    let mut last_error: Box<dyn std::error::Error> = "No connection attempt".into();
+    for _ in 0..MAX_CONNECT_ATTEMPTS {
+        // Built again on each attempt, as a token may have expired
+        let uri = connection_string()?;
+        match klave::sql::connection_open(&uri) {
+            Ok(handle) => return Ok(handle),
+            Err(err) => last_error = err,
+        }
+    }
+    klave::notifier::send_string(&format!(
+        "Failed to connect to PostgreSQL after {MAX_CONNECT_ATTEMPTS} attempts: {last_error}"
+    ));
+    Err(last_error)
+}
+
+// Returns the cached handle of `database_id` if it was opened with the same settings, opens
+// one otherwise. Idle handles of other databases are evicted on the way.
+// klave::sql has no way to close a handle: evicted and replaced handles are dropped, and the
+// host releases their connection.
+pub fn acquire<F>(
+    database_id: &str,
+    fingerprint: &str,
+    connection_string: F,
+) -> Result<String, Box<dyn std::error::Error>>
+where
+    F: Fn() -> Result<String, Box<dyn std::error::Error>>,
+{
+    let now = now();
+    let cached = CONNECTIONS.with(|c| {
+        let mut cache = c.borrow_mut();
+        if now > 0 {
+            cache.evict_idle(now);
+        }
+        cache.get(database_id, fingerprint, now)
+    });
+    if let Some(handle) = cached {
+        return Ok(handle);
+    }
+
+    let handle = open(connection_string)?;
+    CONNECTIONS.with(|c| {
+        c.borrow_mut()
+            .insert(database_id, handle.clone(), fingerprint.to_string(), now)
+    });
+    Ok(handle)
+}
+
+// Handle of `database_id` opened by acquire.
+pub fn current(database_id: &str) -> Result<String, Box<dyn std::error::Error>> {
+    CONNECTIONS
+        .with(|c| c.borrow().current(database_id))
+        .ok_or_else(|| format!("Not connected to database {database_id}").into())
+}
+
+// Marks the handle of `database_id` as holding an open cursor, until unpin.
+pub fn pin(database_id: &str) {
+    CONNECTIONS.with(|c| c.borrow_mut().pin(database_id));
+}
+
+pub fn unpin(database_id: &str) {
+    CONNECTIONS.with(|c| c.borrow_mut().unpin(database_id));
+}
+
+// Whether a cursor is open on the handle of `database_id`.
+pub fn is_pinned(database_id: &str) -> bool {
+    CONNECTIONS.with(|c| c.borrow().is_pinned(database_id))
+}
+
+// Whether the handle still answers.
+pub fn is_alive(handle: &str) -> bool {
+    klave::sql::query(handle, "SELECT 1").is_ok()
+}
+
+// Drops the handle of `database_id` and opens a new one.
+pub fn reconnect<F>(
+    database_id: &str,
+    fingerprint: &str,
+    connection_string: F,
+) -> Result<String, Box<dyn std::error::Error>>
+where
+    F: Fn() -> Result<String, Box<dyn std::error::Error>>,
+{
+    CONNECTIONS.with(|c| c.borrow_mut().remove(database_id));
+    acquire(database_id, fingerprint, connection_string)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_connection_cache() {
+        let mut cache = ConnectionCache::default();
+        assert_eq!(
+            cache.insert("db1", "h1".to_string(), "f1".to_string(), 0),
+            None
+        );
+        assert_eq!(cache.get("db1", "f1", 10), Some("h1".to_string()));
+        // Settings changed, the handle must be replaced
+        assert_eq!(cache.get("db1", "f2", 10), None);
+
+        cache.insert("db2", "h2".to_string(), "f2".to_string(), IDLE_TIMEOUT_NS);
+        assert_eq!(
+            cache.evict_idle(IDLE_TIMEOUT_NS + 11),
+            vec!["h1".to_string()]
+        );
+        assert_eq!(cache.current("db1"), None);
+        assert_eq!(cache.current("db2"), Some("h2".to_string()));
+
+        // A handle holding an open cursor is kept
+        cache.pin("db2");
+        cache.pin("db2");
+        assert!(cache.evict_idle(3 * IDLE_TIMEOUT_NS).is_empty());
+        cache.unpin("db2");
+        assert!(cache.is_pinned("db2"));
+        cache.unpin("db2");
+        assert!(!cache.is_pinned("db2"));
+        assert_eq!(
+            cache.evict_idle(3 * IDLE_TIMEOUT_NS),
+            vec!["h2".to_string()]
+        );
+    }
+}
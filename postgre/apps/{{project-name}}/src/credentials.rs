@@ -0,0 +1,250 @@
+use std::net::IpAddr;
+
+use http::{Request, Uri};
+use klave::crypto::subtle::{load_key, save_key, CryptoKey};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::crypto::{decrypt_bytes, encrypt_bytes, generate_aes_crypto_key};
+
+// Enclave key the credentials of every database client are encrypted under
+pub(crate) const CREDENTIALS_KEY_NAME: &str = "DatabaseCredentialsKey";
+
+// How the client authenticates to the database.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum AuthMode {
+    // `password`, possibly empty
+    #[default]
+    Password,
+    // Short-lived token fetched from `endpoint`, a local auth endpoint, on each connection and
+    // sent as the password
+    Token {
+        endpoint: String,
+        #[serde(default = "default_token_field")]
+        token_field: String,
+    },
+}
+
+fn default_token_field() -> String {
+    "token".to_string()
+}
+
+// Connection settings stored in clear in the client record.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ConnectionSettings {
+    pub host: String,
+    pub dbname: String,
+    pub user: String,
+    #[serde(default)]
+    pub auth: AuthMode,
+}
+
+// Secrets of a client, only stored encrypted under the credentials key.
+// Not Debug, so that they cannot end up in a log.
+#[derive(Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Credentials {
+    #[serde(default)]
+    pub password: String,
+}
+
+// Loads the credentials key, creating it on first use. Keys can only be created in a transaction.
+fn load_or_create_credentials_key() -> Result<CryptoKey, Box<dyn std::error::Error>> {
+    if let Ok(key) = load_key(CREDENTIALS_KEY_NAME) {
+        return Ok(key);
+    }
+    let key = generate_aes_crypto_key()?;
+    match save_key(&key, CREDENTIALS_KEY_NAME) {
+        Ok(_) => Ok(key),
+        Err(err) => {
+            klave::notifier::send_string(&format!("Failed to save credentials key: {err}"));
+            Err(err)
+        }
+    }
+}
+
+impl ConnectionSettings {
+    pub fn validate(&self) -> Result<(), Box<dyn std::error::Error>> {
+        match &self.auth {
+            AuthMode::Password => Ok(()),
+            AuthMode::Token { endpoint, .. } => {
+                if endpoint.is_empty() {
+                    return Err("Token endpoint is required".into());
+                }
+                if !is_local_endpoint(endpoint) {
+                    return Err(format!("Token endpoint {endpoint} is not a local endpoint").into());
+                }
+                Ok(())
+            }
+        }
+    }
+
+    // Builds the libpq connection string. `token` is the token fetched for the Token mode.
+    pub fn connection_string(&self, credentials: &Credentials, token: Option<&str>) -> String {
+        let mut conn_str = format!(
+            "host={} dbname={}",
+            conninfo_value(&self.host),
+            conninfo_value(&self.dbname)
+        );
+        if !self.user.is_empty() {
+            conn_str.push_str(&format!(" user={}", conninfo_value(&self.user)));
+        }
+        match &self.auth {
+            AuthMode::Password => {
+                if !credentials.password.is_empty() {
+                    conn_str.push_str(&format!(
+                        " password={}",
+                        conninfo_value(&credentials.password)
+                    ));
+                }
+            }
+            AuthMode::Token { .. } => {
+                if let Some(token) = token {
+                    conn_str.push_str(&format!(" password={}", conninfo_value(token)));
+                }
+            }
+        }
+        conn_str
+    }
+}
+
+impl Credentials {
+    // Encrypts the credentials, bound to `database_id`.
+    pub fn seal(&self, database_id: &str) -> Result<String, Box<dyn std::error::Error>> {
+        let key = load_or_create_credentials_key()?;
+        let serialized = serde_json::to_vec(self)?;
+        encrypt_bytes(&key, &serialized, database_id.as_bytes())
+    }
+
+    // Decrypts the credentials sealed for `database_id`.
+    pub fn open(
+        database_id: &str,
+        sealed: &str,
+    ) -> Result<Credentials, Box<dyn std::error::Error>> {
+        if sealed.is_empty() {
+            return Ok(Credentials::default());
+        }
+        let key = match load_key(CREDENTIALS_KEY_NAME) {
+            Ok(key) => key,
+            Err(err) => {
+                klave::notifier::send_string(&format!("Failed to load credentials key: {err}"));
+                return Err(err);
+            }
+        };
+        let serialized = match decrypt_bytes(&key, sealed, database_id.as_bytes()) {
+            Ok(bytes) => bytes,
+            Err(err) => {
+                klave::notifier::send_string(&format!("Failed to decrypt credentials: {err}"));
+                return Err(err);
+            }
+        };
+        Ok(serde_json::from_slice::<Credentials>(&serialized)?)
+    }
+}
+
+// Quotes a value of a libpq connection string.
+fn conninfo_value(value: &str) -> String {
+    format!("'{}'", value.replace('\\', "\\\\").replace('\'', "\\'"))
+}
+
+// Whether `endpoint` is an http(s) URL on the loopback interface: `localhost` or a loopback
+// address.
+fn is_local_endpoint(endpoint: &str) -> bool {
+    let Ok(uri) = endpoint.parse::<Uri>() else {
+        return false;
+    };
+    if !matches!(uri.scheme_str(), Some("http" | "https")) {
+        return false;
+    }
+    match uri.host() {
+        Some(host) if host.eq_ignore_ascii_case("localhost") => true,
+        Some(host) => host
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .parse::<IpAddr>()
+            .is_ok_and(|ip| ip.is_loopback()),
+        None => false,
+    }
+}
+
+// Fetches a token from the auth endpoint, read from the `token_field` of its JSON response.
+pub fn fetch_token(
+    endpoint: &str,
+    token_field: &str,
+) -> Result<String, Box<dyn std::error::Error>> {
+    let http_request = Request::builder()
+        .method("GET")
+        .uri(endpoint)
+        .header("Accept", "application/json")
+        .body(String::new())?;
+    let response = match klave::https::request(&http_request) {
+        Ok(r) => r,
+        Err(err) => {
+            klave::notifier::send_string(&format!("Failed to fetch token from {endpoint}: {err}"));
+            return Err(err);
+        }
+    };
+    if !response.status().is_success() {
+        return Err(format!("Token endpoint returned {}", response.status()).into());
+    }
+    let body = serde_json::from_str::<Value>(response.body())?;
+    match body.get(token_field).and_then(Value::as_str) {
+        Some(token) if !token.is_empty() => Ok(token.to_string()),
+        _ => Err(format!("No {token_field} in the token endpoint response").into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_connection_string() {
+        let mut settings = ConnectionSettings {
+            host: "db.example.com".to_string(),
+            dbname: "shop".to_string(),
+            user: "klave".to_string(),
+            auth: AuthMode::Password,
+        };
+        let credentials = Credentials {
+            password: "it's a secret".to_string(),
+        };
+        assert_eq!(
+            settings.connection_string(&credentials, None),
+            "host='db.example.com' dbname='shop' user='klave' password='it\\'s a secret'"
+        );
+
+        settings.auth = AuthMode::Token {
+            endpoint: "http://localhost/token".to_string(),
+            token_field: default_token_field(),
+        };
+        assert_eq!(
+            settings.connection_string(&credentials, Some("t0k3n")),
+            "host='db.example.com' dbname='shop' user='klave' password='t0k3n'"
+        );
+        assert!(settings.validate().is_ok());
+    }
+
+    #[test]
+    fn test_local_endpoint() {
+        assert!(is_local_endpoint("http://localhost:8080/token"));
+        assert!(is_local_endpoint("https://127.0.0.1/token"));
+        assert!(is_local_endpoint("http://[::1]:9000/token"));
+        assert!(!is_local_endpoint("https://auth.example.com/token"));
+        assert!(!is_local_endpoint("http://10.0.0.1/token"));
+        assert!(!is_local_endpoint("http://localhost.example.com/token"));
+        assert!(!is_local_endpoint("ftp://localhost/token"));
+        assert!(!is_local_endpoint("localhost/token"));
+
+        let settings = ConnectionSettings {
+            host: "db.example.com".to_string(),
+            dbname: "shop".to_string(),
+            user: "klave".to_string(),
+            auth: AuthMode::Token {
+                endpoint: "https://auth.example.com/token".to_string(),
+                token_field: default_token_field(),
+            },
+        };
+        assert!(settings.validate().is_err());
+    }
+}
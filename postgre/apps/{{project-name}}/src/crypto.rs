@@ -1,8 +1,11 @@
+use crate::catalog::EncryptionMode;
 use crate::utils::get_serde_value_into_bytes;
-use hex::encode;
+use hex::{decode, encode};
 use klave::crypto::subtle::{
-    self, derive_key, encrypt, export_key, AesGcmParams, AesKeyGenParams, CryptoKey,
-    DerivedKeyAlgorithm, EncryptAlgorithm, HkdfDerivParams, KeyDerivationAlgorithm,
+    self, decrypt, delete_key, derive_key, encrypt, export_key, generate_key, load_key, save_key,
+    sign, AesGcmParams, AesKeyGenParams, CryptoKey, DerivedKeyAlgorithm, EncryptAlgorithm,
+    HkdfDerivParams, HmacKeyGenParams, HmacParams, KeyDerivationAlgorithm, KeyGenAlgorithm,
+    SignAlgorithm,
 };
 use serde_json::Value;
 
@@ -25,6 +28,56 @@ pub fn generate_ecc_crypto_key() -> Result<CryptoKey, Box<dyn std::error::Error>
     Ok(private_key)
 }
 
+pub fn generate_aes_crypto_key() -> Result<CryptoKey, Box<dyn std::error::Error>> {
+    let aes_params = AesKeyGenParams { length: 256 };
+    let gen_algorithm = subtle::KeyGenAlgorithm::Aes(aes_params);
+
+    match subtle::generate_key(&gen_algorithm, false, &["encrypt", "decrypt"]) {
+        Ok(key) => Ok(key),
+        Err(err) => {
+            klave::notifier::send_string(&err.to_string());
+            Err(err)
+        }
+    }
+}
+
+// Encrypts bytes with AES-GCM and a random iv, returns the hex encoded iv and ciphertext.
+// `additional_data` is authenticated, and must be given again to decrypt.
+pub fn encrypt_bytes(
+    key: &CryptoKey,
+    data: &[u8],
+    additional_data: &[u8],
+) -> Result<String, Box<dyn std::error::Error>> {
+    let mut iv = klave::crypto::random::get_random_bytes(AES_GCM_IV_SIZE as i32)?;
+    let aes_gcm_params = AesGcmParams {
+        iv: iv.clone(),
+        additional_data: additional_data.to_vec(),
+        tag_length: 128,
+    };
+    let mut encrypted = encrypt(&EncryptAlgorithm::AesGcm(aes_gcm_params), key, data)?;
+    iv.append(&mut encrypted);
+    Ok(encode(iv))
+}
+
+// Reverses encrypt_bytes.
+pub fn decrypt_bytes(
+    key: &CryptoKey,
+    iv_encrypted: &str,
+    additional_data: &[u8],
+) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let iv_and_encrypted = decode(iv_encrypted)?;
+    if iv_and_encrypted.len() <= AES_GCM_IV_SIZE {
+        return Err("Encrypted value is too short".into());
+    }
+    let (iv, encrypted) = iv_and_encrypted.split_at(AES_GCM_IV_SIZE);
+    let aes_gcm_params = AesGcmParams {
+        iv: iv.to_vec(),
+        additional_data: additional_data.to_vec(),
+        tag_length: 128,
+    };
+    decrypt(&EncryptAlgorithm::AesGcm(aes_gcm_params), key, encrypted)
+}
+
 pub fn compute_sha256_hex_string(data: &[u8]) -> String {
     // Using Klave's crypto utilities
     match klave::crypto::sha::digest("SHA2-256", data) {
@@ -133,6 +186,58 @@ pub fn encrypt_value(
     table_name: String,
     column_name: String,
     value: Value,
+) -> Result<String, Box<dyn std::error::Error>> {
+    // Compute the iv deterministically from the point of view of the value to encrypt.
+    // I derive a key from the master key and the value to encrypt, export it as raw bytes, and use the first 12 bytes as the iv.
+    let iv = match derive_iv(master_key, column_name.clone(), value.clone()) {
+        Ok(res) => res,
+        Err(err) => {
+            klave::notifier::send_string(&format!("Failed to derive AES-GCM key: {err}"));
+            return Err(err);
+        }
+    };
+    encrypt_value_with_iv(master_key, table_name, column_name, value, iv)
+}
+
+// Encrypts with a random iv: equal values give different ciphertexts.
+pub fn encrypt_value_randomized(
+    master_key: &CryptoKey,
+    table_name: String,
+    column_name: String,
+    value: Value,
+) -> Result<String, Box<dyn std::error::Error>> {
+    let iv = match klave::crypto::random::get_random_bytes(AES_GCM_IV_SIZE as i32) {
+        Ok(iv) => iv,
+        Err(err) => {
+            klave::notifier::send_string(&format!("Failed to generate iv: {err}"));
+            return Err(err);
+        }
+    };
+    encrypt_value_with_iv(master_key, table_name, column_name, value, iv)
+}
+
+// Encrypts a value of a column encrypted with the given mode.
+pub fn encrypt_value_with_mode(
+    master_key: &CryptoKey,
+    table_name: String,
+    column_name: String,
+    value: Value,
+    mode: EncryptionMode,
+) -> Result<String, Box<dyn std::error::Error>> {
+    match mode {
+        EncryptionMode::Deterministic => encrypt_value(master_key, table_name, column_name, value),
+        EncryptionMode::Randomized | EncryptionMode::BlindIndex => {
+            encrypt_value_randomized(master_key, table_name, column_name, value)
+        }
+    }
+}
+
+fn encrypt_value_with_iv(
+    master_key: &CryptoKey,
+    table_name: String,
+    column_name: String,
+    value: Value,
+    iv: Vec<u8>,
 ) -> Result<String, Box<dyn std::error::Error>> {
     // Convert serde Value in bytes
     let value_in_bytes = match get_serde_value_into_bytes(&value) {
@@ -152,15 +257,6 @@ pub fn encrypt_value(
             return Err(err);
         }
     };
-    // Compute the iv deterministically from the point of view of the value to encrypt.
-    // I derive a key from the master key and the value to encrypt, export it as raw bytes, and use the first 12 bytes as the iv.
-    let iv = match derive_iv(master_key, column_name.clone(), value.clone()) {
-        Ok(res) => res,
-        Err(err) => {
-            klave::notifier::send_string(&format!("Failed to derive AES-GCM key: {err}"));
-            return Err(err);
-        }
-    };
     let iv_12 = iv[0..12].to_vec();
 
     // Encrypt the value with the derived AES-GCM key
@@ -185,3 +281,215 @@ pub fn encrypt_value(
 
     Ok(encoded_iv_value)
 }
+
+// Master key of a database, with the HMAC key generated along with it for blind indexes and
+// MACs. Keys can only be derived as AES keys, so the HMAC key is generated instead, and saved
+// in the key store under `<master key name>_hmac`. Neither key can be exported.
+pub struct MasterKey {
+    pub key: CryptoKey,
+    pub hmac_key: CryptoKey,
+}
+
+fn hmac_key_name(master_key_name: &str) -> String {
+    format!("{master_key_name}_hmac")
+}
+
+impl MasterKey {
+    // Generates a master key and its HMAC key, and saves them in the key store.
+    pub fn create(name: &str) -> Result<(), Box<dyn std::error::Error>> {
+        save_key(&generate_ecc_crypto_key()?, name)?;
+        let hmac_params = HmacKeyGenParams {
+            hash: "SHA2-256".to_string(),
+        };
+        let hmac_key = generate_key(&KeyGenAlgorithm::Hmac(hmac_params), false, &["sign"])?;
+        save_key(&hmac_key, &hmac_key_name(name))
+    }
+
+    pub fn load(name: &str) -> Result<MasterKey, Box<dyn std::error::Error>> {
+        Ok(MasterKey {
+            key: load_key(name)?,
+            hmac_key: load_key(&hmac_key_name(name))?,
+        })
+    }
+
+    // Deletes both keys from the key store.
+    pub fn delete(&self) -> Result<(), Box<dyn std::error::Error>> {
+        delete_key(&self.key)?;
+        delete_key(&self.hmac_key)
+    }
+}
+
+// HMAC-SHA256 of `purpose || u32_be(len(field)) || field || ...`. The fields are length
+// prefixed so that bytes cannot move from one field to the next.
+fn hmac_sha256(
+    hmac_key: &CryptoKey,
+    purpose: &str,
+    fields: &[&[u8]],
+) -> Result<String, Box<dyn std::error::Error>> {
+    let mut data = format!("klave-{purpose}").into_bytes();
+    for field in fields {
+        data.extend((field.len() as u32).to_be_bytes());
+        data.extend_from_slice(field);
+    }
+    let algorithm = SignAlgorithm::Hmac(HmacParams {
+        hash: "SHA2-256".to_string(),
+    });
+    Ok(encode(sign(&algorithm, hmac_key, &data)?))
+}
+
+// Authenticates a master key rotation checkpoint, so that only checkpoints returned by
+// reencrypt_next_chunk can be committed.
+pub fn compute_checkpoint_mac(
+    hmac_key: &CryptoKey,
+    database_id: &str,
+    data: &[u8],
+) -> Result<String, Box<dyn std::error::Error>> {
+    hmac_sha256(
+        hmac_key,
+        "rotation-checkpoint",
+        &[database_id.as_bytes(), data],
+    )
+}
+
+// Authenticates the receipt of a column encryption, so that only columns encrypted by
+// execute_table_encryption can be marked as encrypted in the catalog.
+pub fn compute_encryption_mac(
+    hmac_key: &CryptoKey,
+    database_id: &str,
+    data: &[u8],
+) -> Result<String, Box<dyn std::error::Error>> {
+    hmac_sha256(
+        hmac_key,
+        "column-encryption",
+        &[database_id.as_bytes(), data],
+    )
+}
+
+// Compares two MACs in constant time.
+pub fn mac_eq(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0, |acc, (x, y)| acc | (x ^ y))
+            == 0
+}
+
+// Computes the blind index of a value: an HMAC-SHA256 of the table, column and value, stored
+// next to the randomized ciphertext so that the column can still be filtered on equality.
+pub fn compute_blind_index(
+    hmac_key: &CryptoKey,
+    table_name: String,
+    column_name: String,
+    value: Value,
+) -> Result<String, Box<dyn std::error::Error>> {
+    let value_in_bytes = match get_serde_value_into_bytes(&value) {
+        Ok(bytes) => bytes,
+        Err(err) => {
+            klave::notifier::send_string(&format!("Failed to convert value to bytes: {err}"));
+            return Err(err);
+        }
+    };
+    match hmac_sha256(
+        hmac_key,
+        "blind-index",
+        &[
+            table_name.as_bytes(),
+            column_name.as_bytes(),
+            &value_in_bytes,
+        ],
+    ) {
+        Ok(mac) => Ok(mac),
+        Err(err) => {
+            klave::notifier::send_string(&format!("Failed to compute blind index: {err}"));
+            Err(err)
+        }
+    }
+}
+
+// Whether a value is a ciphertext of the column under one of the master keys. Unlike
+// decrypt_value, failures are expected and not reported.
+pub fn is_ciphertext(
+    master_keys: &[MasterKey],
+    table_name: &str,
+    column_name: &str,
+    value: &str,
+) -> Result<bool, Box<dyn std::error::Error>> {
+    let Ok(iv_and_encrypted) = decode(value) else {
+        return Ok(false);
+    };
+    if iv_and_encrypted.len() <= AES_GCM_IV_SIZE {
+        return Ok(false);
+    }
+    let (iv, encrypted_value) = iv_and_encrypted.split_at(AES_GCM_IV_SIZE);
+    let decrypt_algo = EncryptAlgorithm::AesGcm(AesGcmParams {
+        iv: iv.to_vec(),
+        additional_data: vec![],
+        tag_length: 128,
+    });
+    for master_key in master_keys {
+        let aes_gcm_key = derive_aes_gcm_key(
+            &master_key.key,
+            table_name.to_string(),
+            column_name.to_string(),
+        )?;
+        if decrypt(&decrypt_algo, &aes_gcm_key, encrypted_value).is_ok() {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+// Decrypts with the first master key that authenticates the value. Several keys are given
+// while a master key rotation is in progress, as columns are then partly re-encrypted.
+pub fn decrypt_value(
+    master_keys: &[MasterKey],
+    table_name: String,
+    column_name: String,
+    iv_encrypted_value: &str,
+) -> Result<Value, Box<dyn std::error::Error>> {
+    // Decode the hex string into iv and encrypted value
+    let iv_and_encrypted = match decode(iv_encrypted_value) {
+        Ok(bytes) => bytes,
+        Err(err) => {
+            klave::notifier::send_string(&format!("Failed to decode encrypted value: {err}"));
+            return Err(err.into());
+        }
+    };
+    if iv_and_encrypted.len() <= AES_GCM_IV_SIZE {
+        klave::notifier::send_string("Encrypted value is too short");
+        return Err("Encrypted value is too short".into());
+    }
+    let (iv, encrypted_value) = iv_and_encrypted.split_at(AES_GCM_IV_SIZE);
+
+    let aes_gcm_params = AesGcmParams {
+        iv: iv.to_vec(),
+        additional_data: vec![], // No additional data
+        tag_length: 128,         // 128 bits
+    };
+    let decrypt_algo = EncryptAlgorithm::AesGcm(aes_gcm_params);
+    for master_key in master_keys {
+        // Derive AES-GCM key for the column
+        let aes_gcm_key =
+            match derive_aes_gcm_key(&master_key.key, table_name.clone(), column_name.clone()) {
+                Ok(key) => key,
+                Err(err) => {
+                    klave::notifier::send_string(&format!("Failed to derive AES-GCM key: {err}"));
+                    return Err(err);
+                }
+            };
+        // Decrypt the value with the derived AES-GCM key, the next key is tried on failure
+        let Ok(value_in_bytes) = decrypt(&decrypt_algo, &aes_gcm_key, encrypted_value) else {
+            continue;
+        };
+        // Values were serialized with serde before encryption
+        return match serde_json::from_slice::<Value>(&value_in_bytes) {
+            Ok(value) => Ok(value),
+            Err(err) => {
+                klave::notifier::send_string(&format!("Failed to convert bytes to value: {err}"));
+                Err(err.into())
+            }
+        };
+    }
+    klave::notifier::send_string(&format!("Failed to decrypt value of column {column_name}"));
+    Err(format!("Failed to decrypt value of column {column_name}").into())
+}
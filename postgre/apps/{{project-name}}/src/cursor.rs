@@ -0,0 +1,225 @@
+use serde_json::Value;
+
+use crate::connection;
+use crate::database::{Client, Field, PostGreResponse};
+use crate::query::Query;
+
+// Rows returned by one FETCH.
+pub type Chunk = PostGreResponse<Vec<Vec<Value>>>;
+
+// Server-side cursor over the rows of a query, fetched `fetch_size` rows at a time.
+// The cursor lives in a transaction opened by Client::cursor: close commits it, dropping the
+// cursor before it is closed rolls it back. While it is open, a lost connection is not
+// reopened: the statements fail, and so does the transaction.
+pub struct Cursor<'a> {
+    client: &'a Client,
+    name: String,
+    fetch_size: usize,
+    fields: Vec<Field>,
+    done: bool,
+    open: bool,
+}
+
+// DECLARE statement of cursor `name` over `query`.
+fn declare_query(name: &str, query: &Query) -> Result<Query, Box<dyn std::error::Error>> {
+    let mut declare = Query::new();
+    declare.push("DECLARE ");
+    declare.push_identifier(name)?;
+    declare.push(" NO SCROLL CURSOR FOR ");
+    declare.append(query);
+    Ok(declare)
+}
+
+// FETCH statement of the next `fetch_size` rows of cursor `name`.
+fn fetch_query(name: &str, fetch_size: usize) -> Result<Query, Box<dyn std::error::Error>> {
+    let mut query = Query::new();
+    query.push(&format!("FETCH FORWARD {fetch_size} FROM "));
+    query.push_identifier(name)?;
+    Ok(query)
+}
+
+// CLOSE statement of cursor `name`.
+fn close_query(name: &str) -> Result<Query, Box<dyn std::error::Error>> {
+    let mut query = Query::new();
+    query.push("CLOSE ");
+    query.push_identifier(name)?;
+    Ok(query)
+}
+
+impl<'a> Cursor<'a> {
+    // Begins a transaction and declares the cursor.
+    pub(crate) fn declare(
+        client: &'a Client,
+        query: &Query,
+        fetch_size: usize,
+    ) -> Result<Cursor<'a>, Box<dyn std::error::Error>> {
+        if fetch_size == 0 {
+            return Err("Cursor fetch size must be greater than 0".into());
+        }
+        let name = format!(
+            "klave_cursor_{}",
+            hex::encode(klave::crypto::random::get_random_bytes(8)?)
+        );
+        client.execute(Query::new().push("BEGIN"))?;
+        // The handle now holds the transaction, it must not be replaced until the cursor ends
+        connection::pin(client.database_id());
+        let cursor = Cursor {
+            client,
+            name,
+            fetch_size,
+            fields: Vec::new(),
+            done: false,
+            open: true,
+        };
+
+        // On failure, dropping the cursor rolls the transaction back
+        cursor
+            .client
+            .execute(&declare_query(&cursor.name, query)?)?;
+        Ok(cursor)
+    }
+
+    // Fields of the rows, known once a chunk has been fetched.
+    pub fn fields(&self) -> &[Field] {
+        &self.fields
+    }
+
+    // Fetches the next chunk of at most fetch_size rows, None once every row has been read.
+    pub fn fetch(&mut self) -> Result<Option<Chunk>, Box<dyn std::error::Error>> {
+        if self.done {
+            return Ok(None);
+        }
+        let chunk = self
+            .client
+            .query::<Vec<Vec<Value>>>(&fetch_query(&self.name, self.fetch_size)?)?;
+        Ok(self.take_chunk(chunk))
+    }
+
+    // Keeps track of a fetched chunk: a short chunk is the last one, an empty one is dropped.
+    fn take_chunk(&mut self, chunk: Chunk) -> Option<Chunk> {
+        self.done = chunk.resultset.len() < self.fetch_size;
+        if chunk.resultset.is_empty() {
+            return None;
+        }
+        if self.fields.is_empty() {
+            self.fields = chunk.fields.clone();
+        }
+        Some(chunk)
+    }
+
+    // Closes the cursor and commits the transaction.
+    pub fn close(mut self) -> Result<(), Box<dyn std::error::Error>> {
+        self.open = false;
+        connection::unpin(self.client.database_id());
+        if let Err(err) = self.client.execute(&close_query(&self.name)?) {
+            let _ = self.client.execute(Query::new().push("ROLLBACK"));
+            return Err(err);
+        }
+        self.client.execute(Query::new().push("COMMIT"))?;
+        Ok(())
+    }
+}
+
+impl Iterator for Cursor<'_> {
+    type Item = Result<Chunk, Box<dyn std::error::Error>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.fetch() {
+            Ok(chunk) => chunk.map(Ok),
+            Err(err) => {
+                // No further chunk after an error
+                self.done = true;
+                Some(Err(err))
+            }
+        }
+    }
+}
+
+impl Drop for Cursor<'_> {
+    fn drop(&mut self) {
+        if self.open {
+            connection::unpin(self.client.database_id());
+            // Closes the cursor and discards the statements run since it was declared
+            let _ = self.client.execute(Query::new().push("ROLLBACK"));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn chunk(rows: usize) -> Chunk {
+        serde_json::from_value(json!({
+            "fields": [{
+                "name": "id", "type": 23, "size": 4, "scale": 0, "nullable": false, "description": null
+            }],
+            "resultset": (0..rows).map(|i| vec![json!(i)]).collect::<Vec<_>>()
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_cursor_statements() {
+        let mut query = Query::new();
+        query.push("SELECT * FROM ");
+        query.push_identifier("users").unwrap();
+        query.push(" WHERE ");
+        query.push_identifier("age").unwrap();
+        query.push(" > ");
+        query.push_param(json!(30));
+
+        let declare = declare_query("klave_cursor_00", &query).unwrap();
+        assert_eq!(
+            declare.sql(),
+            "DECLARE \"klave_cursor_00\" NO SCROLL CURSOR FOR SELECT * FROM \"users\" WHERE \"age\" > $1"
+        );
+        assert_eq!(declare.params(), &[json!(30)]);
+        assert_eq!(
+            fetch_query("klave_cursor_00", 500).unwrap().sql(),
+            "FETCH FORWARD 500 FROM \"klave_cursor_00\""
+        );
+        assert_eq!(
+            close_query("klave_cursor_00").unwrap().sql(),
+            "CLOSE \"klave_cursor_00\""
+        );
+    }
+
+    #[test]
+    fn test_fetch_ends_on_short_chunk() {
+        let client: Client = serde_json::from_value(json!({
+            "database_id": "db",
+            "db_input_details": { "host": "localhost", "dbname": "shop", "user": "klave" },
+            "master_key_name": null
+        }))
+        .unwrap();
+        let mut cursor = Cursor {
+            client: &client,
+            name: "klave_cursor_00".to_string(),
+            fetch_size: 2,
+            fields: Vec::new(),
+            done: false,
+            // Not open on a database, nothing to roll back when dropped
+            open: false,
+        };
+
+        let full = cursor.take_chunk(chunk(2)).unwrap();
+        assert_eq!(full.resultset.len(), 2);
+        assert!(!cursor.done);
+        assert_eq!(cursor.fields()[0].name, "id");
+
+        let short = cursor.take_chunk(chunk(1)).unwrap();
+        assert_eq!(short.resultset.len(), 1);
+        assert!(cursor.done);
+        // No FETCH is run once the short chunk is read
+        assert!(cursor.fetch().unwrap().is_none());
+        assert!(cursor.next().is_none());
+
+        // A chunk ending exactly on fetch_size is followed by an empty one
+        cursor.done = false;
+        assert!(cursor.take_chunk(chunk(0)).is_none());
+        assert!(cursor.done);
+    }
+}
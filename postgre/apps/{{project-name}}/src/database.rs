@@ -1,20 +1,50 @@
-use klave::crypto::subtle::save_key;
 use serde::{Deserialize, Serialize};
 use serde_json::{self, Value};
 
 use crate::{
-    crypto::{encrypt_value, generate_ecc_crypto_key},
-    utils::flatten_vec_of_vec_values_to_single_string,
+    catalog::{blind_index_column, Catalog, EncryptionMode, EncryptionReceipt},
+    connection::{self, MAX_CONNECT_ATTEMPTS},
+    credentials::{fetch_token, AuthMode, ConnectionSettings, Credentials},
+    crypto::{
+        compute_blind_index, compute_encryption_mac, compute_sha256_hex_string, decrypt_value,
+        encrypt_value, encrypt_value_with_mode, is_ciphertext, MasterKey,
+    },
+    cursor::Cursor,
+    dsl::{EncryptedColumn, QuerySpec},
+    query::Query,
+    rotation::{Checkpoint, Rotation},
+    row::{FromRow, Row, RowError},
 };
 
 pub(crate) const DATABASE_CLIENT_TABLE: &str = "DatabaseClientTable";
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+// Connection settings as given to db_setup. The secrets are encrypted before being stored.
+#[derive(Clone, Serialize, Deserialize)]
 pub struct DBInputDetails {
     pub host: String,
     pub dbname: String,
     pub user: String,
+    #[serde(default)]
     pub password: String,
+    #[serde(default)]
+    pub auth: AuthMode,
+}
+
+impl DBInputDetails {
+    // Splits the settings stored in clear from the secrets.
+    pub fn split(self) -> (ConnectionSettings, Credentials) {
+        (
+            ConnectionSettings {
+                host: self.host,
+                dbname: self.dbname,
+                user: self.user,
+                auth: self.auth,
+            },
+            Credentials {
+                password: self.password,
+            },
+        )
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -52,12 +82,40 @@ pub struct ReadEncryptedTablePerUserInput {
     pub last_name: String,
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReadDecryptedRowsInput {
+    pub database_id: String,
+    pub table: String,
+    // Columns to select, all columns when empty
+    #[serde(default)]
+    pub columns: Vec<String>,
+    pub limit: Option<u64>,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct UpdateHandleClientInput {
     pub database_id: String,
     pub opaque_handle: String,
 }
 
+#[derive(Clone, Serialize, Deserialize)]
+pub struct UpdateCredentialsInput {
+    pub database_id: String,
+    #[serde(flatten)]
+    pub db_input_details: DBInputDetails,
+}
+
+// Client as listed to its owner, without the password.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ClientInfo {
+    pub database_id: String,
+    pub host: String,
+    pub dbname: String,
+    pub user: String,
+    pub auth: AuthMode,
+    pub master_key_version: u32,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Clients {
     pub(crate) clients: Vec<String>,
@@ -114,9 +172,10 @@ impl Clients {
         &mut self,
         db_input_details: DBInputDetails,
     ) -> Result<String, Box<dyn std::error::Error>> {
-        let database_id = self.exists(&db_input_details).to_string();
+        let owner = klave::context::get("sender")?;
+        let database_id = self.exists(&db_input_details, &owner).to_string();
         if database_id.is_empty() {
-            let mut client = Client::new(db_input_details);
+            let mut client = Client::new(db_input_details)?;
             client.save()?;
             self.clients.push(client.database_id.clone());
             self.save()?;
@@ -126,13 +185,14 @@ impl Clients {
         }
     }
 
-    pub fn exists(&self, db_input_details: &DBInputDetails) -> String {
+    // Returns the database_id of the client of `owner` with these settings, empty if none.
+    pub fn exists(&self, db_input_details: &DBInputDetails, owner: &str) -> String {
+        let (settings, credentials) = db_input_details.clone().split();
         for database_id in self.clients.iter() {
             if let Ok(client) = Client::load(database_id.to_string()) {
-                if client.db_input_details.host == db_input_details.host
-                    && client.db_input_details.dbname == db_input_details.dbname
-                    && client.db_input_details.user == db_input_details.user
-                    && client.db_input_details.password == db_input_details.password
+                if client.owner == owner
+                    && client.db_input_details == settings
+                    && client.credentials().is_ok_and(|c| c == credentials)
                 {
                     return database_id.to_string();
                 }
@@ -166,14 +226,34 @@ impl Clients {
         }
         Ok(clients)
     }
+
+    // Lists the clients created by the sender.
+    pub fn list_owned(&self) -> Result<Vec<ClientInfo>, Box<dyn std::error::Error>> {
+        let sender = klave::context::get("sender")?;
+        Ok(self
+            .list()?
+            .iter()
+            .filter(|client| !client.owner.is_empty() && client.owner == sender)
+            .map(Client::info)
+            .collect())
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Client {
     database_id: String,
-    db_input_details: DBInputDetails,
-    opaque_handle: String,
+    db_input_details: ConnectionSettings,
+    #[serde(default)]
+    credentials: String, // Credentials, encrypted under the credentials key
     master_key_name: Option<String>, // Optional field for master key name
+    #[serde(default = "default_master_key_version")]
+    master_key_version: u32, // Incremented by each master key rotation
+    #[serde(default)]
+    owner: String, // Sender who created the client
+}
+
+fn default_master_key_version() -> u32 {
+    1
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -199,27 +279,27 @@ pub struct PostGreResponse<T> {
     pub resultset: T, // Use Vec<Vec<Value>> for the varying resultset
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct EncryptionDBDetails {
-    pub id: String,
-    pub encryption_key_name: String,
-}
+impl PostGreResponse<Vec<Vec<Value>>> {
+    pub fn rows(&self) -> impl Iterator<Item = Row<'_>> {
+        self.resultset
+            .iter()
+            .map(|values| Row::new(&self.fields, values))
+    }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct EncryptedQueryWithEncryptedUser {
-    pub query: String,
-    pub first_name_encryption: String,
-    pub last_name_encryption: String,
+    // Maps every row into T, by column name.
+    pub fn map_rows<T: FromRow>(&self) -> Result<Vec<T>, RowError> {
+        self.rows().map(|row| T::from_row(&row)).collect()
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct EncryptedQueryWithEncryptedGender {
-    pub query: String,
-    pub gender_encryption: String,
+pub struct EncryptionDBDetails {
+    pub id: String,
+    pub encryption_key_name: String,
 }
 
 impl Client {
-    pub fn new(db_input_details: DBInputDetails) -> Self {
+    pub fn new(db_input_details: DBInputDetails) -> Result<Self, Box<dyn std::error::Error>> {
         let database_id = match klave::crypto::random::get_random_bytes(64).map(hex::encode) {
             Ok(id) => id,
             Err(e) => {
@@ -227,16 +307,139 @@ impl Client {
                 String::new()
             }
         };
-        Self {
+        let owner = match klave::context::get("sender") {
+            Ok(sender) => sender,
+            Err(e) => {
+                klave::notifier::send_string(&format!("Failed to get sender: {e}"));
+                String::new()
+            }
+        };
+        let (settings, credentials) = db_input_details.split();
+        settings.validate()?;
+        let sealed = credentials.seal(&database_id)?;
+        Ok(Self {
             database_id,
-            db_input_details,
-            opaque_handle: String::new(),
+            db_input_details: settings,
+            credentials: sealed,
             master_key_name: None,
+            master_key_version: default_master_key_version(),
+            owner,
+        })
+    }
+
+    // Checks that the sender is the owner of the client.
+    pub fn check_sender(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let sender = klave::context::get("sender")?;
+        if self.owner.is_empty() || self.owner != sender {
+            return Err(format!(
+                "Sender is not authorised to access database {}",
+                self.database_id
+            )
+            .into());
+        }
+        Ok(())
+    }
+
+    pub fn info(&self) -> ClientInfo {
+        ClientInfo {
+            database_id: self.database_id.clone(),
+            host: self.db_input_details.host.clone(),
+            dbname: self.db_input_details.dbname.clone(),
+            user: self.db_input_details.user.clone(),
+            auth: self.db_input_details.auth.clone(),
+            master_key_version: self.master_key_version,
+        }
+    }
+
+    // Replaces the connection settings, the master key is kept.
+    pub fn set_db_input_details(
+        &mut self,
+        db_input_details: DBInputDetails,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let (settings, credentials) = db_input_details.split();
+        settings.validate()?;
+        self.credentials = credentials.seal(&self.database_id)?;
+        self.db_input_details = settings;
+        Ok(())
+    }
+
+    // Decrypts the credentials, only to connect.
+    fn credentials(&self) -> Result<Credentials, Box<dyn std::error::Error>> {
+        Credentials::open(&self.database_id, &self.credentials)
+    }
+
+    pub fn database_id(&self) -> &str {
+        &self.database_id
+    }
+
+    pub fn master_key_version(&self) -> u32 {
+        self.master_key_version
+    }
+
+    // Replaces the master key, once a rotation has re-encrypted every column.
+    pub fn set_master_key(&mut self, master_key_name: String, master_key_version: u32) {
+        self.master_key_name = Some(master_key_name);
+        self.master_key_version = master_key_version;
+    }
+
+    // Master keys values can be encrypted under: the current one, then the one a rotation in
+    // progress is moving to.
+    pub fn master_keys(&self) -> Result<Vec<MasterKey>, Box<dyn std::error::Error>> {
+        let mut master_keys = vec![self.load_master_key()?];
+        if let Some(rotation) = Rotation::load(&self.database_id)? {
+            match MasterKey::load(&rotation.to_key_name) {
+                Ok(key) => master_keys.push(key),
+                Err(err) => {
+                    klave::notifier::send_string(&format!("Failed to load new master key: {err}"));
+                    return Err(err);
+                }
+            }
+        }
+        Ok(master_keys)
+    }
+
+    // Version of the master key new values are encrypted under, the last of master_keys.
+    pub fn encryption_key_version(&self) -> Result<u32, Box<dyn std::error::Error>> {
+        Ok(match Rotation::load(&self.database_id)? {
+            Some(rotation) => rotation.to_version,
+            None => self.master_key_version,
+        })
+    }
+
+    // Master key of the given version: the current one or the one of a rotation in progress.
+    pub fn master_key_of_version(
+        &self,
+        version: u32,
+    ) -> Result<MasterKey, Box<dyn std::error::Error>> {
+        if version == self.master_key_version {
+            return self.load_master_key();
+        }
+        match Rotation::load(&self.database_id)? {
+            Some(rotation) if rotation.to_version == version => {
+                MasterKey::load(&rotation.to_key_name)
+            }
+            _ => Err(format!("Master key version {version} is no longer in use").into()),
         }
     }
 
-    pub fn get_handle(&self) -> &str {
-        &self.opaque_handle
+    // Loads the master key from the key store.
+    pub fn load_master_key(&self) -> Result<MasterKey, Box<dyn std::error::Error>> {
+        let master_key_name = self
+            .master_key_name
+            .clone()
+            .ok_or("Master key name not set")?;
+        match MasterKey::load(master_key_name.as_str()) {
+            Ok(key) => Ok(key),
+            Err(err) => {
+                klave::notifier::send_string(&format!("Failed to load master key: {err}"));
+                Err(err)
+            }
+        }
+    }
+
+    // Handle of the connection opened by connect.
+    pub fn get_handle(&self) -> Result<String, Box<dyn std::error::Error>> {
+        connection::current(&self.database_id)
     }
 
     // Loads a Client instance from the ledger using the database ID.
@@ -258,20 +461,19 @@ impl Client {
         }
     }
 
+    // Loads a Client instance, checking that the sender owns it.
+    pub fn load_owned(database_id: String) -> Result<Client, Box<dyn std::error::Error>> {
+        let client = Client::load(database_id)?;
+        client.check_sender()?;
+        Ok(client)
+    }
+
     // Saves the master key.
     fn save_master_key(&mut self) -> Result<(), Box<dyn std::error::Error>> {
         // Create master key name
         let master_key_name = hex::encode(klave::crypto::random::get_random_bytes(32)?);
-        // Generate master key
-        let master_key = match generate_ecc_crypto_key() {
-            Ok(key) => key,
-            Err(err) => {
-                klave::notifier::send_string(&format!("Failed to generate master key: {err}"));
-                return Err(err);
-            }
-        };
-        // Store the master key in the ledger
-        match save_key(&master_key, &master_key_name) {
+        // Generate the master key and its HMAC key, and store them in the key store
+        match MasterKey::create(&master_key_name) {
             Ok(_) => (),
             Err(err) => {
                 klave::notifier::send_string(&format!("Failed to save master key: {err}"));
@@ -286,6 +488,11 @@ impl Client {
     pub fn save(&mut self) -> Result<(), Box<dyn std::error::Error>> {
         // Save master key
         self.save_master_key()?;
+        self.save_record()
+    }
+
+    // Saves the Client instance to the ledger, keeping its master key
+    pub fn save_record(&self) -> Result<(), Box<dyn std::error::Error>> {
         // Serialize the Client instance to JSON
         let serialized = serde_json::to_string(self)?;
 
@@ -296,46 +503,93 @@ impl Client {
         Ok(())
     }
 
-    // Constructs the PostgreSQL connection string from the DBInputDetails
-    fn connection_string(&self) -> String {
-        let mut conn_str = format!(
-            "host={} dbname={}",
-            self.db_input_details.host, self.db_input_details.dbname
-        );
-        if !self.db_input_details.user.is_empty() {
-            conn_str.push_str(&format!(" user={}", self.db_input_details.user));
-        }
-        if !self.db_input_details.password.is_empty() {
-            conn_str.push_str(&format!(" password={}", self.db_input_details.password));
-        }
-        conn_str
+    // Constructs the PostgreSQL connection string from the connection settings and the
+    // decrypted credentials, fetching a token first in the Token mode.
+    fn connection_string(&self) -> Result<String, Box<dyn std::error::Error>> {
+        let credentials = self.credentials()?;
+        let token = match &self.db_input_details.auth {
+            AuthMode::Token {
+                endpoint,
+                token_field,
+            } => Some(fetch_token(endpoint, token_field)?),
+            _ => None,
+        };
+        Ok(self
+            .db_input_details
+            .connection_string(&credentials, token.as_deref()))
     }
 
-    // Connects to the PostgreSQL database using the connection string
-    // and stores the opaque handle for further operations.
-    pub fn connect(&mut self) -> Result<(), Box<dyn std::error::Error>> {
-        // Construct the PostgreSQL connection URI
-        let uri = self.connection_string();
+    // Digest of the stored connection settings and credentials, a cached handle is only reused
+    // while it is unchanged.
+    fn fingerprint(&self) -> Result<String, Box<dyn std::error::Error>> {
+        let settings = serde_json::to_string(&self.db_input_details)?;
+        Ok(compute_sha256_hex_string(
+            format!("{settings}{}", self.credentials).as_bytes(),
+        ))
+    }
 
-        // Open the PostgreSQL connection
-        match klave::sql::connection_open(&uri) {
-            Ok(opaque_handle) => {
-                self.opaque_handle = opaque_handle;
-                Ok(())
-            }
-            Err(err) => {
-                klave::notifier::send_string(&format!("Failed to connect to PostgreSQL: {err}"));
-                Err(err)
+    // Connects to the PostgreSQL database, reusing the cached handle of this client if any.
+    pub fn connect(&self) -> Result<(), Box<dyn std::error::Error>> {
+        connection::acquire(&self.database_id, &self.fingerprint()?, || {
+            self.connection_string()
+        })?;
+        Ok(())
+    }
+
+    // Runs a statement on the handle of this client. When it fails on a handle that no longer
+    // answers, reconnects and runs it again, up to MAX_CONNECT_ATTEMPTS times.
+    fn run_with_reconnect<F>(
+        &self,
+        query: &Query,
+        run: F,
+    ) -> Result<String, Box<dyn std::error::Error>>
+    where
+        F: Fn(&str, &str) -> Result<String, Box<dyn std::error::Error>>,
+    {
+        let sql = query.to_sql()?;
+        let mut handle = self.get_handle()?;
+        let mut attempts = 1;
+        loop {
+            match run(&handle, &sql) {
+                Ok(result) => return Ok(result),
+                // Errors on a live handle come from the statement itself
+                Err(err) if attempts >= MAX_CONNECT_ATTEMPTS || connection::is_alive(&handle) => {
+                    return Err(err)
+                }
+                // The transaction of an open cursor is lost with its handle: a new handle would
+                // run the next statements outside of it, so the whole run fails instead
+                Err(err) if connection::is_pinned(&self.database_id) => {
+                    return Err(format!(
+                    "Connection lost while a cursor is open, its transaction is rolled back: {err}"
+                )
+                    .into())
+                }
+                Err(_) => {
+                    attempts += 1;
+                    handle =
+                        connection::reconnect(&self.database_id, &self.fingerprint()?, || {
+                            self.connection_string()
+                        })?;
+                }
             }
         }
     }
 
+    // Connects and runs a trivial query to check the connection settings.
+    pub fn test_connection(&self) -> Result<(), Box<dyn std::error::Error>> {
+        self.connect()?;
+        let mut query = Query::new();
+        query.push("SELECT 1");
+        self.query::<Vec<Vec<Value>>>(&query)?;
+        Ok(())
+    }
+
     // Queries the PostgreSQL database using the provided SQL query, returns a PostGreResponse.
-    pub fn query<T>(&self, query: &str) -> Result<PostGreResponse<T>, Box<dyn std::error::Error>>
+    pub fn query<T>(&self, query: &Query) -> Result<PostGreResponse<T>, Box<dyn std::error::Error>>
     where
         T: for<'de> serde::Deserialize<'de>,
     {
-        match klave::sql::query(&self.opaque_handle, query) {
+        match self.run_with_reconnect(query, klave::sql::query) {
             Ok(result) => {
                 let response = match serde_json::from_str::<PostGreResponse<T>>(&result) {
                     Ok(res) => res,
@@ -353,9 +607,33 @@ impl Client {
         }
     }
 
+    // Queries the PostgreSQL database and maps the rows into T.
+    pub fn query_as<T: FromRow>(
+        &self,
+        query: &Query,
+    ) -> Result<Vec<T>, Box<dyn std::error::Error>> {
+        let response = self.query::<Vec<Vec<Value>>>(query)?;
+        match response.map_rows::<T>() {
+            Ok(rows) => Ok(rows),
+            Err(err) => {
+                klave::notifier::send_string(&format!("Failed to map query result: {err}"));
+                Err(err.into())
+            }
+        }
+    }
+
+    // Opens a server-side cursor over the rows of the query, fetched fetch_size rows at a time.
+    pub fn cursor(
+        &self,
+        query: &Query,
+        fetch_size: usize,
+    ) -> Result<Cursor<'_>, Box<dyn std::error::Error>> {
+        Cursor::declare(self, query, fetch_size)
+    }
+
     // Executes a SQL command on the PostgreSQL database, returns the result as a String.
-    pub fn execute(&self, query: &str) -> Result<String, Box<dyn std::error::Error>> {
-        match klave::sql::execute(&self.opaque_handle, query) {
+    pub fn execute(&self, query: &Query) -> Result<String, Box<dyn std::error::Error>> {
+        match self.run_with_reconnect(query, klave::sql::execute) {
             Ok(result) => Ok(result),
             Err(err) => {
                 klave::notifier::send_string(&format!("Execution failed: {err}"));
@@ -364,8 +642,34 @@ impl Client {
         }
     }
 
-    // Encrypts the specified columns in the given DBTable.
-    pub fn encrypt_columns(&mut self, db_table: DBTable) -> Result<(), Box<dyn std::error::Error>> {
+    // Encrypts the specified columns in the given DBTable. Returns the receipt to record the
+    // encryption in the catalog with mark_columns_encrypted.
+    pub fn encrypt_columns(
+        &mut self,
+        db_table: DBTable,
+    ) -> Result<EncryptionReceipt, Box<dyn std::error::Error>> {
+        // Columns must be in the catalog so that queries know they are encrypted
+        let catalog = Catalog::load(&db_table.database_id)?;
+        for column in db_table.columns.iter() {
+            match catalog.get(&db_table.table, column) {
+                None => {
+                    return Err(format!(
+                        "Column {}.{column} is not registered, call register_encrypted_columns first",
+                        db_table.table
+                    )
+                    .into());
+                }
+                Some(entry) if entry.encrypted_at.is_some() => {
+                    return Err(
+                        format!("Column {}.{column} is already encrypted", db_table.table).into(),
+                    );
+                }
+                Some(_) => (),
+            }
+        }
+        // Read before encrypting, a rotation started meanwhile would change it
+        let key_version = self.encryption_key_version()?;
+
         //for each column name, I retrieve both primary key + data associated to the column to encrypt
         for column in db_table.columns.clone() {
             match self.encrypt_single_column(column.clone(), &db_table) {
@@ -379,7 +683,21 @@ impl Client {
             };
         }
 
-        Ok(())
+        let master_keys = self.master_keys()?;
+        let master_key = master_keys.last().ok_or("Master key not found")?;
+        let data = EncryptionReceipt::mac_data(
+            &db_table.database_id,
+            &db_table.table,
+            &db_table.columns,
+            key_version,
+        )?;
+        Ok(EncryptionReceipt {
+            mac: compute_encryption_mac(&master_key.hmac_key, &db_table.database_id, &data)?,
+            database_id: db_table.database_id,
+            table: db_table.table,
+            columns: db_table.columns,
+            key_version,
+        })
     }
 
     fn encrypt_single_column(
@@ -389,384 +707,489 @@ impl Client {
     ) -> Result<(), Box<dyn std::error::Error>> {
         let table_name = &db_table.table;
         let chunk_size: usize = db_table.chunk_size;
+        let mode = match Catalog::load(&db_table.database_id)?.get(table_name, &column) {
+            Some(entry) => entry.mode,
+            None => return Err(format!("Column {table_name}.{column} is not registered").into()),
+        };
+        if mode == EncryptionMode::BlindIndex {
+            self.add_blind_index_column(table_name, &column)?;
+        }
 
-        // Retrieve the primary key index and the columns to encrypt
-        let answer: PostGreResponse<Vec<Vec<Value>>> =
-            match self.get_column_to_encrypt(&db_table.primary_key, db_table, &column) {
-                Ok(column) => column,
-                Err(err) => {
-                    klave::notifier::send_string(&format!(
-                        "Failed to get columns to encrypt: {err}"
-                    ));
-                    return Err(err);
-                }
-            };
-
-        // Convert resultset
-        let mut processed_rows: Vec<Vec<Value>> = answer.resultset;
+        // Values are encrypted under the key a rotation in progress is moving to, as the
+        // rotation may already have passed this column
+        let master_keys = self.master_keys()?;
+        let master_key = master_keys.last().ok_or("Master key not found")?;
 
-        // Retrieve the master key
-        let master_key_name = self
-            .master_key_name
-            .clone()
-            .ok_or("Master key name not set")?;
-        let master_key = match klave::crypto::subtle::load_key(master_key_name.as_str()) {
-            Ok(key) => key,
+        // Read the primary key and the column to encrypt chunk by chunk, so that at most
+        // chunk_size rows are held at once. The updates are committed when the cursor is closed.
+        let query = self.column_to_encrypt_query(&db_table.primary_key, db_table, &column)?;
+        let mut cursor = match self.cursor(&query, chunk_size) {
+            Ok(cursor) => cursor,
             Err(err) => {
-                klave::notifier::send_string(&format!("Failed to load master key: {err}"));
+                klave::notifier::send_string(&format!("Failed to get columns to encrypt: {err}"));
                 return Err(err);
             }
         };
-
-        // Parse processed rows and encrypt specific column
-        for row in processed_rows.iter_mut() {
-            //the column to encrypt is the second one (index 1)
-            let value = match row.get_mut(1) {
-                Some(item) => item,
-                None => {
-                    klave::notifier::send_string(&format!("Missing column: {column}"));
-                    return Err(format!("Missing column: {column}").into());
+        let mut chunks = 0;
+        let mut checked = false;
+        while let Some(chunk) = cursor.fetch()? {
+            // Columns are encrypted all at once, when the cursor commits: if the first value is
+            // a ciphertext, the column was encrypted by a call whose receipt was not recorded yet
+            if !checked {
+                let first = chunk
+                    .rows()
+                    .map(|row| row.get::<Option<String>>(&column))
+                    .find(|value| !matches!(value, Ok(None)));
+                if let Some(Ok(Some(value))) = &first {
+                    if is_ciphertext(&master_keys, table_name, &column, value)? {
+                        return Err(format!(
+                            "Column {table_name}.{column} is already encrypted, call mark_columns_encrypted with the receipt of its encryption"
+                        )
+                        .into());
+                    }
                 }
-            };
+                checked = first.is_some();
+            }
+            let mut columns = vec![column.clone()];
+            if mode == EncryptionMode::BlindIndex {
+                columns.push(blind_index_column(&column));
+            }
 
-            let iv_encrypted_value = match encrypt_value(
-                &master_key,
-                table_name.to_string(),
-                column.clone(),
-                value.clone(),
-            ) {
-                Ok(enc_value) => enc_value,
-                Err(err) => {
-                    klave::notifier::send_string(&format!("Failed to encrypt value: {err}"));
-                    return Err(err);
+            // Rows of the primary key, the encrypted value and its blind index
+            let mut processed_rows: Vec<Vec<Value>> = Vec::new();
+            for row in chunk.rows() {
+                let primary_key = row.get::<Value>(&db_table.primary_key)?;
+                let plain_value = match row.get::<Value>(&column) {
+                    Ok(value) => value,
+                    Err(err) => {
+                        klave::notifier::send_string(&format!("Failed to read {column}: {err}"));
+                        return Err(err.into());
+                    }
+                };
+                let iv_encrypted_value = match encrypt_value_with_mode(
+                    &master_key.key,
+                    table_name.to_string(),
+                    column.clone(),
+                    plain_value.clone(),
+                    mode,
+                ) {
+                    Ok(enc_value) => enc_value,
+                    Err(err) => {
+                        klave::notifier::send_string(&format!("Failed to encrypt value: {err}"));
+                        return Err(err);
+                    }
+                };
+                let mut processed_row = vec![primary_key, Value::String(iv_encrypted_value)];
+
+                // Blind index of the plaintext, written to its own column
+                if mode == EncryptionMode::BlindIndex {
+                    let blind_index = match compute_blind_index(
+                        &master_key.hmac_key,
+                        table_name.to_string(),
+                        column.clone(),
+                        plain_value,
+                    ) {
+                        Ok(index) => index,
+                        Err(err) => {
+                            klave::notifier::send_string(&format!(
+                                "Failed to compute blind index: {err}"
+                            ));
+                            return Err(err);
+                        }
+                    };
+                    processed_row.push(Value::String(blind_index));
                 }
-            };
+                processed_rows.push(processed_row);
+            }
 
-            //update the value with the encrypted value
-            *value = serde_json::Value::String(iv_encrypted_value);
+            let query = self.build_update_query(
+                &db_table.primary_key,
+                &columns,
+                processed_rows,
+                table_name,
+            )?;
+            if let Err(err) = self.execute(&query) {
+                klave::notifier::send_string(&format!("Failed to update: {err}"));
+                return Err(err);
+            }
+            chunks += 1;
         }
 
-        match self.update(
-            processed_rows,
-            answer.fields.clone(),
-            table_name.clone(),
-            chunk_size,
-            column,
-        ) {
+        match cursor.close() {
             Ok(_) => {
                 klave::notifier::send_string(&format!(
-                    "Table {} successfully encrypted",
-                    table_name.clone()
+                    "Column {column} of table {table_name} has been encrypted in {chunks} chunks"
                 ));
             }
             Err(err) => {
-                klave::notifier::send_string(&format!("Failed to update: {err}"));
+                klave::notifier::send_string(&format!("Failed to commit the encryption: {err}"));
                 return Err(err);
             }
         };
         Ok(())
     }
 
-    fn get_column_to_encrypt(
+    // Adds the column holding the blind indexes of `column`, if missing.
+    fn add_blind_index_column(
         &self,
-        primary_key_field: &String,
-        db_table: &DBTable,
-        column: &String,
-    ) -> Result<PostGreResponse<Vec<Vec<Value>>>, Box<dyn std::error::Error>> {
-        // Build the query to retrieve the primary key and column to encrypt
-        let query = format!(
-            "SELECT {},{} FROM {} ORDER BY {}",
-            primary_key_field, column, db_table.table, primary_key_field
-        );
-        let result = match self.query::<Vec<Vec<Value>>>(&query) {
-            Ok(response) => response,
+        table: &str,
+        column: &str,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let mut query = Query::new();
+        query.push("ALTER TABLE ");
+        query.push_qualified_name(table)?;
+        query.push(" ADD COLUMN IF NOT EXISTS ");
+        query.push_identifier(&blind_index_column(column))?;
+        query.push(" text");
+        match self.execute(&query) {
+            Ok(_) => Ok(()),
             Err(err) => {
-                klave::notifier::send_string(&format!(
-                    "Failed to get the column to encrypt: {err}"
-                ));
-                return Err(err);
+                klave::notifier::send_string(&format!("Failed to add blind index column: {err}"));
+                Err(err)
             }
-        };
-        Ok(result)
+        }
     }
 
-    fn update(
+    // Query of the primary key and the column to encrypt, by primary key.
+    fn column_to_encrypt_query(
         &self,
-        processed_rows: Vec<Vec<Value>>,
-        fields: Vec<Field>,
-        table: String,
-        chunk_size: usize,
-        column_name: String,
-    ) -> Result<(), Box<dyn std::error::Error>> {
-        if processed_rows.len() <= chunk_size {
-            let query = self.build_update_query(processed_rows.clone(), fields, table.clone())?;
-            // Execute the update
-            match self.execute(&query) {
-                Ok(_) => {
-                    klave::notifier::send_string(&format!(
-                        "Column {column_name} of table {table} has been encrypted"
-                    ));
-                }
-                Err(err) => {
-                    klave::notifier::send_string(&format!("Failed to encrypt: {err}"));
-                }
-            };
-        } else {
-            let division_by_chunk: usize = processed_rows.len() / chunk_size;
-            let remaining: usize = processed_rows.len() - division_by_chunk * chunk_size;
-
-            for i in 0..division_by_chunk {
-                let query = self.build_update_query(
-                    processed_rows[i * chunk_size..i * chunk_size + chunk_size].to_vec(),
-                    fields.clone(),
-                    table.clone(),
-                )?;
-                // Execute the update
-                match self.execute(&query) {
-                    Ok(_) => {
-                        klave::notifier::send_string(&format!(
-                            "Chunk {i} of column {column_name} of table {table} has been encrypted"
-                        ));
-                    }
-                    Err(err) => {
-                        klave::notifier::send_string(&format!("Failed to encrypt: {err}"));
-                    }
-                };
-            }
-            if remaining > 0 {
-                let query = self.build_update_query(
-                    processed_rows[division_by_chunk * chunk_size
-                        ..division_by_chunk * chunk_size + remaining]
-                        .to_vec(),
-                    fields.clone(),
-                    table.clone(),
-                )?;
-                // Execute the update
-                match self.execute(&query) {
-                    Ok(_) => {
-                        klave::notifier::send_string(&format!("Last chunk {division_by_chunk} of column {column_name} of table {table} has been encrypted"));
-                    }
-                    Err(err) => {
-                        klave::notifier::send_string(&format!("Failed to encrypt: {err}"));
-                    }
-                };
-            }
-        }
-        Ok(())
+        primary_key_field: &str,
+        db_table: &DBTable,
+        column: &str,
+    ) -> Result<Query, Box<dyn std::error::Error>> {
+        let mut query = Query::new();
+        query.push("SELECT ");
+        query.push_identifiers(&[primary_key_field.to_string(), column.to_string()])?;
+        query.push(" FROM ");
+        query.push_qualified_name(&db_table.table)?;
+        query.push(" ORDER BY ");
+        query.push_identifier(primary_key_field)?;
+        Ok(query)
     }
 
+    // Updates `columns` of the rows of `table` with the given primary keys. Each row holds the
+    // primary key followed by the values of `columns`.
     fn build_update_query(
         &self,
+        primary_key: &str,
+        columns: &[String],
         processed_rows: Vec<Vec<Value>>,
-        fields: Vec<Field>,
-        table: String,
-    ) -> Result<String, Box<dyn std::error::Error>> {
+        table: &str,
+    ) -> Result<Query, Box<dyn std::error::Error>> {
         // Iterate over the processed rows and build the update query
         if processed_rows.is_empty() {
             return Err("No rows to update".into());
         }
-        // Primary key field
-        let pk = &fields[0].name;
-        // Retrieve the column names from the fields
-        let column_names: Vec<String> = fields.iter().map(|f| f.name.clone()).collect();
-        // All columns names
-        let all_columns = column_names.join(",");
+        let pk = primary_key;
+        let column_names: Vec<String> = std::iter::once(pk.to_string())
+            .chain(columns.iter().cloned())
+            .collect();
         // Build the update query
-        let mut query = format!("WITH new_values ({all_columns}) AS (VALUES ");
+        let mut query = Query::new();
+        query.push("WITH new_values (");
+        query.push_identifiers(&column_names)?;
+        query.push(") AS (VALUES ");
         // List all new values
-        query.push_str(flatten_vec_of_vec_values_to_single_string(processed_rows).as_str());
+        for (i, row) in processed_rows.into_iter().enumerate() {
+            if i > 0 {
+                query.push(",");
+            }
+            query.push("(");
+            query.push_params(row);
+            query.push(")");
+        }
         // Update
-        query.push_str(&format!(") UPDATE {table} SET "));
+        query.push(") UPDATE ");
+        query.push_qualified_name(table)?;
+        query.push(" SET ");
         // Update query
         for (i, column_name) in column_names.iter().enumerate() {
             if i == 0 {
                 continue;
             }
-            query.push_str(&format!("{column_name} = new_values.{column_name}"));
+            query.push_identifier(column_name)?;
+            query.push(" = new_values.");
+            query.push_identifier(column_name)?;
             if i < column_names.len() - 1 {
-                query.push_str(", ");
+                query.push(", ");
             }
         }
-        query.push_str(&format!(
-            " FROM new_values WHERE {table}.{pk} = new_values.{pk}"
-        ));
+        query.push(" FROM new_values WHERE ");
+        query.push_qualified_name(table)?;
+        query.push(".");
+        query.push_identifier(pk)?;
+        query.push(" = new_values.");
+        query.push_identifier(pk)?;
 
         Ok(query)
     }
 
+    // Reads rows of a table, decrypting the columns encrypted through encrypt_columns.
+    pub fn read_decrypted_rows(
+        &self,
+        input: &ReadDecryptedRowsInput,
+    ) -> Result<PostGreResponse<Vec<Vec<Value>>>, Box<dyn std::error::Error>> {
+        let mut query = Query::new();
+        query.push("SELECT ");
+        if input.columns.is_empty() {
+            query.push("*");
+        } else {
+            query.push_identifiers(&input.columns)?;
+        }
+        query.push(" FROM ");
+        query.push_qualified_name(&input.table)?;
+        if let Some(limit) = input.limit {
+            query.push(" LIMIT ");
+            query.push_param(Value::from(limit));
+        }
+        let mut result = self.query::<Vec<Vec<Value>>>(&query)?;
+
+        // The catalog is authoritative, callers cannot mark columns as encrypted or plain
+        let encrypted_columns: Vec<String> = Catalog::load(&self.database_id)?
+            .encrypted_columns(&[input.table.as_str()])
+            .into_iter()
+            .map(|c| c.column)
+            .collect();
+        let master_keys = self.master_keys()?;
+        let columns: Vec<String> = result.fields.iter().map(|f| f.name.clone()).collect();
+        for (i, column) in columns.iter().enumerate() {
+            if encrypted_columns.contains(column) {
+                decrypt_result_column(&master_keys, &mut result, i, &input.table, column)?;
+            }
+        }
+        Ok(result)
+    }
+
+    // Runs a query described by a QuerySpec, decrypting the selected encrypted columns.
+    pub fn run_query(
+        &self,
+        spec: &QuerySpec,
+    ) -> Result<PostGreResponse<Vec<Vec<Value>>>, Box<dyn std::error::Error>> {
+        let mut spec = spec.clone();
+        let tables: Vec<&str> = std::iter::once(spec.table.as_str())
+            .chain(spec.joins.iter().map(|j| j.table.as_str()))
+            .collect();
+        spec.encrypted_columns = Catalog::load(&self.database_id)?.encrypted_columns(&tables);
+        let master_keys = self.master_keys()?;
+        let planned = spec.build(|column: &EncryptedColumn, value: Value| {
+            search_tokens(&master_keys, column, value)
+        })?;
+        let mut result = self.query::<Vec<Vec<Value>>>(&planned.query)?;
+        for (i, encrypted) in planned.decrypt.iter().enumerate() {
+            if let Some(column) = encrypted {
+                decrypt_result_column(&master_keys, &mut result, i, &column.table, &column.column)?;
+            }
+        }
+        Ok(result)
+    }
+
+    // Re-encrypts under the new master key the next chunk of rows of a rotation in progress,
+    // returns the checkpoint to commit and the number of rows re-encrypted.
+    pub fn reencrypt_next_chunk(
+        &self,
+        rotation: &Rotation,
+    ) -> Result<(Checkpoint, usize), Box<dyn std::error::Error>> {
+        let catalog = Catalog::load(&self.database_id)?;
+        let checkpoint = &rotation.checkpoint;
+        let Some(entry) = catalog.entries.get(checkpoint.entry) else {
+            return Ok((checkpoint.clone(), 0));
+        };
+        // Registered during the rotation and not encrypted yet, there is nothing to re-encrypt.
+        // Once encrypted, it is under the new key.
+        if entry.encrypted_at.is_none() {
+            return Ok((checkpoint.advance(0, rotation.chunk_size, None), 0));
+        }
+
+        // Next rows of the column, by primary key
+        let mut query = Query::new();
+        query.push("SELECT ");
+        query.push_identifiers(&[entry.primary_key.clone(), entry.column.clone()])?;
+        query.push(" FROM ");
+        query.push_qualified_name(&entry.table)?;
+        if let Some(last_primary_key) = &checkpoint.last_primary_key {
+            query.push(" WHERE ");
+            query.push_identifier(&entry.primary_key)?;
+            query.push(" > ");
+            query.push_param(last_primary_key.clone());
+        }
+        query.push(" ORDER BY ");
+        query.push_identifier(&entry.primary_key)?;
+        query.push(" LIMIT ");
+        query.push_param(Value::from(rotation.chunk_size));
+        let answer = self.query::<Vec<Vec<Value>>>(&query)?;
+        let mut columns = vec![entry.column.clone()];
+        if entry.mode == EncryptionMode::BlindIndex {
+            columns.push(blind_index_column(&entry.column));
+        }
+
+        // Values are decrypted with either key, so that a chunk can be re-encrypted again
+        let master_keys = self.master_keys()?;
+        let new_master_key = master_keys.last().ok_or("New master key not found")?;
+        let mut processed_rows: Vec<Vec<Value>> = Vec::new();
+        let mut last_primary_key = None;
+        for row in answer.rows() {
+            let primary_key = row.get::<Value>(&entry.primary_key)?;
+            last_primary_key = Some(primary_key.clone());
+            // Ciphertexts are hex strings, NULL values were not encrypted
+            let Some(iv_encrypted_value) = row.get::<Option<String>>(&entry.column)? else {
+                let mut processed_row = vec![primary_key, Value::Null];
+                if entry.mode == EncryptionMode::BlindIndex {
+                    processed_row.push(Value::Null);
+                }
+                processed_rows.push(processed_row);
+                continue;
+            };
+            let decrypted = decrypt_value(
+                &master_keys,
+                entry.table.clone(),
+                entry.column.clone(),
+                &iv_encrypted_value,
+            )?;
+            let mut processed_row = vec![
+                primary_key,
+                Value::String(encrypt_value_with_mode(
+                    &new_master_key.key,
+                    entry.table.clone(),
+                    entry.column.clone(),
+                    decrypted.clone(),
+                    entry.mode,
+                )?),
+            ];
+            if entry.mode == EncryptionMode::BlindIndex {
+                processed_row.push(Value::String(compute_blind_index(
+                    &new_master_key.hmac_key,
+                    entry.table.clone(),
+                    entry.column.clone(),
+                    decrypted,
+                )?));
+            }
+            processed_rows.push(processed_row);
+        }
+
+        let rows = processed_rows.len();
+        if rows > 0 {
+            let query = self.build_update_query(
+                &entry.primary_key,
+                &columns,
+                processed_rows,
+                &entry.table,
+            )?;
+            self.execute(&query)?;
+        }
+        Ok((
+            checkpoint.advance(rows, rotation.chunk_size, last_primary_key),
+            rows,
+        ))
+    }
+
     pub fn build_encrypted_query(
         &self,
         input: ReadEncryptedTableInput,
-    ) -> Result<String, Box<dyn std::error::Error>> {
+    ) -> Result<Query, Box<dyn std::error::Error>> {
         let table = input.table;
         let column = input.encrypted_column;
-        let mut values = input.values;
-        let mut query = "".to_string();
-
-        // Retrieve the master key
-        let master_key_name = self
-            .master_key_name
-            .clone()
-            .ok_or("Master key name not set")?;
-        let master_key = match klave::crypto::subtle::load_key(master_key_name.as_str()) {
-            Ok(key) => key,
-            Err(err) => {
-                klave::notifier::send_string(&format!("Failed to load master key: {err}"));
-                return Err(err);
-            }
+        let mode = match Catalog::load(&self.database_id)?.get(&table, &column) {
+            Some(entry) => entry.mode,
+            None => return Err(format!("Column {table}.{column} is not registered").into()),
+        };
+        let encrypted_column = EncryptedColumn {
+            table: table.clone(),
+            column: column.clone(),
+            mode,
         };
 
-        for value in values.iter_mut() {
+        let master_keys = self.master_keys()?;
+        let mut tokens = Vec::new();
+        for value in input.values {
             // Reuse serde to be in line with encryption
-            let serde_value = serde_json::Value::String(value.clone());
-
-            let iv_encrypted_value = match encrypt_value(
-                &master_key,
-                table.clone(),
-                column.clone(),
-                serde_value.clone(),
-            ) {
-                Ok(enc_value) => enc_value,
+            let serde_value = serde_json::Value::String(value);
+            match search_tokens(&master_keys, &encrypted_column, serde_value) {
+                Ok(values) => tokens.extend(values.into_iter().map(Value::String)),
                 Err(err) => {
                     klave::notifier::send_string(&format!("Failed to encrypt value: {err}"));
                     return Err(err);
                 }
             };
-            //replace in value
-            *value = iv_encrypted_value;
         }
 
-        let list_values = format!(
-            "({})",
-            values
-                .iter()
-                .map(|s| format!("'{s}'"))
-                .collect::<Vec<String>>() // Collect directly into Vec<String>
-                .join(",") // Join the collected Vec
-        );
-
-        query.push_str(&format!(
-            "SELECT * FROM {table} WHERE {column} in {list_values}"
-        ));
+        let mut query = Query::new();
+        query.push("SELECT * FROM ");
+        query.push_qualified_name(&table)?;
+        query.push(" WHERE ");
+        if mode == EncryptionMode::BlindIndex {
+            query.push_identifier(&blind_index_column(&column))?;
+        } else {
+            query.push_identifier(&column)?;
+        }
+        query.push(" in (");
+        query.push_params(tokens);
+        query.push(")");
 
         Ok(query)
     }
+}
 
-    pub fn build_encrypted_query_per_user(
-        &self,
-        input: &ReadEncryptedTablePerUserInput,
-    ) -> Result<EncryptedQueryWithEncryptedUser, Box<dyn std::error::Error>> {
-        let table = &input.table;
-        //retrieve first name and last name and trim them
-        let first_name = &input.first_name.trim().to_string();
-        let last_name = &input.last_name.trim().to_string();
-
-        // Retrieve the master key
-        let master_key_name = self
-            .master_key_name
-            .clone()
-            .ok_or("Master key name not set")?;
-        let master_key = match klave::crypto::subtle::load_key(master_key_name.as_str()) {
-            Ok(key) => key,
-            Err(err) => {
-                klave::notifier::send_string(&format!("Failed to load master key: {err}"));
-                return Err(err);
-            }
-        };
-
-        // Recompute first name encrypted value
-        // Reuse serde to be in line with encryption
-        let serde_value_first_name = serde_json::Value::String(first_name.clone());
-
-        let iv_encrypted_value_first_name = match encrypt_value(
-            &master_key,
-            table.clone(),
-            "first_name".to_string(),
-            serde_value_first_name.clone(),
-        ) {
-            Ok(enc_value) => enc_value,
-            Err(err) => {
-                klave::notifier::send_string(&format!("Failed to encrypt value: {err}"));
-                return Err(err);
+// Values searched for a value of an encrypted column, one per master key: its deterministic
+// ciphertext, or its blind index. Randomized columns cannot be searched.
+fn search_tokens(
+    master_keys: &[MasterKey],
+    column: &EncryptedColumn,
+    value: Value,
+) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    master_keys
+        .iter()
+        .map(|master_key| match column.mode {
+            EncryptionMode::Deterministic => encrypt_value(
+                &master_key.key,
+                column.table.clone(),
+                column.column.clone(),
+                value.clone(),
+            ),
+            EncryptionMode::BlindIndex => compute_blind_index(
+                &master_key.hmac_key,
+                column.table.clone(),
+                column.column.clone(),
+                value.clone(),
+            ),
+            EncryptionMode::Randomized => {
+                Err(format!("Randomized column {} cannot be searched", column.column).into())
             }
-        };
-
-        // Recompute first name encrypted value
-        // Reuse serde to be in line with encryption
-        let serde_value_last_name = serde_json::Value::String(last_name.clone());
+        })
+        .collect()
+}
 
-        let iv_encrypted_value_last_name = match encrypt_value(
-            &master_key,
-            table.clone(),
-            "last_name".to_string(),
-            serde_value_last_name.clone(),
-        ) {
-            Ok(enc_value) => enc_value,
-            Err(err) => {
-                klave::notifier::send_string(&format!("Failed to encrypt value: {err}"));
-                return Err(err);
-            }
+// Decrypts in place the values of the column at `index` of a query result.
+fn decrypt_result_column(
+    master_keys: &[MasterKey],
+    result: &mut PostGreResponse<Vec<Vec<Value>>>,
+    index: usize,
+    table: &str,
+    column: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    for row in result.resultset.iter_mut() {
+        let Some(value) = row.get_mut(index) else {
+            return Err(format!("Missing column: {column}").into());
         };
-
-        let query: String = format!("select u.first_name, u.last_name, pu.purchase_date, pr.product_name, pr.category, pr.brand, pr.description, pr.price from users as u \
-            inner join purchases as pu on pu.user_id = u.id \
-            inner join products as pr on pr.id = pu.product_id \
-            where u.first_name = '{iv_encrypted_value_first_name}' and u.last_name = '{iv_encrypted_value_last_name}'");
-
-        let res = EncryptedQueryWithEncryptedUser {
-            query,
-            first_name_encryption: iv_encrypted_value_first_name,
-            last_name_encryption: iv_encrypted_value_last_name,
+        // Ciphertexts are hex strings, other values were not encrypted
+        let Some(iv_encrypted_value) = value.as_str() else {
+            continue;
         };
-
-        Ok(res)
-    }
-
-    pub fn build_encrypted_query_per_gender(
-        &self,
-        gender: &String,
-    ) -> Result<String, Box<dyn std::error::Error>> {
-        // Retrieve the master key
-        let master_key_name = self
-            .master_key_name
-            .clone()
-            .ok_or("Master key name not set")?;
-        let master_key = match klave::crypto::subtle::load_key(master_key_name.as_str()) {
-            Ok(key) => key,
-            Err(err) => {
-                klave::notifier::send_string(&format!("Failed to load master key: {err}"));
-                return Err(err);
-            }
-        };
-
-        // Encrypted query
-        // Reuse serde to be in line with encryption
-        let serde_value_gender = serde_json::Value::String(gender.to_string());
-
-        let iv_encrypted_value_gender = match encrypt_value(
-            &master_key,
-            "users".to_string(),
-            "gender".to_string(),
-            serde_value_gender.clone(),
+        *value = match decrypt_value(
+            master_keys,
+            table.to_string(),
+            column.to_string(),
+            iv_encrypted_value,
         ) {
-            Ok(enc_value) => enc_value,
+            Ok(decrypted) => decrypted,
             Err(err) => {
-                klave::notifier::send_string(&format!("Failed to encrypt value: {err}"));
+                klave::notifier::send_string(&format!("Failed to decrypt column {column}: {err}"));
                 return Err(err);
             }
         };
-
-        // Query
-        let query = format!(
-            "SELECT avg(u.age) FROM users as u \
-            INNER JOIN purchases AS pu ON pu.user_id = u.id \
-            INNER JOIN products AS pr ON pr.id = pu.product_id \
-            WHERE pu.total_price > 300 AND u.gender = '{iv_encrypted_value_gender}'"
-        );
-
-        Ok(query)
     }
+    Ok(())
 }
+
 #[cfg(test)]
 mod tests {
     use std::collections::HashMap;
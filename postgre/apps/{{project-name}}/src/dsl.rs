@@ -0,0 +1,642 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::catalog::{blind_index_column, EncryptionMode};
+use crate::query::Query;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct QuerySpec {
+    pub database_id: String,
+    pub table: String,
+    pub alias: Option<String>,
+    #[serde(default)]
+    pub joins: Vec<Join>,
+    pub select: Vec<SelectItem>,
+    #[serde(default)]
+    pub filters: Vec<Filter>,
+    #[serde(default)]
+    pub group_by: Vec<String>,
+    #[serde(default)]
+    pub order_by: Vec<OrderBy>,
+    pub limit: Option<u64>,
+    // Columns encrypted through encrypt_columns, always read from the catalog by run_query
+    #[serde(skip)]
+    pub encrypted_columns: Vec<EncryptedColumn>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum JoinKind {
+    #[default]
+    Inner,
+    Left,
+}
+
+// Joins `table` on `left = right`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Join {
+    pub table: String,
+    pub alias: Option<String>,
+    #[serde(default)]
+    pub kind: JoinKind,
+    pub left: String,
+    pub right: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Aggregate {
+    Count,
+    Sum,
+    Avg,
+    Min,
+    Max,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SelectItem {
+    // Column, optionally qualified by a table or alias, or "*" for count
+    pub column: String,
+    pub aggregate: Option<Aggregate>,
+    #[serde(rename = "as")]
+    pub alias: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Op {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    In,
+    Like,
+    IsNull,
+    IsNotNull,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Filter {
+    pub column: String,
+    pub op: Op,
+    // Array of values for `in`, ignored for `is_null` and `is_not_null`
+    #[serde(default)]
+    pub value: Value,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OrderBy {
+    pub column: String,
+    #[serde(default)]
+    pub desc: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct EncryptedColumn {
+    pub table: String,
+    pub column: String,
+    #[serde(default)]
+    pub mode: EncryptionMode,
+}
+
+// Query built from a QuerySpec, with the encrypted column behind each selected column.
+#[derive(Debug, Clone)]
+pub struct PlannedQuery {
+    pub query: Query,
+    pub decrypt: Vec<Option<EncryptedColumn>>,
+}
+
+// Column reference split by QuerySpec::split_column.
+struct ColumnRef<'a> {
+    table: Option<&'a str>,
+    qualifier: Option<&'a str>,
+    name: &'a str,
+}
+
+impl Op {
+    fn sql(&self) -> &'static str {
+        match self {
+            Op::Eq => " = ",
+            Op::Ne => " <> ",
+            Op::Lt => " < ",
+            Op::Le => " <= ",
+            Op::Gt => " > ",
+            Op::Ge => " >= ",
+            Op::In => " IN (",
+            Op::Like => " LIKE ",
+            Op::IsNull => " IS NULL",
+            Op::IsNotNull => " IS NOT NULL",
+        }
+    }
+}
+
+impl Aggregate {
+    fn sql(&self) -> &'static str {
+        match self {
+            Aggregate::Count => "count",
+            Aggregate::Sum => "sum",
+            Aggregate::Avg => "avg",
+            Aggregate::Min => "min",
+            Aggregate::Max => "max",
+        }
+    }
+}
+
+impl QuerySpec {
+    // Tables of the query with their alias, the main table first.
+    fn tables(&self) -> impl Iterator<Item = (&String, &Option<String>)> {
+        std::iter::once((&self.table, &self.alias))
+            .chain(self.joins.iter().map(|j| (&j.table, &j.alias)))
+    }
+
+    // Splits a column reference into its qualifier, which must be a table or an alias of the
+    // query, and the column name. Unqualified names are taken whole.
+    fn split_column<'a>(
+        &'a self,
+        column: &'a str,
+    ) -> Result<ColumnRef<'a>, Box<dyn std::error::Error>> {
+        let Some((qualifier, name)) = column.rsplit_once('.') else {
+            return Ok(ColumnRef {
+                table: None,
+                qualifier: None,
+                name: column,
+            });
+        };
+        self.tables()
+            .find(|(table, alias)| alias.as_deref() == Some(qualifier) || *table == qualifier)
+            .map(|(table, _)| ColumnRef {
+                table: Some(table),
+                qualifier: Some(qualifier),
+                name,
+            })
+            .ok_or_else(|| format!("Unknown table or alias in column {column}").into())
+    }
+
+    // Resolves a column reference to the table it belongs to and the column name, the mode
+    // is left to its default. An unqualified name is looked up in the encrypted columns of
+    // every table of the query, and belongs to `table` when none has it.
+    fn resolve(&self, column: &str) -> Result<EncryptedColumn, Box<dyn std::error::Error>> {
+        let reference = self.split_column(column)?;
+        let table = match reference.table {
+            Some(table) => table.to_string(),
+            None => {
+                let mut owners: Vec<&String> = self
+                    .tables()
+                    .map(|(table, _)| table)
+                    .filter(|table| {
+                        self.encrypted_columns
+                            .iter()
+                            .any(|c| &c.table == *table && c.column == reference.name)
+                    })
+                    .collect();
+                owners.sort();
+                owners.dedup();
+                match owners.as_slice() {
+                    [] => self.table.clone(),
+                    [table] => table.to_string(),
+                    _ => {
+                        return Err(format!(
+                            "Column {column} is ambiguous, qualify it with a table or alias"
+                        )
+                        .into())
+                    }
+                }
+            }
+        };
+        Ok(EncryptedColumn {
+            table,
+            column: reference.name.to_string(),
+            mode: EncryptionMode::default(),
+        })
+    }
+
+    // Appends a column reference, quoting its qualifier and name separately.
+    fn push_column(
+        &self,
+        query: &mut Query,
+        column: &str,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let column = self.split_column(column)?;
+        query.push_column(column.qualifier, column.name)?;
+        Ok(())
+    }
+
+    // Appends the blind index column of a column reference.
+    fn push_blind_index_column(
+        &self,
+        query: &mut Query,
+        column: &str,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let column = self.split_column(column)?;
+        query.push_column(column.qualifier, &blind_index_column(column.name))?;
+        Ok(())
+    }
+
+    fn is_encrypted(
+        &self,
+        column: &str,
+    ) -> Result<Option<EncryptedColumn>, Box<dyn std::error::Error>> {
+        if column == "*" {
+            return Ok(None);
+        }
+        let resolved = self.resolve(column)?;
+        Ok(self
+            .encrypted_columns
+            .iter()
+            .find(|c| c.table == resolved.table && c.column == resolved.column)
+            .cloned())
+    }
+
+    fn push_table(
+        query: &mut Query,
+        table: &str,
+        alias: &Option<String>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        query.push_qualified_name(table)?;
+        if let Some(alias) = alias {
+            query.push(" AS ");
+            query.push_identifier(alias)?;
+        }
+        Ok(())
+    }
+
+    // Builds the query. Equality filters on encrypted columns are rewritten with `encrypt`,
+    // which returns the search tokens of a value for a table and column, one per master key in
+    // use: deterministic ciphertexts, or blind indexes for `blind_index` columns.
+    pub fn build<F>(&self, encrypt: F) -> Result<PlannedQuery, Box<dyn std::error::Error>>
+    where
+        F: Fn(&EncryptedColumn, Value) -> Result<Vec<String>, Box<dyn std::error::Error>>,
+    {
+        if self.select.is_empty() {
+            return Err("At least one column must be selected".into());
+        }
+        let mut query = Query::new();
+        let mut decrypt = Vec::new();
+
+        query.push("SELECT ");
+        for (i, item) in self.select.iter().enumerate() {
+            if i > 0 {
+                query.push(", ");
+            }
+            let encrypted = self.is_encrypted(&item.column)?;
+            match item.aggregate {
+                Some(aggregate) => {
+                    if encrypted.is_some() && aggregate != Aggregate::Count {
+                        return Err(format!(
+                            "Cannot compute {} on encrypted column {}",
+                            aggregate.sql(),
+                            item.column
+                        )
+                        .into());
+                    }
+                    query.push(aggregate.sql());
+                    query.push("(");
+                    if item.column == "*" {
+                        query.push("*");
+                    } else {
+                        self.push_column(&mut query, &item.column)?;
+                    }
+                    query.push(")");
+                    decrypt.push(None);
+                }
+                None => {
+                    if item.column == "*" {
+                        return Err("'*' can only be selected with count".into());
+                    }
+                    self.push_column(&mut query, &item.column)?;
+                    decrypt.push(encrypted);
+                }
+            }
+            if let Some(alias) = &item.alias {
+                query.push(" AS ");
+                query.push_identifier(alias)?;
+            }
+        }
+
+        query.push(" FROM ");
+        Self::push_table(&mut query, &self.table, &self.alias)?;
+        for join in &self.joins {
+            query.push(match join.kind {
+                JoinKind::Inner => " INNER JOIN ",
+                JoinKind::Left => " LEFT JOIN ",
+            });
+            Self::push_table(&mut query, &join.table, &join.alias)?;
+            query.push(" ON ");
+            self.push_column(&mut query, &join.left)?;
+            query.push(" = ");
+            self.push_column(&mut query, &join.right)?;
+        }
+
+        for (i, filter) in self.filters.iter().enumerate() {
+            query.push(if i == 0 { " WHERE " } else { " AND " });
+            let encrypted = self.is_encrypted(&filter.column)?;
+            if let Some(column) = &encrypted {
+                let searched = matches!(filter.op, Op::Eq | Op::Ne | Op::In);
+                if searched && !column.mode.is_searchable() {
+                    return Err(format!(
+                        "Randomized column {} cannot be filtered on",
+                        filter.column
+                    )
+                    .into());
+                }
+                // Blind index columns are searched through their index column
+                if searched && column.mode == EncryptionMode::BlindIndex {
+                    self.push_blind_index_column(&mut query, &filter.column)?;
+                } else {
+                    self.push_column(&mut query, &filter.column)?;
+                }
+            } else {
+                self.push_column(&mut query, &filter.column)?;
+            }
+            match (filter.op, &encrypted) {
+                (Op::IsNull | Op::IsNotNull, _) => {
+                    query.push(filter.op.sql());
+                }
+                (Op::In, _) => {
+                    let values = match &filter.value {
+                        Value::Array(values) if !values.is_empty() => values,
+                        _ => {
+                            return Err(format!(
+                                "Filter 'in' on {} expects a non empty array",
+                                filter.column
+                            )
+                            .into())
+                        }
+                    };
+                    let mut params = Vec::new();
+                    for value in values {
+                        match &encrypted {
+                            Some(column) => params.extend(
+                                encrypt(column, value.clone())?
+                                    .into_iter()
+                                    .map(Value::String),
+                            ),
+                            None => params.push(value.clone()),
+                        }
+                    }
+                    query.push(filter.op.sql());
+                    query.push_params(params);
+                    query.push(")");
+                }
+                (Op::Eq | Op::Ne, Some(column)) => {
+                    let mut ciphertexts: Vec<Value> = encrypt(column, filter.value.clone())?
+                        .into_iter()
+                        .map(Value::String)
+                        .collect();
+                    if ciphertexts.len() == 1 {
+                        query.push(filter.op.sql());
+                        query.push_param(ciphertexts.remove(0));
+                    } else {
+                        // The value may be encrypted under any of the master keys
+                        query.push(if filter.op == Op::Eq {
+                            " IN ("
+                        } else {
+                            " NOT IN ("
+                        });
+                        query.push_params(ciphertexts);
+                        query.push(")");
+                    }
+                }
+                (_, Some(_)) => {
+                    return Err(format!(
+                        "Encrypted column {} only supports equality filters",
+                        filter.column
+                    )
+                    .into());
+                }
+                _ => {
+                    query.push(filter.op.sql());
+                    query.push_param(filter.value.clone());
+                }
+            }
+        }
+
+        if !self.group_by.is_empty() {
+            query.push(" GROUP BY ");
+            for (i, column) in self.group_by.iter().enumerate() {
+                if i > 0 {
+                    query.push(",");
+                }
+                // Equal values have equal ciphertexts or blind indexes, so the groups are the
+                // same as on the plaintext
+                match self.is_encrypted(column)?.map(|c| c.mode) {
+                    Some(EncryptionMode::Randomized) => {
+                        return Err(
+                            format!("Randomized column {column} cannot be grouped by").into()
+                        );
+                    }
+                    Some(EncryptionMode::BlindIndex) => {
+                        self.push_blind_index_column(&mut query, column)?;
+                    }
+                    Some(EncryptionMode::Deterministic) | None => {
+                        self.push_column(&mut query, column)?;
+                    }
+                }
+            }
+        }
+        for (i, order_by) in self.order_by.iter().enumerate() {
+            // Ciphertexts are not ordered like the plaintext
+            if self.is_encrypted(&order_by.column)?.is_some() {
+                return Err(
+                    format!("Encrypted column {} cannot be ordered by", order_by.column).into(),
+                );
+            }
+            query.push(if i == 0 { " ORDER BY " } else { ", " });
+            self.push_column(&mut query, &order_by.column)?;
+            if order_by.desc {
+                query.push(" DESC");
+            }
+        }
+        if let Some(limit) = self.limit {
+            query.push(" LIMIT ");
+            query.push_param(Value::from(limit));
+        }
+
+        Ok(PlannedQuery { query, decrypt })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn fake_encrypt(
+        column: &EncryptedColumn,
+        value: Value,
+    ) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+        Ok(vec![format!(
+            "enc({}.{},{value})",
+            column.table, column.column
+        )])
+    }
+
+    #[test]
+    fn test_build() {
+        let mut spec: QuerySpec = serde_json::from_value(json!({
+            "database_id": "db",
+            "table": "users",
+            "alias": "u",
+            "joins": [{ "table": "purchases", "alias": "pu", "left": "pu.user_id", "right": "u.id" }],
+            "select": [
+                { "column": "u.gender" },
+                { "column": "u.age", "aggregate": "avg", "as": "avg_age" }
+            ],
+            "filters": [
+                { "column": "u.gender", "op": "eq", "value": "Male" },
+                { "column": "pu.total_price", "op": "gt", "value": 300 },
+                { "column": "u.email", "op": "eq", "value": "a@b.c" }
+            ],
+            "group_by": ["u.gender"]
+        }))
+        .unwrap();
+        spec.encrypted_columns = serde_json::from_value(json!([
+            { "table": "users", "column": "gender" },
+            { "table": "users", "column": "email", "mode": "blind_index" }
+        ]))
+        .unwrap();
+
+        let planned = spec.build(fake_encrypt).unwrap();
+        assert_eq!(
+            planned.query.sql(),
+            "SELECT \"u\".\"gender\", avg(\"u\".\"age\") AS \"avg_age\" FROM \"users\" AS \"u\" \
+             INNER JOIN \"purchases\" AS \"pu\" ON \"pu\".\"user_id\" = \"u\".\"id\" \
+             WHERE \"u\".\"gender\" = $1 AND \"pu\".\"total_price\" > $2 \
+             AND \"u\".\"email_bidx\" = $3 GROUP BY \"u\".\"gender\""
+        );
+        assert_eq!(
+            planned.query.params(),
+            &[
+                json!("enc(users.gender,\"Male\")"),
+                json!(300),
+                json!("enc(users.email,\"a@b.c\")")
+            ]
+        );
+        assert_eq!(
+            planned.decrypt,
+            vec![
+                Some(EncryptedColumn {
+                    table: "users".to_string(),
+                    column: "gender".to_string(),
+                    mode: EncryptionMode::Deterministic
+                }),
+                None
+            ]
+        );
+    }
+
+    #[test]
+    fn test_build_rejects_encrypted_ranges() {
+        let mut spec: QuerySpec = serde_json::from_value(json!({
+            "database_id": "db",
+            "table": "users",
+            "select": [{ "column": "age", "aggregate": "avg" }]
+        }))
+        .unwrap();
+        spec.encrypted_columns =
+            serde_json::from_value(json!([{ "table": "users", "column": "age" }])).unwrap();
+        assert!(spec.build(fake_encrypt).is_err());
+
+        spec.select = vec![SelectItem {
+            column: "id".to_string(),
+            aggregate: None,
+            alias: None,
+        }];
+        spec.filters = vec![Filter {
+            column: "age".to_string(),
+            op: Op::Gt,
+            value: json!(30),
+        }];
+        assert!(spec.build(fake_encrypt).is_err());
+
+        spec.filters[0].column = "p.age".to_string();
+        assert!(spec.build(fake_encrypt).is_err());
+
+        spec.encrypted_columns[0].mode = EncryptionMode::Randomized;
+        spec.filters[0] = Filter {
+            column: "age".to_string(),
+            op: Op::Eq,
+            value: json!(30),
+        };
+        assert!(spec.build(fake_encrypt).is_err());
+    }
+
+    #[test]
+    fn test_build_group_and_order_by_encrypted_columns() {
+        let mut spec: QuerySpec = serde_json::from_value(json!({
+            "database_id": "db",
+            "table": "users",
+            "select": [{ "column": "*", "aggregate": "count" }],
+            "group_by": ["email"]
+        }))
+        .unwrap();
+        spec.encrypted_columns = serde_json::from_value(json!([
+            { "table": "users", "column": "email", "mode": "blind_index" },
+            { "table": "users", "column": "notes", "mode": "randomized" }
+        ]))
+        .unwrap();
+        assert_eq!(
+            spec.build(fake_encrypt).unwrap().query.sql(),
+            "SELECT count(*) FROM \"users\" GROUP BY \"email_bidx\""
+        );
+
+        spec.group_by = vec!["notes".to_string()];
+        assert!(spec.build(fake_encrypt).is_err());
+
+        spec.group_by = Vec::new();
+        spec.order_by = vec![OrderBy {
+            column: "email".to_string(),
+            desc: false,
+        }];
+        assert!(spec.build(fake_encrypt).is_err());
+    }
+
+    #[test]
+    fn test_build_rejects_empty_in() {
+        let spec: QuerySpec = serde_json::from_value(json!({
+            "database_id": "db",
+            "table": "users",
+            "select": [{ "column": "id" }],
+            "filters": [{ "column": "gender", "op": "in", "value": [] }]
+        }))
+        .unwrap();
+        assert!(spec.build(fake_encrypt).is_err());
+    }
+
+    #[test]
+    fn test_resolve_unqualified_columns() {
+        let mut spec: QuerySpec = serde_json::from_value(json!({
+            "database_id": "db",
+            "table": "users",
+            "joins": [{ "table": "purchases", "left": "purchases.user_id", "right": "users.id" }],
+            "select": [{ "column": "card" }]
+        }))
+        .unwrap();
+        spec.encrypted_columns =
+            serde_json::from_value(json!([{ "table": "purchases", "column": "card" }])).unwrap();
+        // Only encrypted in the joined table
+        let planned = spec.build(fake_encrypt).unwrap();
+        assert_eq!(
+            planned.decrypt,
+            vec![Some(EncryptedColumn {
+                table: "purchases".to_string(),
+                column: "card".to_string(),
+                mode: EncryptionMode::Deterministic
+            })]
+        );
+
+        spec.encrypted_columns.push(EncryptedColumn {
+            table: "users".to_string(),
+            column: "card".to_string(),
+            mode: EncryptionMode::Deterministic,
+        });
+        assert!(spec.build(fake_encrypt).is_err());
+
+        spec.select[0].column = "users.card".to_string();
+        let planned = spec.build(fake_encrypt).unwrap();
+        assert_eq!(planned.decrypt[0].as_ref().unwrap().table, "users");
+    }
+}
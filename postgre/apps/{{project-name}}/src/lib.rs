@@ -4,15 +4,35 @@ mod bindings;
 use bindings::Guest;
 
 pub mod business;
+pub mod catalog;
+pub mod connection;
+pub mod credentials;
 pub mod crypto;
+pub mod cursor;
 pub mod database;
+pub mod dsl;
+pub mod query;
+pub mod rotation;
+pub mod row;
 pub mod utils;
 
 struct Component;
 impl Guest for Component {
     fn register_routes() {
         klave::router::add_user_transaction(&String::from("db_setup"));
+        klave::router::add_user_query(&String::from("list_database_clients"));
+        klave::router::add_user_transaction(&String::from("delete_database_client"));
+        klave::router::add_user_transaction(&String::from("update_database_credentials"));
+        klave::router::add_user_query(&String::from("test_database_connection"));
+        klave::router::add_user_transaction(&String::from("register_encrypted_columns"));
+        klave::router::add_user_query(&String::from("get_encrypted_columns"));
         klave::router::add_user_query(&String::from("execute_table_encryption"));
+        klave::router::add_user_transaction(&String::from("mark_columns_encrypted"));
+        klave::router::add_user_transaction(&String::from("rotate_master_key"));
+        klave::router::add_user_query(&String::from("reencrypt_next_chunk"));
+        klave::router::add_user_transaction(&String::from("commit_rotation_checkpoint"));
+        klave::router::add_user_query(&String::from("read_decrypted_rows"));
+        klave::router::add_user_query(&String::from("run_query"));
 
         //routes defined in business part
         klave::router::add_user_query(&String::from("read_encrypted_data_per_user"));
@@ -48,6 +68,224 @@ impl Guest for Component {
         }
     }
 
+    fn list_database_clients(_cmd: String) {
+        let clients = match database::Clients::load() {
+            Ok(c) => c,
+            Err(err) => {
+                klave::notifier::send_string(&format!("Failed to load clients: {err}"));
+                return;
+            }
+        };
+
+        match clients.list_owned() {
+            Ok(list) => {
+                let _ = klave::notifier::send_json(&list);
+            }
+            Err(err) => {
+                klave::notifier::send_string(&format!("Failed to list database clients: {err}"));
+            }
+        }
+    }
+
+    fn delete_database_client(cmd: String) {
+        let input: database::DeleteInput = match serde_json::from_str(&cmd) {
+            Ok(input) => input,
+            Err(err) => {
+                klave::notifier::send_string(&format!("Invalid input: {err}"));
+                return;
+            }
+        };
+
+        if let Err(err) = database::Client::load_owned(input.database_id.clone()) {
+            klave::notifier::send_string(&format!("Failed to load client: {err}"));
+            return;
+        }
+        // The master key cannot change while columns are being re-encrypted
+        match rotation::Rotation::load(&input.database_id) {
+            Ok(None) => (),
+            Ok(Some(_)) => {
+                klave::notifier::send_string("A master key rotation is in progress");
+                return;
+            }
+            Err(err) => {
+                klave::notifier::send_string(&format!("Failed to load rotation: {err}"));
+                return;
+            }
+        }
+        let mut clients = match database::Clients::load() {
+            Ok(c) => c,
+            Err(err) => {
+                klave::notifier::send_string(&format!("Failed to load clients: {err}"));
+                return;
+            }
+        };
+
+        let deleted = clients.delete(&input.database_id).and_then(|_| {
+            catalog::Catalog::load(&input.database_id).and_then(|catalog| catalog.remove())
+        });
+        match deleted {
+            Ok(_) => {
+                klave::notifier::send_string(&format!(
+                    "Database client {} deleted",
+                    input.database_id
+                ));
+            }
+            Err(err) => {
+                klave::notifier::send_string(&format!("Failed to delete database client: {err}"));
+                klave::router::cancel_transaction();
+            }
+        }
+    }
+
+    fn update_database_credentials(cmd: String) {
+        let input: database::UpdateCredentialsInput = match serde_json::from_str(&cmd) {
+            Ok(input) => input,
+            Err(err) => {
+                klave::notifier::send_string(&format!("Invalid input: {err}"));
+                return;
+            }
+        };
+
+        let mut client: database::Client =
+            match database::Client::load_owned(input.database_id.clone()) {
+                Ok(c) => c,
+                Err(err) => {
+                    klave::notifier::send_string(&format!("Failed to load client: {err}"));
+                    return;
+                }
+            };
+        // The master key is kept, encrypted columns stay readable
+        match client
+            .set_db_input_details(input.db_input_details)
+            .and_then(|_| client.save_record())
+        {
+            Ok(_) => {
+                let _ = klave::notifier::send_json(&client.info());
+            }
+            Err(err) => {
+                klave::notifier::send_string(&format!("Failed to update credentials: {err}"));
+                klave::router::cancel_transaction();
+            }
+        }
+    }
+
+    fn test_database_connection(cmd: String) {
+        let input: database::DatabaseIdInput = match serde_json::from_str(&cmd) {
+            Ok(input) => input,
+            Err(err) => {
+                klave::notifier::send_string(&format!("Invalid input: {err}"));
+                return;
+            }
+        };
+
+        let client: database::Client = match database::Client::load_owned(input.database_id.clone())
+        {
+            Ok(c) => c,
+            Err(err) => {
+                klave::notifier::send_string(&format!("Failed to load client: {err}"));
+                return;
+            }
+        };
+        match client.test_connection() {
+            Ok(_) => {
+                let _ = klave::notifier::send_json(&serde_json::json!({
+                    "database_id": input.database_id,
+                    "connected": true
+                }));
+            }
+            Err(err) => {
+                let _ = klave::notifier::send_json(&serde_json::json!({
+                    "database_id": input.database_id,
+                    "connected": false,
+                    "error": err.to_string()
+                }));
+            }
+        }
+    }
+
+    fn register_encrypted_columns(cmd: String) {
+        let input: catalog::RegisterEncryptedColumnsInput = match serde_json::from_str(&cmd) {
+            Ok(input) => input,
+            Err(err) => {
+                klave::notifier::send_string(&format!("Invalid input: {err}"));
+                return;
+            }
+        };
+
+        let client: database::Client = match database::Client::load_owned(input.database_id.clone())
+        {
+            Ok(c) => c,
+            Err(err) => {
+                klave::notifier::send_string(&format!("Failed to load client: {err}"));
+                return;
+            }
+        };
+        let registered_at =
+            match klave::context::get("trusted_time").and_then(|t| Ok(t.parse::<u64>()?)) {
+                Ok(t) => t,
+                Err(err) => {
+                    klave::notifier::send_string(&format!("Failed to get trusted time: {err}"));
+                    return;
+                }
+            };
+
+        let mut catalog = match catalog::Catalog::load(&input.database_id) {
+            Ok(c) => c,
+            Err(err) => {
+                klave::notifier::send_string(&format!("Failed to load catalog: {err}"));
+                return;
+            }
+        };
+        for column in input.columns.iter() {
+            let entry = catalog::CatalogEntry {
+                table: input.table.clone(),
+                column: column.clone(),
+                primary_key: input.primary_key.clone(),
+                key_version: client.master_key_version(),
+                mode: input.mode,
+                registered_at,
+                encrypted_at: None,
+            };
+            if let Err(err) = catalog.register(entry) {
+                klave::notifier::send_string(&format!("Failed to register column: {err}"));
+                klave::router::cancel_transaction();
+                return;
+            }
+        }
+        match catalog.save() {
+            Ok(_) => {
+                let _ = klave::notifier::send_json(&catalog);
+            }
+            Err(err) => {
+                klave::notifier::send_string(&format!("Failed to save catalog: {err}"));
+                klave::router::cancel_transaction();
+            }
+        }
+    }
+
+    fn get_encrypted_columns(cmd: String) {
+        let input: database::DatabaseIdInput = match serde_json::from_str(&cmd) {
+            Ok(input) => input,
+            Err(err) => {
+                klave::notifier::send_string(&format!("Invalid input: {err}"));
+                return;
+            }
+        };
+
+        if let Err(err) = database::Client::load_owned(input.database_id.clone()) {
+            klave::notifier::send_string(&format!("Failed to load client: {err}"));
+            return;
+        }
+        match catalog::Catalog::load(&input.database_id) {
+            Ok(catalog) => {
+                let _ = klave::notifier::send_json(&catalog);
+            }
+            Err(err) => {
+                klave::notifier::send_string(&format!("Failed to load catalog: {err}"));
+            }
+        }
+    }
+
     fn execute_table_encryption(cmd: String) {
         let db_table: database::DBTable = match serde_json::from_str(&cmd) {
             Ok(input) => input,
@@ -58,7 +296,7 @@ impl Guest for Component {
         };
 
         let mut client: database::Client =
-            match database::Client::load(db_table.database_id.clone()) {
+            match database::Client::load_owned(db_table.database_id.clone()) {
                 Ok(c) => c,
                 Err(err) => {
                     klave::notifier::send_string(&format!("Failed to load client: {err}"));
@@ -73,13 +311,76 @@ impl Guest for Component {
             }
         };
         match client.encrypt_columns(db_table) {
-            Ok(_) => (),
+            Ok(receipt) => {
+                let _ = klave::notifier::send_json(&receipt);
+            }
             Err(err) => {
                 klave::notifier::send_string(&format!("Failed to encrypt columns: {err}"));
             }
         }
     }
 
+    fn mark_columns_encrypted(cmd: String) {
+        catalog::mark_columns_encrypted(cmd);
+    }
+
+    fn rotate_master_key(cmd: String) {
+        rotation::rotate_master_key(cmd);
+    }
+
+    fn reencrypt_next_chunk(cmd: String) {
+        rotation::reencrypt_next_chunk(cmd);
+    }
+
+    fn commit_rotation_checkpoint(cmd: String) {
+        rotation::commit_rotation_checkpoint(cmd);
+    }
+
+    fn read_decrypted_rows(cmd: String) {
+        let input: database::ReadDecryptedRowsInput = match serde_json::from_str(&cmd) {
+            Ok(input) => input,
+            Err(err) => {
+                klave::notifier::send_string(&format!("Invalid input: {err}"));
+                return;
+            }
+        };
+
+        let client: database::Client = match database::Client::load_owned(input.database_id.clone())
+        {
+            Ok(c) => c,
+            Err(err) => {
+                klave::notifier::send_string(&format!("Failed to load client: {err}"));
+                return;
+            }
+        };
+        match client.connect() {
+            Ok(_) => (),
+            Err(err) => {
+                klave::notifier::send_string(&format!("Failed to connect to client: {err}"));
+                return;
+            }
+        };
+        match client.read_decrypted_rows(&input) {
+            Ok(res) => {
+                let _ = klave::notifier::send_json(&res);
+            }
+            Err(err) => {
+                klave::notifier::send_string(&format!("Failed to read decrypted rows: {err}"));
+            }
+        }
+    }
+
+    fn run_query(cmd: String) {
+        let spec: dsl::QuerySpec = match serde_json::from_str(&cmd) {
+            Ok(input) => input,
+            Err(err) => {
+                klave::notifier::send_string(&format!("Invalid input: {err}"));
+                return;
+            }
+        };
+        business::run_query_spec(spec);
+    }
+
     fn read_encrypted_data_per_user(cmd: String) {
         business::read_encrypted_data_per_user(cmd);
     }
@@ -0,0 +1,261 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+// A piece of a query: trusted SQL text or a reference to a bound parameter.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+enum Part {
+    Sql(String),
+    Param(usize),
+}
+
+// SQL query with bound parameters ($1, $2, ...).
+// Identifiers are quoted when added and values are only ever held as parameters.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Query {
+    parts: Vec<Part>,
+    params: Vec<Value>,
+}
+
+// Quotes a single identifier, e.g. `users` becomes `"users"`. Dots are part of the name:
+// `a.b` becomes `"a.b"`.
+pub fn quote_identifier(name: &str) -> Result<String, Box<dyn std::error::Error>> {
+    if name.is_empty() {
+        return Err("Empty identifier".into());
+    }
+    if name.contains('\0') {
+        return Err(format!("Invalid identifier: {name}").into());
+    }
+    Ok(format!("\"{}\"", name.replace('"', "\"\"")))
+}
+
+// Quotes a table name, optionally qualified by its schema: `public.users` becomes
+// `"public"."users"`. Only use it where a table is expected.
+pub fn quote_qualified_name(name: &str) -> Result<String, Box<dyn std::error::Error>> {
+    match name.split_once('.') {
+        Some((schema, table)) => {
+            if schema.is_empty() || table.is_empty() || table.contains('.') {
+                return Err(format!("Invalid table name: {name}").into());
+            }
+            Ok(format!(
+                "{}.{}",
+                quote_identifier(schema)?,
+                quote_identifier(table)?
+            ))
+        }
+        None => quote_identifier(name),
+    }
+}
+
+// Quotes a value as a SQL literal. Arrays and objects are passed as their JSON text.
+// Strings are escape string constants (`E'...'`), so that backslashes and quotes are escaped
+// whatever `standard_conforming_strings` is set to.
+pub fn quote_literal(value: &Value) -> Result<String, Box<dyn std::error::Error>> {
+    let text = match value {
+        Value::Null => return Ok("NULL".to_string()),
+        Value::Bool(b) => return Ok(if *b { "TRUE" } else { "FALSE" }.to_string()),
+        Value::Number(n) => return Ok(n.to_string()),
+        Value::String(s) => s.clone(),
+        Value::Array(_) | Value::Object(_) => value.to_string(),
+    };
+    if text.contains('\0') {
+        return Err("String literals cannot contain NUL characters".into());
+    }
+    Ok(format!(
+        "E'{}'",
+        text.replace('\\', "\\\\").replace('\'', "\\'")
+    ))
+}
+
+impl Query {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Appends trusted SQL text. Never pass user input here.
+    pub fn push(&mut self, sql: &str) -> &mut Self {
+        match self.parts.last_mut() {
+            Some(Part::Sql(last)) => last.push_str(sql),
+            _ => self.parts.push(Part::Sql(sql.to_string())),
+        }
+        self
+    }
+
+    // Appends a quoted identifier.
+    pub fn push_identifier(&mut self, name: &str) -> Result<&mut Self, Box<dyn std::error::Error>> {
+        let quoted = quote_identifier(name)?;
+        Ok(self.push(&quoted))
+    }
+
+    // Appends a quoted table name, optionally qualified by its schema.
+    pub fn push_qualified_name(
+        &mut self,
+        name: &str,
+    ) -> Result<&mut Self, Box<dyn std::error::Error>> {
+        let quoted = quote_qualified_name(name)?;
+        Ok(self.push(&quoted))
+    }
+
+    // Appends a column, qualified by a table name or alias when given.
+    pub fn push_column(
+        &mut self,
+        qualifier: Option<&str>,
+        column: &str,
+    ) -> Result<&mut Self, Box<dyn std::error::Error>> {
+        if let Some(qualifier) = qualifier {
+            self.push_qualified_name(qualifier)?;
+            self.push(".");
+        }
+        self.push_identifier(column)
+    }
+
+    // Appends a comma separated list of quoted identifiers.
+    pub fn push_identifiers(
+        &mut self,
+        names: &[String],
+    ) -> Result<&mut Self, Box<dyn std::error::Error>> {
+        for (i, name) in names.iter().enumerate() {
+            if i > 0 {
+                self.push(",");
+            }
+            self.push_identifier(name)?;
+        }
+        Ok(self)
+    }
+
+    // Binds a value and appends its placeholder.
+    pub fn push_param(&mut self, value: Value) -> &mut Self {
+        self.params.push(value);
+        self.parts.push(Part::Param(self.params.len()));
+        self
+    }
+
+    // Binds values and appends their placeholders as a comma separated list.
+    pub fn push_params(&mut self, values: Vec<Value>) -> &mut Self {
+        for (i, value) in values.into_iter().enumerate() {
+            if i > 0 {
+                self.push(",");
+            }
+            self.push_param(value);
+        }
+        self
+    }
+
+    // Appends another query, renumbering its parameters after the ones already bound.
+    pub fn append(&mut self, other: &Query) -> &mut Self {
+        let offset = self.params.len();
+        for part in &other.parts {
+            match part {
+                Part::Sql(sql) => {
+                    self.push(sql);
+                }
+                Part::Param(n) => self.parts.push(Part::Param(offset + n)),
+            }
+        }
+        self.params.extend(other.params.iter().cloned());
+        self
+    }
+
+    pub fn params(&self) -> &[Value] {
+        &self.params
+    }
+
+    // SQL text with $n placeholders.
+    pub fn sql(&self) -> String {
+        self.parts
+            .iter()
+            .map(|part| match part {
+                Part::Sql(sql) => sql.clone(),
+                Part::Param(n) => format!("${n}"),
+            })
+            .collect()
+    }
+
+    // SQL text sent to the database.
+    // `klave::sql` takes a single statement text, so parameters are inlined as quoted literals here, and only here.
+    pub fn to_sql(&self) -> Result<String, Box<dyn std::error::Error>> {
+        let mut sql = String::new();
+        for part in &self.parts {
+            match part {
+                Part::Sql(text) => sql.push_str(text),
+                Part::Param(n) => sql.push_str(&quote_literal(&self.params[n - 1])?),
+            }
+        }
+        Ok(sql)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn test_quote_identifier() {
+        assert_eq!(quote_identifier("users").unwrap(), "\"users\"");
+        // User supplied names are never split
+        assert_eq!(
+            quote_identifier("public.users").unwrap(),
+            "\"public.users\""
+        );
+        assert_eq!(
+            quote_identifier("a\"; DROP TABLE users; --").unwrap(),
+            "\"a\"\"; DROP TABLE users; --\""
+        );
+        assert!(quote_identifier("").is_err());
+        assert!(quote_identifier("a\0b").is_err());
+
+        assert_eq!(
+            quote_qualified_name("public.users").unwrap(),
+            "\"public\".\"users\""
+        );
+        assert_eq!(quote_qualified_name("users").unwrap(), "\"users\"");
+        assert!(quote_qualified_name("users.").is_err());
+        assert!(quote_qualified_name("a.b.c").is_err());
+    }
+
+    #[test]
+    fn test_quote_literal() {
+        assert_eq!(quote_literal(&json!("O'Brien")).unwrap(), "E'O\\'Brien'");
+        // A trailing backslash cannot escape the closing quote
+        assert_eq!(
+            quote_literal(&json!("a\\' OR 1=1 --")).unwrap(),
+            "E'a\\\\\\' OR 1=1 --'"
+        );
+        assert_eq!(quote_literal(&json!(3)).unwrap(), "3");
+        assert_eq!(quote_literal(&Value::Null).unwrap(), "NULL");
+        assert_eq!(quote_literal(&json!(["x"])).unwrap(), "E'[\"x\"]'");
+    }
+
+    #[test]
+    fn test_query() {
+        let mut query = Query::new();
+        query.push("SELECT * FROM ");
+        query.push_identifier("users").unwrap();
+        query.push(" WHERE ");
+        query.push_identifier("first_name").unwrap();
+        query.push(" IN (");
+        query.push_params(vec![json!("O'Brien"), json!(3), Value::Null]);
+        query.push(")");
+
+        assert_eq!(
+            query.sql(),
+            "SELECT * FROM \"users\" WHERE \"first_name\" IN ($1,$2,$3)"
+        );
+        assert_eq!(query.params().len(), 3);
+        assert_eq!(
+            query.to_sql().unwrap(),
+            "SELECT * FROM \"users\" WHERE \"first_name\" IN (E'O\\'Brien',3,NULL)"
+        );
+        assert!(Query::new().push_param(json!("a\0b")).to_sql().is_err());
+
+        let mut outer = Query::new();
+        outer.push("SELECT 1 WHERE ").push_param(json!(300));
+        outer.push(" > 0 AND EXISTS (").append(&query).push(")");
+        assert_eq!(
+            outer.sql(),
+            "SELECT 1 WHERE $1 > 0 AND EXISTS (SELECT * FROM \"users\" WHERE \"first_name\" IN ($2,$3,$4))"
+        );
+        assert_eq!(outer.params().len(), 4);
+    }
+}
@@ -0,0 +1,277 @@
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::{
+    catalog::Catalog,
+    crypto::{compute_checkpoint_mac, mac_eq, MasterKey},
+    database,
+};
+
+pub(crate) const MASTER_KEY_ROTATION_TABLE: &str = "MasterKeyRotationTable";
+
+// Next rows to re-encrypt: catalog entry `entry`, rows after `last_primary_key`.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Checkpoint {
+    pub entry: usize,
+    pub last_primary_key: Option<Value>,
+}
+
+// Master key rotation in progress. Columns are encrypted under either key until it finishes.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Rotation {
+    pub database_id: String,
+    pub from_version: u32,
+    pub to_version: u32,
+    pub to_key_name: String,
+    pub chunk_size: usize,
+    pub checkpoint: Checkpoint,
+    pub started_at: u64, // trusted_time, in nanoseconds
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RotateMasterKeyInput {
+    pub database_id: String,
+    pub chunk_size: usize,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CommitCheckpointInput {
+    pub database_id: String,
+    pub checkpoint: Checkpoint,
+    // MAC returned by reencrypt_next_chunk with the checkpoint
+    pub mac: String,
+}
+
+impl Checkpoint {
+    // Checkpoint after re-encrypting `rows` rows of the current entry, the last one having
+    // `last_primary_key`.
+    pub fn advance(&self, rows: usize, chunk_size: usize, last_primary_key: Option<Value>) -> Self {
+        if rows < chunk_size {
+            Checkpoint {
+                entry: self.entry + 1,
+                last_primary_key: None,
+            }
+        } else {
+            Checkpoint {
+                entry: self.entry,
+                last_primary_key,
+            }
+        }
+    }
+
+    pub fn is_done(&self, catalog: &Catalog) -> bool {
+        self.entry >= catalog.entries.len()
+    }
+}
+
+impl Rotation {
+    pub fn load(database_id: &str) -> Result<Option<Rotation>, Box<dyn std::error::Error>> {
+        let v = match klave::ledger::get_table(MASTER_KEY_ROTATION_TABLE).get(database_id) {
+            Ok(v) if !v.is_empty() => v,
+            _ => return Ok(None),
+        };
+        match serde_json::from_slice::<Rotation>(&v) {
+            Ok(rotation) => Ok(Some(rotation)),
+            Err(e) => {
+                klave::notifier::send_string(&format!(
+                    "ERROR: failed to parse master key rotation: {e}"
+                ));
+                Err(e.into())
+            }
+        }
+    }
+
+    fn save(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let serialized = serde_json::to_string(&self)?;
+        klave::ledger::get_table(MASTER_KEY_ROTATION_TABLE)
+            .set(&self.database_id, serialized.as_bytes())
+    }
+
+    fn remove(&self) -> Result<(), Box<dyn std::error::Error>> {
+        klave::ledger::get_table(MASTER_KEY_ROTATION_TABLE).remove(&self.database_id)
+    }
+
+    // MAC of the move from the stored checkpoint to `next`, under a key derived from the new
+    // master key. A MAC only verifies while the stored checkpoint is the one it was computed
+    // from, so checkpoints advance one re-encrypted chunk at a time.
+    pub fn checkpoint_mac(&self, next: &Checkpoint) -> Result<String, Box<dyn std::error::Error>> {
+        let key = MasterKey::load(&self.to_key_name)?;
+        let data = serde_json::to_vec(&json!({
+            "database_id": self.database_id,
+            "to_version": self.to_version,
+            "from": self.checkpoint,
+            "to": next,
+        }))?;
+        compute_checkpoint_mac(&key.hmac_key, &self.database_id, &data)
+    }
+}
+
+fn start(input: &RotateMasterKeyInput) -> Result<Rotation, Box<dyn std::error::Error>> {
+    let client = database::Client::load_owned(input.database_id.clone())?;
+    if input.chunk_size == 0 {
+        return Err("chunk_size must be greater than 0".into());
+    }
+    if Rotation::load(&input.database_id)?.is_some() {
+        return Err("A master key rotation is already in progress".into());
+    }
+    // A column encrypted but not marked yet would not be re-encrypted, and be lost with the
+    // current key
+    if let Some(entry) = Catalog::load(&input.database_id)?.first_unencrypted() {
+        return Err(format!(
+            "Column {}.{} is registered but not marked as encrypted, encrypt it and call mark_columns_encrypted first",
+            entry.table, entry.column
+        )
+        .into());
+    }
+    // The new key is kept next to the current one until the rotation finishes
+    let to_key_name = hex::encode(klave::crypto::random::get_random_bytes(32)?);
+    MasterKey::create(&to_key_name)?;
+    let rotation = Rotation {
+        database_id: input.database_id.clone(),
+        from_version: client.master_key_version(),
+        to_version: client.master_key_version() + 1,
+        to_key_name,
+        chunk_size: input.chunk_size,
+        checkpoint: Checkpoint::default(),
+        started_at: klave::context::get("trusted_time")?.parse::<u64>()?,
+    };
+    rotation.save()?;
+    Ok(rotation)
+}
+
+fn commit(input: &CommitCheckpointInput) -> Result<Value, Box<dyn std::error::Error>> {
+    let mut client = database::Client::load_owned(input.database_id.clone())?;
+    let Some(mut rotation) = Rotation::load(&input.database_id)? else {
+        return Err("No master key rotation in progress".into());
+    };
+    if !mac_eq(&rotation.checkpoint_mac(&input.checkpoint)?, &input.mac) {
+        return Err(
+            "Checkpoint was not returned by reencrypt_next_chunk from the stored one".into(),
+        );
+    }
+    rotation.checkpoint = input.checkpoint.clone();
+
+    let mut catalog = Catalog::load(&input.database_id)?;
+    if !rotation.checkpoint.is_done(&catalog) {
+        rotation.save()?;
+        return Ok(json!({ "done": false, "rotation": rotation }));
+    }
+    // Every column is re-encrypted, the new key becomes the master key
+    let old_master_key = client.load_master_key()?;
+    client.set_master_key(rotation.to_key_name.clone(), rotation.to_version);
+    client.save_record()?;
+    catalog.set_key_version(rotation.to_version);
+    catalog.save()?;
+    rotation.remove()?;
+    // No value is encrypted under the old key anymore
+    old_master_key.delete()?;
+    Ok(json!({ "done": true, "master_key_version": rotation.to_version }))
+}
+
+pub fn rotate_master_key(cmd: String) {
+    let input: RotateMasterKeyInput = match serde_json::from_str(&cmd) {
+        Ok(input) => input,
+        Err(err) => {
+            klave::notifier::send_string(&format!("Invalid input: {err}"));
+            return;
+        }
+    };
+    match start(&input) {
+        Ok(rotation) => {
+            let _ = klave::notifier::send_json(&rotation);
+        }
+        Err(err) => {
+            klave::notifier::send_string(&format!("Failed to rotate master key: {err}"));
+            klave::router::cancel_transaction();
+        }
+    }
+}
+
+pub fn reencrypt_next_chunk(cmd: String) {
+    let input: database::DatabaseIdInput = match serde_json::from_str(&cmd) {
+        Ok(input) => input,
+        Err(err) => {
+            klave::notifier::send_string(&format!("Invalid input: {err}"));
+            return;
+        }
+    };
+    let client = match database::Client::load_owned(input.database_id.clone()) {
+        Ok(c) => c,
+        Err(err) => {
+            klave::notifier::send_string(&format!("Failed to load client: {err}"));
+            return;
+        }
+    };
+    let rotation = match Rotation::load(&input.database_id) {
+        Ok(Some(rotation)) => rotation,
+        Ok(None) => {
+            klave::notifier::send_string("No master key rotation in progress");
+            return;
+        }
+        Err(err) => {
+            klave::notifier::send_string(&format!("Failed to load rotation: {err}"));
+            return;
+        }
+    };
+
+    // Connect to the DB and establish a handle
+    match client.connect() {
+        Ok(_) => (),
+        Err(err) => {
+            klave::notifier::send_string(&format!("Failed to connect to client: {err}"));
+            return;
+        }
+    };
+    match client
+        .reencrypt_next_chunk(&rotation)
+        .and_then(|(checkpoint, rows)| {
+            Ok((rotation.checkpoint_mac(&checkpoint)?, checkpoint, rows))
+        }) {
+        Ok((mac, checkpoint, rows)) => {
+            let _ = klave::notifier::send_json(&json!({
+                "rows": rows,
+                "checkpoint": checkpoint,
+                "mac": mac
+            }));
+        }
+        Err(err) => {
+            klave::notifier::send_string(&format!("Failed to re-encrypt chunk: {err}"));
+        }
+    }
+}
+
+pub fn commit_rotation_checkpoint(cmd: String) {
+    let input: CommitCheckpointInput = match serde_json::from_str(&cmd) {
+        Ok(input) => input,
+        Err(err) => {
+            klave::notifier::send_string(&format!("Invalid input: {err}"));
+            return;
+        }
+    };
+    match commit(&input) {
+        Ok(res) => {
+            let _ = klave::notifier::send_json(&res);
+        }
+        Err(err) => {
+            klave::notifier::send_string(&format!("Failed to commit checkpoint: {err}"));
+            klave::router::cancel_transaction();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_advance() {
+        let checkpoint = Checkpoint::default();
+        let next = checkpoint.advance(10, 10, Some(Value::from(42)));
+        assert_eq!(next.entry, 0);
+        assert_eq!(next.last_primary_key, Some(Value::from(42)));
+
+        let next = next.advance(3, 10, Some(Value::from(45)));
+        assert_eq!(next.entry, 1);
+        assert_eq!(next.last_primary_key, None);
+    }
+}
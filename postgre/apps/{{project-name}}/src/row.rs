@@ -0,0 +1,539 @@
+use std::fmt;
+
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+
+use crate::database::Field;
+
+// Postgres type OIDs, as given in `Field::field_type`.
+pub mod oid {
+    pub const BOOL: u32 = 16;
+    pub const BYTEA: u32 = 17;
+    pub const NAME: u32 = 19;
+    pub const INT8: u32 = 20;
+    pub const INT2: u32 = 21;
+    pub const INT4: u32 = 23;
+    pub const TEXT: u32 = 25;
+    pub const OID: u32 = 26;
+    pub const JSON: u32 = 114;
+    pub const FLOAT4: u32 = 700;
+    pub const FLOAT8: u32 = 701;
+    pub const BPCHAR: u32 = 1042;
+    pub const VARCHAR: u32 = 1043;
+    pub const DATE: u32 = 1082;
+    pub const TIME: u32 = 1083;
+    pub const TIMESTAMP: u32 = 1114;
+    pub const TIMESTAMPTZ: u32 = 1184;
+    pub const NUMERIC: u32 = 1700;
+    pub const UUID: u32 = 2950;
+    pub const JSONB: u32 = 3802;
+
+    pub const INTEGERS: &[u32] = &[INT2, INT4, INT8, OID];
+    pub const FLOATS: &[u32] = &[FLOAT4, FLOAT8, NUMERIC, INT2, INT4, INT8];
+
+    pub fn name(oid: u32) -> Option<&'static str> {
+        Some(match oid {
+            BOOL => "bool",
+            BYTEA => "bytea",
+            NAME => "name",
+            INT8 => "int8",
+            INT2 => "int2",
+            INT4 => "int4",
+            TEXT => "text",
+            OID => "oid",
+            JSON => "json",
+            FLOAT4 => "float4",
+            FLOAT8 => "float8",
+            BPCHAR => "bpchar",
+            VARCHAR => "varchar",
+            DATE => "date",
+            TIME => "time",
+            TIMESTAMP => "timestamp",
+            TIMESTAMPTZ => "timestamptz",
+            NUMERIC => "numeric",
+            UUID => "uuid",
+            JSONB => "jsonb",
+            _ => return None,
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum RowError {
+    MissingColumn(String),
+    UnexpectedNull(String),
+    WrongType {
+        column: String,
+        oid: u32,
+        reason: String,
+    },
+}
+
+impl fmt::Display for RowError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RowError::MissingColumn(column) => write!(f, "Missing column {column}"),
+            RowError::UnexpectedNull(column) => write!(f, "Column {column} is NULL"),
+            RowError::WrongType {
+                column,
+                oid,
+                reason,
+            } => match oid::name(*oid) {
+                Some(name) => write!(f, "Column {column} of type {name}: {reason}"),
+                None => write!(f, "Column {column} of type oid {oid}: {reason}"),
+            },
+        }
+    }
+}
+
+impl std::error::Error for RowError {}
+
+// Conversion of a column value into a Rust type. Values of known OIDs are checked against the
+// types the Rust type accepts, values of other OIDs are converted from their JSON shape.
+pub trait FromSql: Sized {
+    fn from_sql(oid: u32, value: &Value) -> Result<Self, String>;
+
+    // NULL is only accepted by Option
+    fn from_null() -> Option<Self> {
+        None
+    }
+}
+
+// Fails if `oid` is a known type outside of `accepted`.
+fn check_oid(oid: u32, accepted: &[u32], expected: &str) -> Result<(), String> {
+    if oid::name(oid).is_some() && !accepted.contains(&oid) {
+        return Err(format!("expected {expected}"));
+    }
+    Ok(())
+}
+
+fn unexpected(expected: &str, value: &Value) -> String {
+    format!("expected {expected}, found {value}")
+}
+
+impl<T: FromSql> FromSql for Option<T> {
+    fn from_sql(oid: u32, value: &Value) -> Result<Self, String> {
+        T::from_sql(oid, value).map(Some)
+    }
+
+    fn from_null() -> Option<Self> {
+        Some(None)
+    }
+}
+
+impl FromSql for bool {
+    fn from_sql(oid: u32, value: &Value) -> Result<Self, String> {
+        check_oid(oid, &[oid::BOOL], "a boolean")?;
+        match value {
+            Value::Bool(b) => Ok(*b),
+            Value::String(s) if s == "t" || s == "true" => Ok(true),
+            Value::String(s) if s == "f" || s == "false" => Ok(false),
+            _ => Err(unexpected("a boolean", value)),
+        }
+    }
+}
+
+impl FromSql for i64 {
+    fn from_sql(oid: u32, value: &Value) -> Result<Self, String> {
+        check_oid(oid, oid::INTEGERS, "an integer")?;
+        match value {
+            Value::Number(n) => n.as_i64(),
+            // int8 may be given as text
+            Value::String(s) => s.parse::<i64>().ok(),
+            _ => None,
+        }
+        .ok_or_else(|| unexpected("an integer", value))
+    }
+}
+
+impl FromSql for i32 {
+    fn from_sql(oid: u32, value: &Value) -> Result<Self, String> {
+        i32::try_from(i64::from_sql(oid, value)?).map_err(|_| unexpected("an int4", value))
+    }
+}
+
+impl FromSql for i16 {
+    fn from_sql(oid: u32, value: &Value) -> Result<Self, String> {
+        i16::try_from(i64::from_sql(oid, value)?).map_err(|_| unexpected("an int2", value))
+    }
+}
+
+impl FromSql for f64 {
+    fn from_sql(oid: u32, value: &Value) -> Result<Self, String> {
+        check_oid(oid, oid::FLOATS, "a number")?;
+        match value {
+            Value::Number(n) => n.as_f64(),
+            // numeric is given as text
+            Value::String(s) => s.parse::<f64>().ok(),
+            _ => None,
+        }
+        .ok_or_else(|| unexpected("a number", value))
+    }
+}
+
+impl FromSql for f32 {
+    fn from_sql(oid: u32, value: &Value) -> Result<Self, String> {
+        f64::from_sql(oid, value).map(|f| f as f32)
+    }
+}
+
+// Text form of any scalar value.
+impl FromSql for String {
+    fn from_sql(_oid: u32, value: &Value) -> Result<Self, String> {
+        match value {
+            Value::String(s) => Ok(s.clone()),
+            Value::Number(n) => Ok(n.to_string()),
+            Value::Bool(b) => Ok(b.to_string()),
+            _ => Err(unexpected("a scalar value", value)),
+        }
+    }
+}
+
+// Any value, json and jsonb given as text are parsed.
+impl FromSql for Value {
+    fn from_sql(oid: u32, value: &Value) -> Result<Self, String> {
+        match value {
+            Value::String(s) if oid == oid::JSON || oid == oid::JSONB => {
+                serde_json::from_str(s).map_err(|e| format!("invalid json: {e}"))
+            }
+            _ => Ok(value.clone()),
+        }
+    }
+
+    fn from_null() -> Option<Self> {
+        Some(Value::Null)
+    }
+}
+
+// bytea, given as `\x` followed by hex digits.
+impl FromSql for Vec<u8> {
+    fn from_sql(oid: u32, value: &Value) -> Result<Self, String> {
+        check_oid(oid, &[oid::BYTEA], "a bytea")?;
+        match value {
+            Value::String(s) => {
+                let digits = s.strip_prefix("\\x").unwrap_or(s);
+                hex::decode(digits).map_err(|_| unexpected("hex encoded bytes", value))
+            }
+            Value::Array(items) => items
+                .iter()
+                .map(|item| item.as_u64().and_then(|b| u8::try_from(b).ok()))
+                .collect::<Option<Vec<u8>>>()
+                .ok_or_else(|| unexpected("an array of bytes", value)),
+            _ => Err(unexpected("a bytea", value)),
+        }
+    }
+}
+
+// json or jsonb value deserialized into T.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Json<T>(pub T);
+
+impl<T: DeserializeOwned> FromSql for Json<T> {
+    fn from_sql(oid: u32, value: &Value) -> Result<Self, String> {
+        check_oid(oid, &[oid::JSON, oid::JSONB], "a json")?;
+        let value = Value::from_sql(oid, value)?;
+        serde_json::from_value(value)
+            .map(Json)
+            .map_err(|e| format!("invalid json: {e}"))
+    }
+}
+
+// numeric value, kept as its exact decimal text.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Numeric(pub String);
+
+impl Numeric {
+    pub fn to_f64(&self) -> Option<f64> {
+        self.0.parse::<f64>().ok()
+    }
+}
+
+impl FromSql for Numeric {
+    fn from_sql(oid: u32, value: &Value) -> Result<Self, String> {
+        check_oid(oid, oid::FLOATS, "a numeric")?;
+        match value {
+            Value::Number(n) => Ok(Numeric(n.to_string())),
+            Value::String(s) if s == "NaN" || s.parse::<f64>().is_ok() => Ok(Numeric(s.clone())),
+            _ => Err(unexpected("a numeric", value)),
+        }
+    }
+}
+
+// timestamp, timestamptz or date, as seconds and nanoseconds since the Unix epoch in UTC.
+// A timestamp without time zone is read as UTC.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Timestamp {
+    pub seconds: i64,
+    pub nanos: u32,
+}
+
+// Days since 1970-01-01 of a date of the proleptic Gregorian calendar.
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = if year >= 0 { year } else { year - 399 } / 400;
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+fn parse_number(s: &str, min: i64, max: i64) -> Option<i64> {
+    if s.is_empty() || !s.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    s.parse::<i64>().ok().filter(|n| (min..=max).contains(n))
+}
+
+impl Timestamp {
+    // Parses `YYYY-MM-DD`, optionally followed by ` HH:MM:SS[.ffffff]` (or `T`) and a time zone
+    // (`Z`, `+HH`, `+HH:MM`).
+    pub fn parse(s: &str) -> Option<Timestamp> {
+        let (date, time) = match s.get(10..11) {
+            Some(" ") | Some("T") => (&s[..10], Some(&s[11..])),
+            _ => (s, None),
+        };
+        let mut date_parts = date.splitn(3, '-');
+        let year = parse_number(date_parts.next()?, 1, 9999)?;
+        let month = parse_number(date_parts.next()?, 1, 12)?;
+        let day = parse_number(date_parts.next()?, 1, 31)?;
+        let mut seconds = days_from_civil(year, month, day) * 86400;
+        let mut nanos = 0;
+
+        if let Some(time) = time {
+            let (clock, zone) = match time.find(['Z', '+', '-']) {
+                Some(i) => time.split_at(i),
+                None => (time, ""),
+            };
+            let (clock, fraction) = clock.split_once('.').unwrap_or((clock, ""));
+            let mut clock_parts = clock.splitn(3, ':');
+            let hours = parse_number(clock_parts.next()?, 0, 24)?;
+            let minutes = parse_number(clock_parts.next()?, 0, 59)?;
+            let secs = parse_number(clock_parts.next()?, 0, 60)?;
+            seconds += hours * 3600 + minutes * 60 + secs;
+            if !fraction.is_empty() {
+                let digits = fraction.get(..9).unwrap_or(fraction);
+                nanos = parse_number(digits, 0, 999_999_999)? as u32
+                    * 10u32.pow(9 - digits.len() as u32);
+            }
+            if !zone.is_empty() && zone != "Z" {
+                let (sign, offset) = zone.split_at(1);
+                let mut offset_parts = offset.splitn(3, ':');
+                let offset_hours = parse_number(offset_parts.next()?, 0, 15)?;
+                let offset_minutes = match offset_parts.next() {
+                    Some(m) => parse_number(m, 0, 59)?,
+                    None => 0,
+                };
+                let offset = offset_hours * 3600 + offset_minutes * 60;
+                // Local time is ahead of UTC by a positive offset
+                seconds += if sign == "+" { -offset } else { offset };
+            }
+        }
+        Some(Timestamp { seconds, nanos })
+    }
+}
+
+impl FromSql for Timestamp {
+    fn from_sql(oid: u32, value: &Value) -> Result<Self, String> {
+        check_oid(
+            oid,
+            &[oid::TIMESTAMP, oid::TIMESTAMPTZ, oid::DATE],
+            "a timestamp",
+        )?;
+        value
+            .as_str()
+            .and_then(Timestamp::parse)
+            .ok_or_else(|| unexpected("a timestamp", value))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Uuid(pub [u8; 16]);
+
+impl Uuid {
+    pub fn parse(s: &str) -> Option<Uuid> {
+        let hyphenated = s.len() == 36 && [8, 13, 18, 23].iter().all(|&i| s.as_bytes()[i] == b'-');
+        if !hyphenated && s.len() != 32 {
+            return None;
+        }
+        let digits: String = s.chars().filter(|c| *c != '-').collect();
+        let bytes = hex::decode(digits).ok()?;
+        Some(Uuid(bytes.try_into().ok()?))
+    }
+}
+
+impl fmt::Display for Uuid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let h = hex::encode(self.0);
+        write!(
+            f,
+            "{}-{}-{}-{}-{}",
+            &h[0..8],
+            &h[8..12],
+            &h[12..16],
+            &h[16..20],
+            &h[20..32]
+        )
+    }
+}
+
+impl FromSql for Uuid {
+    fn from_sql(oid: u32, value: &Value) -> Result<Self, String> {
+        check_oid(
+            oid,
+            &[oid::UUID, oid::TEXT, oid::VARCHAR, oid::BPCHAR],
+            "a uuid",
+        )?;
+        value
+            .as_str()
+            .and_then(Uuid::parse)
+            .ok_or_else(|| unexpected("a uuid", value))
+    }
+}
+
+// A row of a query result, its values are read by column name.
+#[derive(Debug, Clone, Copy)]
+pub struct Row<'a> {
+    fields: &'a [Field],
+    values: &'a [Value],
+}
+
+impl<'a> Row<'a> {
+    pub fn new(fields: &'a [Field], values: &'a [Value]) -> Self {
+        Self { fields, values }
+    }
+
+    pub fn get<T: FromSql>(&self, column: &str) -> Result<T, RowError> {
+        let index = self
+            .fields
+            .iter()
+            .position(|f| f.name == column)
+            .ok_or_else(|| RowError::MissingColumn(column.to_string()))?;
+        let value = self
+            .values
+            .get(index)
+            .ok_or_else(|| RowError::MissingColumn(column.to_string()))?;
+        let oid = self.fields[index].field_type;
+        if value.is_null() {
+            return T::from_null().ok_or_else(|| RowError::UnexpectedNull(column.to_string()));
+        }
+        T::from_sql(oid, value).map_err(|reason| RowError::WrongType {
+            column: column.to_string(),
+            oid,
+            reason,
+        })
+    }
+}
+
+// Conversion of a row into a Rust type, implemented for structs with impl_from_row!.
+pub trait FromRow: Sized {
+    fn from_row(row: &Row) -> Result<Self, RowError>;
+}
+
+// Implements FromRow for a struct, reading each listed field from the column of the same name.
+//
+// struct Purchase { first_name: String, total_price: Numeric, purchase_date: Timestamp }
+// impl_from_row!(Purchase { first_name, total_price, purchase_date });
+#[macro_export]
+macro_rules! impl_from_row {
+    ($name:ident { $($field:ident),* $(,)? }) => {
+        impl $crate::row::FromRow for $name {
+            fn from_row(row: &$crate::row::Row) -> Result<Self, $crate::row::RowError> {
+                Ok(Self {
+                    $($field: row.get(stringify!($field))?,)*
+                })
+            }
+        }
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[derive(Debug)]
+    struct Purchase {
+        id: i32,
+        uuid: Uuid,
+        total_price: Numeric,
+        purchase_date: Timestamp,
+        details: Json<Value>,
+        receipt: Option<Vec<u8>>,
+    }
+    impl_from_row!(Purchase {
+        id,
+        uuid,
+        total_price,
+        purchase_date,
+        details,
+        receipt
+    });
+
+    fn field(name: &str, field_type: u32) -> Field {
+        Field {
+            name: name.to_string(),
+            field_type,
+            size: 0,
+            scale: 0,
+            nullable: true,
+            description: None,
+        }
+    }
+
+    #[test]
+    fn test_from_row() {
+        let fields = vec![
+            field("id", oid::INT4),
+            field("uuid", oid::UUID),
+            field("total_price", oid::NUMERIC),
+            field("purchase_date", oid::TIMESTAMPTZ),
+            field("details", oid::JSONB),
+            field("receipt", oid::BYTEA),
+        ];
+        let mut values = vec![
+            json!(7),
+            json!("a0eebc99-9c0b-4ef8-bb6d-6bb9bd380a11"),
+            json!("1200.50"),
+            json!("2024-03-01 10:00:00.25+02"),
+            json!("{\"gift\": true}"),
+            Value::Null,
+        ];
+
+        let purchase = Purchase::from_row(&Row::new(&fields, &values)).unwrap();
+        assert_eq!(purchase.id, 7);
+        assert_eq!(
+            purchase.uuid.to_string(),
+            "a0eebc99-9c0b-4ef8-bb6d-6bb9bd380a11"
+        );
+        assert_eq!(purchase.total_price.to_f64(), Some(1200.5));
+        assert_eq!(
+            purchase.purchase_date,
+            Timestamp {
+                seconds: 1709280000,
+                nanos: 250_000_000
+            }
+        );
+        assert_eq!(purchase.details.0, json!({ "gift": true }));
+        assert_eq!(purchase.receipt, None);
+
+        values[5] = json!("\\x01ff");
+        let row = Row::new(&fields, &values);
+        assert_eq!(row.get::<Vec<u8>>("receipt").unwrap(), vec![1, 255]);
+        assert_eq!(
+            row.get::<bool>("nope"),
+            Err(RowError::MissingColumn("nope".to_string()))
+        );
+        let err = row.get::<i64>("uuid").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Column uuid of type uuid: expected an integer"
+        );
+
+        values[0] = Value::Null;
+        assert_eq!(
+            Row::new(&fields, &values).get::<i32>("id"),
+            Err(RowError::UnexpectedNull("id".to_string()))
+        );
+    }
+}
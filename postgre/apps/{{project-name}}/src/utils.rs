@@ -1,5 +1,3 @@
-use serde_json::Value;
-
 // pub fn get_client_id() -> String {
 //     let client_id = match klave::context::get("sender") {
 //             Ok(id) => id,
@@ -25,41 +23,3 @@ pub fn get_serde_value_into_bytes(
     };
     Ok(bytes)
 }
-
-pub fn flatten_vec_of_vec_values_to_single_string(data: Vec<Vec<Value>>) -> String {
-    let inner_strings: Vec<String> = data
-        .into_iter() // Take ownership of the outer Vec
-        .map(|inner_vec| {
-            // For each inner Vec<Value>
-            let values_as_strings: Vec<String> = inner_vec
-                .into_iter() // Take ownership of inner Vec's elements
-                .map(|value| {
-                    // For each Value
-                    // Convert the Value enum variant into its string representation
-                    match value {
-                        Value::String(s) => format!("'{s}'"),
-                        Value::Number(n) => n.to_string(),
-                        Value::Bool(b) => b.to_string(),
-                        Value::Null => "null".to_string(),
-                        Value::Array(arr) => arr
-                            .into_iter()
-                            .map(|v| v.to_string())
-                            .collect::<Vec<String>>()
-                            .join(";"),
-                        Value::Object(obj) => obj
-                            .into_iter()
-                            .map(|(k, v)| format!("{k}:{v}"))
-                            .collect::<Vec<String>>()
-                            .join(";"),
-                    }
-                })
-                .collect(); // Collect all processed Value strings into a Vec<String>
-
-            // Join the values for this inner_vec with a comma, then wrap in parentheses
-            format!("({})", values_as_strings.join(","))
-        })
-        .collect(); // Collect all the resulting parenthesized strings into a Vec<String>
-
-    // Finally, join all these parenthesized strings into one single String
-    inner_strings.join(",")
-}